@@ -0,0 +1,368 @@
+// Cache of the probed dependencies, see `Config::cache_file`, and versions pinned using `Config::pin_versions`
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+
+use crate::{Dependencies, IncludeArgStyle, Library, Source};
+
+// What the dependencies have been probed with, the cache is only used if it matches
+#[derive(Debug, PartialEq)]
+pub(crate) struct State {
+    // the environment variables which may change the probing results
+    pub(crate) env: BTreeMap<String, String>,
+    // the settings of the `Config`
+    pub(crate) config: String,
+    // keys of the dependencies declared in the metadata
+    pub(crate) deps: Vec<String>,
+}
+
+impl State {
+    fn to_toml(&self) -> Table {
+        let mut t = Table::new();
+        t.insert(
+            "env".into(),
+            Value::Table(
+                self.env
+                    .iter()
+                    .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                    .collect(),
+            ),
+        );
+        t.insert("config".into(), Value::String(self.config.clone()));
+        t.insert("deps".into(), strs(&self.deps));
+        t
+    }
+
+    fn from_toml(t: &Table) -> Option<Self> {
+        let env = t
+            .get("env")?
+            .as_table()?
+            .iter()
+            .map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            env,
+            config: get_str(t, "config")?,
+            deps: get_strs(t, "deps")?,
+        })
+    }
+}
+
+// Load the dependencies and warnings cached in `path`, if it has been written after all the `sources`
+// were last modified and using the same `state`
+pub(crate) fn load(
+    path: &Path,
+    sources: &[PathBuf],
+    state: &State,
+) -> Option<(Dependencies, Vec<String>)> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    for source in sources {
+        let source_modified = fs::metadata(source).and_then(|m| m.modified()).ok()?;
+        if source_modified >= modified {
+            return None;
+        }
+    }
+
+    let content = fs::read_to_string(path).ok()?;
+    let cache = content.parse::<Value>().ok()?;
+    let cache = cache.as_table()?;
+    if State::from_toml(cache.get("state")?.as_table()?)? != *state {
+        return None;
+    }
+
+    let libs = cache
+        .get("deps")?
+        .as_array()?
+        .iter()
+        .map(|dep| {
+            let dep = dep.as_table()?;
+            Some((get_str(dep, "key")?, lib_from_toml(dep)?))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let mut libraries = Dependencies::default();
+    for (key, lib) in libs {
+        libraries.add(&key, lib);
+    }
+
+    Some((libraries, get_strs(cache, "warnings")?))
+}
+
+// Write the dependencies and warnings resulting from probing with `state` to `path`
+pub(crate) fn store(
+    path: &Path,
+    state: &State,
+    libraries: &Dependencies,
+    warnings: &[String],
+) -> std::io::Result<()> {
+    let deps = libraries
+        .libs
+        .iter()
+        .map(|(key, lib)| {
+            let mut dep = lib_to_toml(lib);
+            dep.insert("key".into(), Value::String(key.clone()));
+            Value::Table(dep)
+        })
+        .collect();
+
+    let mut cache = Table::new();
+    cache.insert("state".into(), Value::Table(state.to_toml()));
+    cache.insert("warnings".into(), strs(warnings));
+    cache.insert("deps".into(), Value::Array(deps));
+
+    let content = toml::to_string(&Value::Table(cache))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    fs::write(path, content)
+}
+
+// Load the versions pinned in `path`, by dependency key, or `None` if the file does not exist
+pub(crate) fn load_versions(path: &Path) -> Result<Option<BTreeMap<String, String>>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    let pinned = content.parse::<Value>().map_err(|e| e.to_string())?;
+
+    pinned
+        .get("versions")
+        .and_then(|v| v.as_table())
+        .and_then(|versions| {
+            versions
+                .iter()
+                .map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect::<Option<_>>()
+        })
+        .map(Some)
+        .ok_or_else(|| "versions is not a table of strings".to_string())
+}
+
+// Write the pinned `versions`, by dependency key, to `path`
+pub(crate) fn store_versions(
+    path: &Path,
+    versions: &BTreeMap<String, String>,
+) -> std::io::Result<()> {
+    let mut pinned = Table::new();
+    pinned.insert(
+        "versions".into(),
+        Value::Table(
+            versions
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect(),
+        ),
+    );
+
+    let content = toml::to_string(&Value::Table(pinned))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    fs::write(
+        path,
+        format!(
+            "# Versions of the system dependencies pinned by system-deps\n{}",
+            content
+        ),
+    )
+}
+
+fn lib_to_toml(lib: &Library) -> Table {
+    let mut t = Table::new();
+    t.insert("name".into(), Value::String(lib.name.clone()));
+    let source = match lib.source {
+        Source::PkgConfig => "pkg-config",
+        Source::EnvVariables => "env-variables",
+        Source::BuildInternal => "build-internal",
+    };
+    t.insert("source".into(), Value::String(source.into()));
+    t.insert("libs".into(), strs(&lib.libs));
+    t.insert("link_paths".into(), paths(&lib.link_paths));
+    t.insert("frameworks".into(), strs(&lib.frameworks));
+    t.insert("framework_paths".into(), paths(&lib.framework_paths));
+    t.insert("search_paths".into(), paths(&lib.search_paths));
+    t.insert("include_paths".into(), paths(&lib.include_paths));
+    t.insert(
+        "private_include_paths".into(),
+        paths(&lib.private_include_paths),
+    );
+    // `toml` has no null value so defines are stored as `[key]` or `[key, value]`
+    let defines = lib
+        .defines
+        .iter()
+        .map(|(k, v)| strs(&std::iter::once(k).chain(v).cloned().collect::<Vec<_>>()))
+        .collect();
+    t.insert("defines".into(), Value::Array(defines));
+    t.insert("version".into(), Value::String(lib.version.clone()));
+    t.insert("statik".into(), Value::Boolean(lib.statik));
+    t.insert("link_args".into(), strs(&lib.link_args));
+    t.insert("link_args_bins".into(), strs(&lib.link_args_bins));
+    t.insert("link_args_tests".into(), strs(&lib.link_args_tests));
+    t.insert("link_args_benches".into(), strs(&lib.link_args_benches));
+    t.insert("link_arg_files".into(), paths(&lib.link_arg_files));
+    t.insert("static_libs".into(), strs(&lib.static_libs));
+    t.insert("dylib_libs".into(), strs(&lib.dylib_libs));
+    t.insert("whole_archive_libs".into(), strs(&lib.whole_archive_libs));
+    t.insert("verbatim_libs".into(), strs(&lib.verbatim_libs));
+    t.insert("vendored".into(), Value::Boolean(lib.vendored));
+    if let Some(probe_command) = &lib.probe_command {
+        t.insert("probe_command".into(), Value::String(probe_command.clone()));
+    }
+    t.insert("requires".into(), strs(&lib.requires));
+    // stored as `[module, required...]`
+    let requires_graph = lib
+        .requires_graph
+        .iter()
+        .map(|(m, r)| strs(&std::iter::once(m).chain(r).cloned().collect::<Vec<_>>()))
+        .collect();
+    t.insert("requires_graph".into(), Value::Array(requires_graph));
+    t.insert("defines_only".into(), Value::Boolean(lib.defines_only));
+    t.insert("emit".into(), Value::Boolean(lib.emit));
+    t.insert("link_group".into(), Value::Boolean(lib.link_group));
+    t.insert("applied_env_vars".into(), strs(&lib.applied_env_vars));
+    t.insert(
+        "weak_frameworks".into(),
+        Value::Table(
+            lib.weak_frameworks
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect(),
+        ),
+    );
+    t.insert(
+        "exported_variables".into(),
+        Value::Table(
+            lib.exported_variables
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect(),
+        ),
+    );
+    if let Some(prefix) = &lib.prefix {
+        t.insert(
+            "prefix".into(),
+            Value::String(prefix.to_string_lossy().to_string()),
+        );
+    }
+    t.insert("pkg_config_paths".into(), paths(&lib.pkg_config_paths));
+    if let Some(same_as) = &lib.same_as {
+        t.insert("same_as".into(), Value::String(same_as.clone()));
+    }
+    t
+}
+
+fn lib_from_toml(t: &Table) -> Option<Library> {
+    let source = match t.get("source")?.as_str()? {
+        "pkg-config" => Source::PkgConfig,
+        "env-variables" => Source::EnvVariables,
+        "build-internal" => Source::BuildInternal,
+        _ => return None,
+    };
+    let defines = t
+        .get("defines")?
+        .as_array()?
+        .iter()
+        .map(|define| {
+            let define = define.as_array()?;
+            let key = define.first()?.as_str()?.to_string();
+            let value = match define.get(1) {
+                Some(v) => Some(v.as_str()?.to_string()),
+                None => None,
+            };
+            Some((key, value))
+        })
+        .collect::<Option<HashMap<_, _>>>()?;
+    let weak_frameworks = t
+        .get("weak_frameworks")?
+        .as_table()?
+        .iter()
+        .map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+        .collect::<Option<_>>()?;
+    let exported_variables = t
+        .get("exported_variables")?
+        .as_table()?
+        .iter()
+        .map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+        .collect::<Option<_>>()?;
+    let requires_graph = t
+        .get("requires_graph")?
+        .as_array()?
+        .iter()
+        .map(|module| {
+            let module = module.as_array()?;
+            let (name, requires) = module.split_first()?;
+            let requires = requires
+                .iter()
+                .map(|r| r.as_str().map(|r| r.to_string()))
+                .collect::<Option<_>>()?;
+            Some((name.as_str()?.to_string(), requires))
+        })
+        .collect::<Option<_>>()?;
+
+    Some(Library {
+        name: get_str(t, "name")?,
+        source,
+        libs: get_strs(t, "libs")?,
+        link_paths: get_paths(t, "link_paths")?,
+        frameworks: get_strs(t, "frameworks")?,
+        framework_paths: get_paths(t, "framework_paths")?,
+        search_paths: get_paths(t, "search_paths")?,
+        include_paths: get_paths(t, "include_paths")?,
+        private_include_paths: get_paths(t, "private_include_paths")?,
+        defines,
+        version: get_str(t, "version")?,
+        statik: t.get("statik")?.as_bool()?,
+        link_args: get_strs(t, "link_args")?,
+        link_args_bins: get_strs(t, "link_args_bins")?,
+        link_args_tests: get_strs(t, "link_args_tests")?,
+        link_args_benches: get_strs(t, "link_args_benches")?,
+        link_arg_files: get_paths(t, "link_arg_files")?,
+        static_libs: get_strs(t, "static_libs")?,
+        dylib_libs: get_strs(t, "dylib_libs")?,
+        whole_archive_libs: get_strs(t, "whole_archive_libs")?,
+        verbatim_libs: get_strs(t, "verbatim_libs")?,
+        vendored: t.get("vendored")?.as_bool()?,
+        probe_command: get_str(t, "probe_command"),
+        requires: get_strs(t, "requires")?,
+        requires_graph,
+        defines_only: t.get("defines_only")?.as_bool()?,
+        emit: t.get("emit")?.as_bool()?,
+        link_group: t.get("link_group")?.as_bool()?,
+        applied_env_vars: get_strs(t, "applied_env_vars")?,
+        weak_frameworks,
+        exported_variables,
+        prefix: get_str(t, "prefix").map(PathBuf::from),
+        pkg_config_paths: get_paths(t, "pkg_config_paths")?,
+        // not cached as it is set by the `Config` loading the cache
+        include_arg_style: IncludeArgStyle::Normal,
+        same_as: get_str(t, "same_as"),
+    })
+}
+
+fn strs(values: &[String]) -> Value {
+    Value::Array(values.iter().cloned().map(Value::String).collect())
+}
+
+fn paths(values: &[PathBuf]) -> Value {
+    Value::Array(
+        values
+            .iter()
+            .map(|p| Value::String(p.to_string_lossy().to_string()))
+            .collect(),
+    )
+}
+
+fn get_str(t: &Table, key: &str) -> Option<String> {
+    t.get(key)?.as_str().map(|s| s.to_string())
+}
+
+fn get_strs(t: &Table, key: &str) -> Option<Vec<String>> {
+    t.get(key)?
+        .as_array()?
+        .iter()
+        .map(|v| v.as_str().map(|s| s.to_string()))
+        .collect()
+}
+
+fn get_paths(t: &Table, key: &str) -> Option<Vec<PathBuf>> {
+    get_strs(t, key).map(|paths| paths.into_iter().map(PathBuf::from).collect())
+}
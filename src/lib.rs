@@ -21,7 +21,7 @@
 //!
 //! Finally, in your `build.rs`, add:
 //!
-//! ```should_panic
+//! ```no_run
 //! fn main() {
 //!     system_deps::Config::new().probe().unwrap();
 //! }
@@ -37,6 +37,21 @@
 //!
 //! `system-deps` will check for `testdata` only if the `use-testdata` feature has been enabled.
 //!
+//! If the dependency is always needed and the feature only enables extra code using it, set `always_probe`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testdata = { version = "4.5", feature = "use-testdata", always_probe = true }
+//! ```
+//!
+//! `testdata` is then probed, and the build fails if it is missing, whether `use-testdata` is enabled or not.
+//! Unlike `feature` alone, the feature no longer controls whether the dependency is required,
+//! only the code of your crate using it.
+//!
+//! The enabled features are read from the `CARGO_FEATURE_*` environment variables defined by Cargo.
+//! Tools evaluating the dependencies required by a given set of features can set those
+//! using [Config::enabled_features] instead.
+//!
 //! # Optional dependency
 //!
 //! Another option is to use the `optional` setting, which can also be used using [features versions](#feature-versions):
@@ -47,6 +62,24 @@
 //! testmore = { version = "2", v3 = { version = "3.0", optional = true }}
 //! ```
 //!
+//! When any version of an optional dependency will do, set `any_version` instead of requiring a version:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testdata = { optional = true, any_version = true }
+//! ```
+//!
+//! `testdata` is then only checked for existence, and [Library::version] is the version reported by `pkg-config`.
+//! `any_version` has no effect on non-optional dependencies, which still need a version.
+//!
+//! Required dependencies whose version is meaningless, such as git snapshots, can use `skip_version_check`
+//! so they are probed without checking their version, including when they are built internally:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", skip_version_check = true }
+//! ```
+//!
 //! `system-deps` will automatically export for each dependency a feature `system_deps_have_$DEP` where `$DEP`
 //! is the `toml` key defining the dependency in [snake_case](https://en.wikipedia.org/wiki/Snake_case).
 //! This can be used to check if an optional dependency has been found or not:
@@ -56,6 +89,100 @@
 //! println!("found test-data");
 //! ```
 //!
+//! The name of the cfg can be chosen using `cfg_name`, keeping it stable if the key is renamed:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! gstreamer_1_0 = { name = "gstreamer-1.0", version = "1.0", cfg_name = "gst" }
+//! ```
+//!
+//! The cfg of this dependency is then `system_deps_have_gst`.
+//!
+//! A matching `cargo:rustc-check-cfg` instruction is emitted for each dependency declared in `Cargo.toml`,
+//! even the ones which have not been found, so rustc does not warn about unexpected cfgs.
+//! Crates managing their own cfgs can disable those using [Config::emit_have_cfgs].
+//!
+//! The `HAVE_*` macros defined by the dependencies, such as `-DHAVE_FOO`, can also be exposed to Rust
+//! code as cfgs using [Config::emit_define_cfgs], so it can use `#[cfg(have_foo)]`.
+//!
+//! # Minimum OS version
+//! Dependencies requiring a minimum version of an Apple OS, for example because they use recent frameworks,
+//! can declare it using `min_os_version`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", min_os_version = "11.0" }
+//! ```
+//!
+//! When building for an Apple target, probing fails with [Error::DeploymentTargetTooOld] if the deployment
+//! target is older, or the dependency is skipped if it is optional. The deployment target is read from
+//! `MACOSX_DEPLOYMENT_TARGET`, `IPHONEOS_DEPLOYMENT_TARGET`, `TVOS_DEPLOYMENT_TARGET`, `WATCHOS_DEPLOYMENT_TARGET`
+//! or `XROS_DEPLOYMENT_TARGET`, depending on the target OS. Nothing is checked if this variable is not set
+//! or when building for other targets.
+//!
+//! Frameworks can be linked on top of the ones reported by `pkg-config` using `frameworks`. Those only available
+//! starting from a given OS version can be declared with `weak_below`: they are weakly linked, using
+//! `-Wl,-weak_framework`, if the deployment target is older than this version, or if it is not set,
+//! and linked as usual otherwise:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", frameworks = ["Metal", { name = "Foo", weak_below = "12.0" }] }
+//! ```
+//!
+//! Weakly linked frameworks are only available at runtime on a new enough OS, so the code using them has to check
+//! that they are. `weak_below` is ignored when building for other targets.
+//!
+//! # Universal binaries
+//! Tools building universal macOS binaries can probe the dependencies of each architecture slice using
+//! [Config::probe_for_targets]. The `pkg-config` files of each target can be provided using
+//! `PKG_CONFIG_PATH_$TARGET`, such as `PKG_CONFIG_PATH_aarch64-apple-darwin`.
+//!
+//! # Required defines
+//! Dependencies whose build options are exposed as defines in their `.pc` file can require some of them,
+//! optionally with a specific value:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", require_defines = ["TESTLIB_ENABLE_X", "TESTLIB_ABI=3"] }
+//! ```
+//!
+//! Probing then fails with [Error::MissingDefine] if the library does not define one of those, or with
+//! another value, rather than failing later when compiling code relying on it.
+//!
+//! # Versions of the required modules
+//! Mixed installations, where a library such as `gstreamer-base-1.0` 1.20 is used with the `gstreamer-1.0` 1.22
+//! it requires, can be detected using `requires_same_version`. The version of each module listed in the `Requires`
+//! of the library has then to be the same as the one of the library, up to the `major`, `minor` or `patch` version:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! gstreamer_base = { name = "gstreamer-base-1.0", version = "1.20", requires_same_version = "minor" }
+//! ```
+//!
+//! Probing fails with [Error::MismatchedVersion] naming the first module whose version differs.
+//!
+//! # Conflicting dependencies
+//! Dependencies which cannot be used together can be declared using `conflicts_with`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1", optional = true, conflicts_with = ["testanotherlib"] }
+//! testanotherlib = { version = "1", optional = true }
+//! ```
+//!
+//! Probing fails with [Error::ConflictingDeps] if both dependencies have been found.
+//!
+//! # Describing dependencies
+//! A human readable `description` can be set on dependencies. It is then used in the
+//! error messages and warnings about them, making those easier to understand
+//! for the users of the crate:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! gstreamer_1_0 = { name = "gstreamer-1.0", version = "1.0", description = "GStreamer core" }
+//! ```
+//!
 //! # Overriding library name
 //! `toml` keys cannot contain dot characters so if your library name does you can define it using the `name` field:
 //!
@@ -63,6 +190,91 @@
 //! [package.metadata.system-deps]
 //! glib = { name = "glib-2.0", version = "2.64" }
 //! ```
+//!
+//! When several keys refer to the same library, use `same_as` so it is only probed once:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = "1.2"
+//! test_lib = { same_as = "testlib" }
+//! ```
+//!
+//! `test_lib` is then a copy of `testlib` in the returned [Dependencies],
+//! and the build flags of the library are only emitted once.
+//!
+//! # Dependency groups
+//! When any library of a set provides the needed feature, declare them using `one_of`.
+//! The members are tried in declaration order and the first one found is used:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! tls = { one_of = { openssl = "1.1", libressl = "3.0" } }
+//! ```
+//!
+//! [Library::name] is then the name of the selected member.
+//! Probing fails with [Error::NoGroupMember] if none of them has been found, unless the group is `optional`.
+//!
+//! When a dependency is provided by several modules, declare them using `modules`.
+//! All of them are required and the resulting [Library] aggregates their settings:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! gst = { modules = { "gstreamer-1.0" = "1.14", "gstreamer-base-1.0" = "1.14" } }
+//! ```
+//!
+//! With `allow_partial = true`, the modules which cannot be found are skipped with a warning
+//! and the dependency only uses the ones found. Probing still fails if none of them has been found.
+//!
+//! # Including dependencies from another file
+//! Crates with many dependencies can declare them in a separate `toml` file using the `include` key.
+//! The path of this file is relative to `Cargo.toml`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! include = "system-deps.toml"
+//! ```
+//!
+//! The included file uses the same syntax as the `[package.metadata.system-deps]` section.
+//! Dependencies defined in `Cargo.toml` take precedence over the ones with the same key in the included file.
+//!
+//! When building a packaged crate whose normalized `Cargo.toml` lost the `[package.metadata.system-deps]` section,
+//! [Config::check_orig_manifest] reads the metadata from the `Cargo.toml.orig` file kept by `cargo package` instead.
+//!
+//! # Using the crate version
+//! `-sys` crates whose version follows the one of the system library can use `crate` as version:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = "crate"
+//! ```
+//!
+//! The required version is then the major and minor versions of the crate, ignoring its patch version
+//! and any pre-release or build metadata. For example, `testlib-sys` `1.18.3` requires `testlib >= 1.18`.
+//!
+//! # Version comparison
+//! The version found by `pkg-config` has to be at least the required one. The `version_op` key selects another
+//! comparison: `exact` to require this very version, or `max` to reject the newer versions, for example if
+//! the next major version is not compatible:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.99", version_op = "max" }
+//! ```
+//!
+//! The allowed values are `atleast`, the default, `exact` and `max`. The comparison only applies to the
+//! dependencies probed using `pkg-config`.
+//!
+//! For reproducible builds, `version = { exact = "1.2.3" }` is a shorthand for `version = "1.2.3", version_op = "exact"`,
+//! failing if `pkg-config` reports any other version. Feature versions can declare their own exact version,
+//! the other ones using the `version_op` of the dependency:
+//!
+//! ```toml
+//! [package.metadata.system-deps.gstreamer_1_0]
+//! name = "gstreamer-1.0"
+//! version = "1.18"
+//! v1_22 = { version = { exact = "1.22.1" } }
+//! ```
+//!
 //! # Feature versions
 //! `-sys` crates willing to support various versions of their underlying system libraries
 //! can use features to control the version of the dependency required.
@@ -92,6 +304,36 @@
 //! v1_18 = { version = "1.18", name = "gstreamer-gl-egl-1.0" }
 //! ```
 //!
+//! Feature versions are expected to require increasing versions, in their declaration order.
+//! Using [Config::strict_feature_versions], probing fails if a feature version requires an older
+//! version than the ones declared before it, such as a `v1_4 = { version = "1.1" }` typo.
+//!
+//! Declaring a dependency with `require_version_feature = true` makes probing fail with [Error::MissingVersionFeature]
+//! if none of its feature versions is enabled, rather than silently requiring the base `version`:
+//!
+//! ```toml
+//! [package.metadata.system-deps.gstreamer_1_0]
+//! name = "gstreamer-1.0"
+//! version = "1.0"
+//! require_version_feature = true
+//! v1_2 = { version = "1.2" }
+//! v1_4 = { version = "1.4" }
+//! ```
+//!
+//! Feature versions can also link extra frameworks, added to the ones reported by `pkg-config`:
+//!
+//! ```toml
+//! [package.metadata.system-deps.testlib]
+//! version = "1.0"
+//! v1_2 = { version = "1.2", frameworks = ["Metal"], framework_paths = ["/opt/frameworks"] }
+//! ```
+//!
+//! Using [Config::emit_version_cfgs], code can adapt to a system library newer than the one required
+//! by the enabled features. The versions of the feature versions of a dependency are used as thresholds
+//! and a `system_deps_$DEP_$VERSION` cfg is emitted for the highest one satisfied by the version actually found,
+//! with the dots of the version replaced by underscores. With the `gstreamer_1_0` dependency above,
+//! finding `gstreamer-1.0` 1.5.2 emits `system_deps_gstreamer_1_0_1_4`, whatever the enabled feature.
+//!
 //! # Target specific dependencies
 //!
 //! You can define target specific dependencies:
@@ -116,26 +358,246 @@
 //! - `target_pointer_width`
 //! - `target_vendor`
 //! - `unix` and `windows`
+//! - `feature`
+//!
+//! A dependency can be declared in several tables as long as only one of them matches the target,
+//! otherwise probing fails with [Error::InvalidMetadata].
+//!
+//! A dependency declared in a `cfg()` table can also be [feature-specific](#feature-specific-dependency),
+//! it is then only probed if both the `cfg()` expression matches the target and the feature is enabled:
+//!
+//! ```toml
+//! [package.metadata.system-deps.'cfg(target_os = "linux")']
+//! testlib = { version = "1", feature = "extra" }
+//! ```
+//!
+//! Bare-metal targets, such as `thumbv7em-none-eabihf`, can be matched using `target_os = "none"`.
+//! Targets unknown to `system-deps` are evaluated using the `CARGO_CFG_TARGET_*` environment variables
+//! defined by Cargo.
+//!
+//! # Link arguments
+//! Extra arguments can be passed to the linker using the `link_args` field, emitted as
+//! [`cargo:rustc-link-arg`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#rustc-link-arg).
+//! `link_args_bins`, `link_args_tests` and `link_args_benches` restrict those arguments to binaries,
+//! tests and benchmarks respectively:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", link_args = ["-Wl,--as-needed"], link_args_bins = ["-Wl,-rpath,/opt/testlib/lib"] }
+//! ```
+//!
+//! Linker scripts shipped with the crate, as commonly used on embedded targets, can be listed in `link_arg_files`.
+//! Their paths are relative to the directory of `Cargo.toml` and they are passed as `-T$PATH` link arguments.
+//! The build script is also run again when they change:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", link_arg_files = ["link.x"] }
+//! ```
+//!
+//! # Selecting the linked libraries
+//! When `pkg-config` reports several libraries for a dependency, some of them can be linked statically
+//! while the others are still linked dynamically by listing them in `static_libs`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", static_libs = ["test"] }
+//! ```
+//!
+//! The listed libraries are emitted as `cargo:rustc-link-lib=static=$LIB`. A warning is emitted for
+//! each listed library which is not one of the libraries of the dependency.
+//!
+//! Static libraries whose object files should all be linked, even if none of their symbols is used,
+//! such as plugins registering themselves, can be listed in `whole_archive_libs`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", whole_archive_libs = ["test"] }
+//! ```
+//!
+//! Those are emitted as `cargo:rustc-link-lib=static:+whole-archive=$LIB`.
+//!
+//! Libraries whose file name does not follow the `lib$LIB.a` or `$LIB.lib` conventions, such as
+//! some Windows import libraries, can be linked using their exact file name with `verbatim_libs`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", verbatim_libs = ["libtest-1.2.a"] }
+//! ```
+//!
+//! Those are emitted in addition to the libraries reported by `pkg-config`, as
+//! `cargo:rustc-link-lib=static:+verbatim=$FILE` if the library is linked statically or the file is listed
+//! in `static_libs`, and as `cargo:rustc-link-lib=dylib:+verbatim=$FILE` otherwise.
+//! rustc only accepts `verbatim` as a modifier of the link kind, hence the kind being always set.
+//!
+//! Libraries can also be declared in `libs`, in addition to the ones reported by `pkg-config`,
+//! or instead of them using `replace_libs = true`. Each of them can set the kind it is linked with,
+//! `static` or `dylib`, emitted as `cargo:rustc-link-lib=$KIND=$LIB`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", libs = [{ name = "test", kind = "static" }, { name = "test-utils", kind = "dylib" }, "test-extra"] }
+//! ```
+//!
+//! The libraries declared without kind are linked as the ones reported by `pkg-config`.
+//!
+//! Libraries reported by `pkg-config` which should not be linked at all can be listed in `exclude_libs`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", exclude_libs = ["test2"] }
+//! ```
+//!
+//! Static libraries referencing each other's symbols can be linked in a group, so the linker resolves
+//! their circular references, using `link_group`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", link_group = true }
+//! ```
+//!
+//! As rustc cannot group libraries, those are then passed to the linker as `cargo:rustc-link-arg=-l$LIB`
+//! between `-Wl,--start-group` and `-Wl,--end-group`, the `static_libs` and `whole_archive_libs` being
+//! surrounded by `-Wl,-Bstatic` and `-Wl,-Bdynamic`. The libraries are emitted as usual for MSVC targets,
+//! as its linker does not need groups, and for Apple targets, as their linker does not support them.
+//!
+//! # Vendored `pkg-config` files
+//! Directories containing the `.pc` file of a vendored copy of a dependency can be listed, relative to `Cargo.toml`,
+//! in `pkg_config_paths`. They are searched in order before the default `pkg-config` search path,
+//! only when probing this dependency:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", pkg_config_paths = ["vendor/pkgconfig"] }
+//! ```
+//!
+//! [Library::vendored] tells if the vendored copy has been used.
+//!
+//! The paths can reference environment variables as `${VAR}`, for example `pkg_config_paths = ["${SDK_ROOT}/lib/pkgconfig"]`.
+//! Probing fails with [Error::InvalidMetadata] if a referenced variable is not set.
+//!
+//! For hermetic builds, [Config::pkg_config_libdir] sets `PKG_CONFIG_LIBDIR` while probing.
+//! Unlike `PKG_CONFIG_PATH`, which adds directories in front of the default search path,
+//! `PKG_CONFIG_LIBDIR` replaces this default search path, so the `.pc` files installed on the host are ignored.
+//!
+//! Other environment variables read by `pkg-config`, such as `PKG_CONFIG_ALLOW_SYSTEM_CFLAGS`, can be set while probing
+//! using [Config::pkg_config_env]. Their previous values are restored afterward.
+//!
+//! # Build flags order
+//! The build flags are emitted grouped by dependency, following the order in which the dependencies are declared
+//! in `Cargo.toml`. For each dependency, the search paths are emitted first, then the libraries and finally the frameworks,
+//! each in the order reported by `pkg-config`. This matters when linking statically as the linker is sensitive
+//! to the order of the libraries.
+//!
+//! The link arguments and the `cargo:include` instruction of all the dependencies are emitted afterward.
+//!
+//! The build flags of a dependency declared with `emit = false` are not emitted, so they can be inspected,
+//! filtered and emitted by the build script itself using the fields of its [Library]. The dependency is still
+//! probed and checked as usual. Defining `SYSTEM_DEPS_$NAME_NO_EMIT` has the same effect.
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.0", emit = false }
+//! ```
+//!
+//! # Exporting `pkg-config` variables
+//! The `pkg-config` variables listed in `export_variables` are read from the `.pc` file of the dependency
+//! and emitted as `cargo:$NAME_$VARIABLE=$VALUE`, after its frameworks. Like `cargo:include`, they are available
+//! to the build scripts of the dependent crates, for example as `DEP_$LINKS_TESTLIB_DATADIR`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.0", export_variables = ["datadir", "plugindir"] }
+//! ```
+//!
+//! A warning is emitted for each variable not defined by the `.pc` file. The exported variables are also
+//! available using [Library::exported_variables].
+//!
+//! # Private include paths
+//! The include paths of a library are the ones reported by `pkg-config --cflags`, from the `Cflags` of its `.pc` file,
+//! and are the ones exposed by [Dependencies::all_include_paths] and the `cargo:include` instruction.
+//! When linking statically, `pkg-config --cflags --static` also reports the `Cflags.private`: the additional
+//! include paths are stored in [Library::private_include_paths] and returned by
+//! [Dependencies::all_include_paths_with_private]. This keeps the headers of the private dependencies
+//! out of the public bindings generated from the include paths.
+//!
+//! [Library::cflags] returns the include paths and defines of a library as compiler arguments, for example to
+//! pass them to `bindgen`. Using [Config::include_arg_style] with [IncludeArgStyle::ISystem], the include paths
+//! are passed using `-isystem` rather than `-I` so the compiler does not report warnings from the headers of the library.
 //!
 //! # Overriding build flags
 //! By default `system-deps` automatically defines the required build flags for each dependency using the information fetched from `pkg-config`.
 //! These flags can be overriden using environment variables if needed:
 //! - `SYSTEM_DEPS_$NAME_SEARCH_NATIVE` to override the [`cargo:rustc-link-search=native`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#cargorustc-link-searchkindpath) flag;
+//! - `SYSTEM_DEPS_$NAME_SEARCH` to override the [`cargo:rustc-link-search`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#cargorustc-link-searchkindpath) flag, without kind so all kinds of libraries are searched;
 //! - `SYSTEM_DEPS_$NAME_SEARCH_FRAMEWORK` to override the [`cargo:rustc-link-search=framework`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#cargorustc-link-searchkindpath) flag;
 //! - `SYSTEM_DEPS_$NAME_LIB` to override the [`cargo:rustc-link-lib`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#rustc-link-lib) flag;
+//! - `SYSTEM_DEPS_$NAME_LIB_REMOVE` to remove some libraries from the [`cargo:rustc-link-lib`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#rustc-link-lib) flag,
+//!   after applying `SYSTEM_DEPS_$NAME_LIB`;
 //! - `SYSTEM_DEPS_$NAME_LIB_FRAMEWORK` to override the [`cargo:rustc-link-lib=framework`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#rustc-link-lib) flag;
+//! - `SYSTEM_DEPS_$NAME_FRAMEWORK_APPEND` to link some frameworks on top of the ones of the
+//!   [`cargo:rustc-link-lib=framework`](https://doc.rust-lang.org/cargo/reference/build-scripts.html#rustc-link-lib) flag,
+//!   after applying `SYSTEM_DEPS_$NAME_LIB_FRAMEWORK`;
 //! - `SYSTEM_DEPS_$NAME_INCLUDE` to override the [`cargo:include`](https://kornel.ski/rust-sys-crate#headers) flag.
 //!
 //! With `$NAME` being the upper case name of the key defining the dependency in `Cargo.toml`.
 //! For example `SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE=/opt/lib` could be used to override a dependency named `testlib`.
 //!
+//! Overrides of multiple dependencies can also be defined at once using the `SYSTEM_DEPS_OVERRIDES` environment variable.
+//! It contains a `toml` document with a table for each dependency to override, using the `toml` key defining it in `Cargo.toml`:
+//!
+//! ```toml
+//! [testlib]
+//! libs = ["test", "other-test"]
+//! link_paths = ["/opt/lib"]
+//! include_paths = ["/opt/include"]
+//! ```
+//!
+//! The supported keys are `libs`, `link_paths`, `search_paths`, `frameworks`, `framework_paths` and `include_paths`.
+//! The `SYSTEM_DEPS_$NAME_*` variables take precedence over the values defined in `SYSTEM_DEPS_OVERRIDES`.
+//!
 //! One can also define the environment variable `SYSTEM_DEPS_$NAME_NO_PKG_CONFIG` to fully disable `pkg-config` lookup
-//! for the given dependency. In this case at least SYSTEM_DEPS_$NAME_LIB or SYSTEM_DEPS_$NAME_LIB_FRAMEWORK should be defined as well.
+//! for the given dependency. In this case at least SYSTEM_DEPS_$NAME_LIB or SYSTEM_DEPS_$NAME_LIB_FRAMEWORK should be defined as well,
+//! unless the dependency is declared with `defines_only = true` as it only provides defines and include paths
+//! and so has nothing to link, or declares its libs in its [fallback settings](#fallback-settings).
+//!
+//! To check how a crate behaves when a dependency is missing, without uninstalling it,
+//! define `SYSTEM_DEPS_$NAME_FORCE_MISSING`. The dependency is then reported as not found by `pkg-config`:
+//! optional dependencies are skipped and required ones make the build fail.
+//!
+//! If the version of a dependency is older than required, the build can still proceed using the version found
+//! by defining `SYSTEM_DEPS_$NAME_ALLOW_OLDER`, or `SYSTEM_DEPS_ALLOW_OLDER` for all dependencies.
+//! A warning stating the found and required versions is then emitted.
+//! Use this with caution as the code of the crate may rely on features of the required version.
+//!
+//! # Fallback settings
+//! The build flags of a dependency can be declared in the metadata using `fallback`, to be used as if they were
+//! defined by the `SYSTEM_DEPS_$NAME_*` environment variables when a required dependency cannot be resolved,
+//! for example on platforms where `pkg-config` is usually not available:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", fallback = { libs = ["test"], link_paths = ["/usr/lib"], include_paths = ["/usr/include/testlib"] } }
+//! ```
+//!
+//! The supported keys are `libs`, `link_paths`, `include_paths`, `frameworks` and `framework_paths`.
+//! The fallback is only used if the target matches its `cfg()` expression, if any:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", fallback = { cfg = 'cfg(target_os = "windows")', libs = ["test"] } }
+//! ```
+//!
+//! The resulting [Library] has [Source::EnvVariables] as source. Optional dependencies are still skipped if they cannot be resolved.
+//!
+//! The fallback is also used when `SYSTEM_DEPS_$NAME_NO_PKG_CONFIG` is set, so the dependency can be defined manually
+//! without setting `SYSTEM_DEPS_$NAME_LIB`. The `SYSTEM_DEPS_$NAME_*` environment variables which are set still take
+//! precedence over the fallback.
 //!
 //! # Statically build system library
 //! `-sys` crates can provide support for building and statically link their underlying system library as part of their build process.
 //! Here is how to do this in your `build.rs`:
-//! ```should_panic
+//! ```no_run
 //! fn main() {
 //!     system_deps::Config::new()
 //!         .add_build_internal("testlib", |lib, version| {
@@ -150,11 +612,114 @@
 //! This feature can be controlled using the `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` environment variable
 //! which can have the following values:
 //! - `auto`: build the dependency only if the required version has not been found by `pkg-config`;
-//! - `always`: always build the dependency, ignoring any version which may be installed on the system;
+//! - `always`: always build the dependency, ignoring any version which may be installed on the system.
+//!   The `version` field of the dependency is then optional, the closure receiving an empty version if it is not set;
 //! - `never`: (default) never build the dependency, `system-deps` will fail if the required version is not found on the system.
 //!
 //! You can also use the `SYSTEM_DEPS_BUILD_INTERNAL` environment variable with the same values
 //! defining the behavior for all the dependencies which don't have `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` defined.
+//!
+//! The default behavior can be defined for a specific [feature version](#feature-versions) using `build_internal`,
+//! for example to build only the versions which are not packaged yet:
+//!
+//! ```toml
+//! [package.metadata.system-deps.gstreamer_1_0]
+//! name = "gstreamer-1.0"
+//! version = "1.0"
+//! v1_18 = { version = "1.18", build_internal = "auto" }
+//! ```
+//!
+//! It is used only if this version is selected, and both `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` and
+//! `SYSTEM_DEPS_BUILD_INTERNAL` take precedence over it.
+//!
+//! # Resolve order
+//! The ways of resolving a dependency can also be listed in the order they should be tried using `resolve_order`,
+//! `pkg-config` looking up the library installed on the system and `internal` building it using the closure passed
+//! to [Config::add_build_internal]:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.0", resolve_order = ["internal", "pkg-config"] }
+//! ```
+//!
+//! The first stage succeeding is used, [Library::source] telling which one it was. Each stage has to satisfy the
+//! version of the dependency: the version found by `pkg-config` and the one returned by the closure are both checked.
+//! If no stage succeeds the error of the last stage is reported. Optional dependencies are skipped instead if they have
+//! not been found by `pkg-config`, but the failures of the internal build and the errors in the metadata are still reported.
+//!
+//! The closure is looked up by the key of the dependency first, then by the name of its `pkg-config` module
+//! if it differs, such as `testlib` for `test_lib = { name = "testlib", version = "1.0" }`.
+//!
+//! `resolve_order` takes precedence over the `build_internal` of the feature versions, which is the same as
+//! `["pkg-config", "internal"]` for `auto`, `["internal"]` for `always` and `["pkg-config"]` for `never`.
+//! `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` and `SYSTEM_DEPS_BUILD_INTERNAL` take precedence over it,
+//! and are overridden by `SYSTEM_DEPS_$NAME_RESOLVE_ORDER` defined as a comma separated list of stages,
+//! such as `SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER=pkg-config,internal`.
+//!
+//! # Caching
+//! Build scripts probing many dependencies can avoid running `pkg-config` again when nothing changed by
+//! storing the probed dependencies in a file using [Config::cache_file]:
+//!
+//! ```no_run
+//! let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+//! system_deps::Config::new()
+//!     .cache_file(out_dir.join("system-deps-cache.toml"))
+//!     .probe()
+//!     .unwrap();
+//! ```
+//!
+//! The dependencies are probed again, and the cache rewritten, if:
+//! - the cache file does not exist, cannot be parsed or has been written by another version of `system-deps`;
+//! - `Cargo.toml` or the file it includes has been modified after the cache file;
+//! - the dependencies declared in the metadata, or the ones selected using [Config::probe_only], changed;
+//! - the settings of the [Config] changed, the closures being only compared by name;
+//! - any of the environment variables which may change the results changed, appeared or disappeared:
+//!   `TARGET`, `HOST`, `CARGO_ENCODED_RUSTFLAGS`, `SYSTEM_DEPS_*`, `PKG_CONFIG*`, `CARGO_FEATURE_*`, `CARGO_CFG_TARGET_*`, `CARGO_PKG_VERSION*`,
+//!   `*_STATIC`, `*_DYNAMIC`, `*_DEPLOYMENT_TARGET` and the ones referenced as `${VAR}` in paths.
+//!
+//! The `.pc` files themselves are not checked, so the cache has to be removed, for example by running `cargo clean`,
+//! when the system libraries are upgraded. The same goes for changes in the behavior of the closures passed to the [Config].
+//! The build flags are always emitted, even if the dependencies have been loaded from the cache.
+//!
+//! # Pinning versions
+//! For reproducible builds, [Config::pin_versions] records the version found for each dependency in a file
+//! when first probing, and then fails with [Error::PinnedVersionMismatch] if another version is found:
+//!
+//! ```no_run
+//! system_deps::Config::new()
+//!     .pin_versions("system-deps-versions.toml")
+//!     .probe()
+//!     .unwrap();
+//! ```
+//!
+//! Committing this file detects the version drifts across machines or CI. Dependencies which are not pinned
+//! yet are added to the file, and defining `SYSTEM_DEPS_REFRESH_PINNED_VERSIONS` pins the versions currently found.
+//! Relative paths are resolved from the current directory, which is the crate root when running a build script.
+//!
+//! # Validating the manifest
+//! The metadata can be checked without a build environment, for example by a linter or in CI,
+//! using [validate_manifest]:
+//!
+//! ```no_run
+//! if let Err(errors) = system_deps::validate_manifest(std::path::Path::new("Cargo.toml")) {
+//!     for error in errors {
+//!         eprintln!("{}", error);
+//!     }
+//! }
+//! ```
+//!
+//! It checks that the metadata can be parsed, as well as the `cfg()` expressions and version strings it contains,
+//! without running `pkg-config`, and reports all the invalid keys instead of stopping at the first one.
+//!
+//! To check that a machine has the required system libraries, [Config::check] probes all the dependencies
+//! without emitting any `cargo:` instruction and reports all the dependencies which cannot be found
+//! in the required versions.
+//!
+//! # Logging
+//!
+//! If the `log` feature is enabled, each probing step (`cfg()` evaluation, feature checks, selected version,
+//! environment overrides and `pkg-config` results) is reported through the [log](https://docs.rs/log) crate.
+//! Those records can be displayed by initializing a logger in `build.rs`.
 
 #![deny(missing_docs)]
 
@@ -165,18 +730,41 @@ extern crate lazy_static;
 #[cfg(test)]
 mod test;
 
+// Report probing steps through the `log` crate, if the `log` feature is enabled
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::debug!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)+);
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::trace!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)+);
+    };
+}
+
 use heck::{ShoutySnakeCase, SnakeCase};
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, EnumString};
 use thiserror::Error;
 use version_compare::VersionCompare;
 
+mod cache;
 mod metadata;
 use metadata::MetaData;
 
@@ -185,7 +773,7 @@ use metadata::MetaData;
 pub enum Error {
     /// pkg-config error
     #[error(transparent)]
-    PkgConfig(#[from] pkg_config::Error),
+    PkgConfig(Box<pkg_config::Error>),
     /// One of the `Config::add_build_internal` closures failed
     #[error("Failed to build {0}: {1}")]
     BuildInternalClosureError(String, #[source] BuildInternalClosureError),
@@ -204,6 +792,10 @@ pub enum Error {
     /// contained an invalid value (allowed: `auto`, `always`, `never`)
     #[error("{0}")]
     BuildInternalInvalid(String),
+    /// The environment variable `SYSTEM_DEPS_$NAME_RESOLVE_ORDER`
+    /// contained an invalid value (allowed: `pkg-config`, `internal`)
+    #[error("{0}")]
+    ResolveOrderInvalid(String),
     /// system-deps has been asked to internally build a lib, through
     /// `SYSTEM_DEPS_$NAME_BUILD_INTERNAL=always' or `SYSTEM_DEPS_$NAME_BUILD_INTERNAL=auto',
     /// but not closure has been defined using `Config::add_build_internal` to build
@@ -214,15 +806,126 @@ pub enum Error {
     /// required version defined in `Cargo.toml`
     #[error("Internally built {0} {1} but minimum required version is {2}")]
     BuildInternalWrongVersion(String, String, String),
+    /// The closure registered using `Config::add_build_internal_send` to build
+    /// this lib did not return within the delay set using `Config::build_internal_timeout`
+    #[error("Building {0} internally timed out")]
+    BuildInternalTimeout(String),
+    /// The Apple deployment target is older than the `min_os_version` of a dependency,
+    /// contains the dependency, its minimum version, the env variable defining the
+    /// deployment target and its value
+    #[error("{0} requires an OS version of at least {1} but {2} is set to {3}")]
+    DeploymentTargetTooOld(String, String, String, String),
+    /// The target passed to `Config::probe_for_targets` is not known
+    #[error("Unsupported target: {0}")]
+    UnsupportedTarget(String),
     /// The `cfg()` expression used in `Cargo.toml` is currently not supported
     #[error("Unsupported cfg() expression: {0}")]
     UnsupportedCfg(String),
+    /// Two dependencies declared as conflicting using `conflicts_with` have both been found
+    #[error("{0} conflicts with {1}")]
+    ConflictingDeps(String, String),
+    /// A define listed in the `require_defines` of a dependency is missing or has another value,
+    /// contains the dependency and the required define
+    #[error("{0} does not define {1}")]
+    MissingDefine(String, String),
+    /// The version of a module required by a dependency declared with `requires_same_version`
+    /// differs from the one of the dependency, contains the dependency, the required module and its version
+    #[error("{0} requires {1} {2} whose version does not match")]
+    MismatchedVersion(String, String, String),
+    /// None of the feature versions of a dependency declared with `require_version_feature` is enabled,
+    /// contains the dependency and the features of its feature versions
+    #[error("{0} requires one of the features {1} to be enabled")]
+    MissingVersionFeature(String, String),
+    /// The callback registered using `Config::validate` rejected the probed dependencies
+    #[error("Dependencies validation failed: {0}")]
+    ValidationFailed(String),
+    /// None of the members of a dependency declared using `one_of` has been found,
+    /// contains the key of the dependency and the error of each member
+    #[error("No member of {0} has been found:\n{1}")]
+    NoGroupMember(String, String),
+    /// The `SYSTEM_DEPS_OVERRIDES` environment variable is not a valid `toml` document
+    #[error("Invalid {}: {0}", EnvVariable::Overrides)]
+    InvalidOverrides(String),
+    /// The version of a dependency differs from the one pinned using `Config::pin_versions`,
+    /// contains the dependency, the pinned version and the version found
+    #[error("{0} is pinned to version {1} but version {2} has been found, define {} to update the pinned versions", EnvVariable::RefreshPinnedVersions)]
+    PinnedVersionMismatch(String, String, String),
+    /// The file storing the versions pinned using `Config::pin_versions` cannot be read
+    #[error("Invalid pinned versions file {0}: {1}")]
+    InvalidPinnedVersions(String, String),
+    /// `$CARGO_MANIFEST_DIR` is not set and no manifest has been defined using `Config::manifest_path`
+    #[error("No manifest to read the metadata from: $CARGO_MANIFEST_DIR is not set and no manifest path has been defined")]
+    MissingManifest,
+}
+
+// `pkg_config::Error` is boxed as it is much larger than the other variants
+impl From<pkg_config::Error> for Error {
+    fn from(err: pkg_config::Error) -> Self {
+        Self::PkgConfig(Box::new(err))
+    }
 }
 
 #[derive(Debug, Default)]
 /// All the system dependencies retrieved by [Config::probe].
 pub struct Dependencies {
-    libs: HashMap<String, Library>,
+    // in the order the dependencies have been probed
+    libs: Vec<(String, Library)>,
+    // position of each dependency in `libs`, by key
+    index: HashMap<String, usize>,
+    // env variables of the `Config` used to probe the dependencies making cargo run the build script again
+    config_env_vars: Vec<EnvVariable>,
+    // external files the dependencies have been read from
+    includes: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+/// What triggers probing the dependencies again, as returned by [Dependencies::rerun_triggers].
+pub struct RerunTriggers {
+    /// Environment variables emitted as `cargo:rerun-if-env-changed`
+    pub env_vars: Vec<String>,
+    /// Files emitted as `cargo:rerun-if-changed`
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// The number of dependencies declared in `Cargo.toml` by category, as returned by [Config::dep_summary].
+///
+/// A dependency declared in several `cfg()` tables is counted once, in each category matching any of its declarations.
+pub struct DepSummary {
+    /// Number of declared dependencies
+    pub total: usize,
+    /// Number of dependencies declared with `optional = true`
+    pub optional: usize,
+    /// Number of dependencies only probed if a feature is enabled, declared using `feature`
+    pub feature_gated: usize,
+    /// Number of dependencies declared in a `cfg()` table
+    pub cfg_gated: usize,
+    /// Number of dependencies declaring feature versions
+    pub with_version_overrides: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+/// The `pkg-config` modules pulled in by the dependencies, as returned by [Dependencies::dependency_graph].
+pub struct DependencyGraph {
+    /// Names of the modules, the ones of the dependencies coming first in the order they have been probed
+    pub nodes: Vec<String>,
+    /// `(module, required)` pairs, `required` being listed in the `Requires` field of `module`
+    pub edges: Vec<(String, String)>,
+}
+
+impl DependencyGraph {
+    /// Render the graph in the [DOT](https://graphviz.org/doc/info/lang.html) language.
+    pub fn to_dot(&self) -> String {
+        let mut dot = "digraph system_deps {\n".to_string();
+        for node in self.nodes.iter() {
+            dot.push_str(&format!("    {:?};\n", node));
+        }
+        for (from, to) in self.edges.iter() {
+            dot.push_str(&format!("    {:?} -> {:?};\n", from, to));
+        }
+        dot.push_str("}\n");
+        dot
+    }
 }
 
 impl Dependencies {
@@ -232,7 +935,7 @@ impl Dependencies {
     ///
     /// * `name`: the name of the `toml` key defining the dependency in `Cargo.toml`
     pub fn get_by_name(&self, name: &str) -> Option<&Library> {
-        self.libs.get(name)
+        self.index.get(name).map(|&i| &self.libs[i].1)
     }
 
     /// An iterator visiting all system dependencies in arbitrary order.
@@ -242,14 +945,21 @@ impl Dependencies {
         self.libs.iter().map(|(k, v)| (k.as_str(), v))
     }
 
+    /// An iterator visiting all system dependencies in the order they are declared in `Cargo.toml`.
+    /// Dependencies only declared in an [included file](index.html#including-dependencies-from-another-file)
+    /// come last.
+    pub fn iter_ordered(&self) -> impl Iterator<Item = (&str, &Library)> {
+        self.libs.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
     fn aggregate_str<F: Fn(&Library) -> &Vec<String>>(
         &self,
         getter: F,
     ) -> impl Iterator<Item = &str> {
         self.libs
-            .values()
-            .map(|l| getter(l))
-            .flatten()
+            .iter()
+            .map(|(_, l)| l)
+            .flat_map(getter)
             .map(|s| s.as_str())
             .sorted()
             .dedup()
@@ -260,13 +970,21 @@ impl Dependencies {
         getter: F,
     ) -> impl Iterator<Item = &PathBuf> {
         self.libs
-            .values()
-            .map(|l| getter(l))
-            .flatten()
+            .iter()
+            .map(|(_, l)| l)
+            .flat_map(getter)
             .sorted()
             .dedup()
     }
 
+    // Values in the order they have been found, keeping only their first occurrence
+    fn aggregate_ordered<'a, T: 'a + Eq + std::hash::Hash, F: 'a + Fn(&Library) -> &Vec<T>>(
+        &'a self,
+        getter: F,
+    ) -> impl Iterator<Item = &'a T> {
+        self.libs.iter().map(|(_, l)| l).flat_map(getter).unique()
+    }
+
     /// An iterator returning each [Library::libs] of each library, removing duplicates.
     pub fn all_libs(&self) -> impl Iterator<Item = &str> {
         self.aggregate_str(|l| &l.libs)
@@ -277,6 +995,16 @@ impl Dependencies {
         self.aggregate_path_buf(|l| &l.link_paths)
     }
 
+    /// An iterator returning each [Library::link_paths] of each library, removing duplicates.
+    ///
+    /// Unlike [Dependencies::all_link_paths], paths are not sorted but returned in the order
+    /// they have been found, dependencies being visited in the order they are declared in
+    /// `Cargo.toml`. Only the first occurrence of each path is kept, so a directory meant to
+    /// shadow the system libraries stays in front of them.
+    pub fn all_link_paths_ordered(&self) -> impl Iterator<Item = &PathBuf> {
+        self.aggregate_ordered(|l| &l.link_paths)
+    }
+
     /// An iterator returning each [Library::frameworks] of each library, removing duplicates.
     pub fn all_frameworks(&self) -> impl Iterator<Item = &str> {
         self.aggregate_str(|l| &l.frameworks)
@@ -287,54 +1015,300 @@ impl Dependencies {
         self.aggregate_path_buf(|l| &l.framework_paths)
     }
 
+    /// An iterator returning each [Library::frameworks] of each library, removing duplicates.
+    ///
+    /// Unlike [Dependencies::all_frameworks], frameworks are not sorted but returned in the order
+    /// they have been found, dependencies being visited in the order they are declared in
+    /// `Cargo.toml`. Only the first occurrence of each framework is kept.
+    pub fn all_frameworks_ordered(&self) -> impl Iterator<Item = &str> {
+        self.aggregate_ordered(|l| &l.frameworks)
+            .map(|s| s.as_str())
+    }
+
+    /// An iterator returning each [Library::framework_paths] of each library, removing duplicates.
+    ///
+    /// Unlike [Dependencies::all_framework_paths], paths are not sorted but returned in the order
+    /// they have been found, as in [Dependencies::all_frameworks_ordered].
+    pub fn all_framework_paths_ordered(&self) -> impl Iterator<Item = &PathBuf> {
+        self.aggregate_ordered(|l| &l.framework_paths)
+    }
+
     /// An iterator returning each [Library::include_paths] of each library, removing duplicates.
+    ///
+    /// Only the public include paths are returned, see [Dependencies::all_include_paths_with_private]
+    /// to also get the ones needed when linking statically.
     pub fn all_include_paths(&self) -> impl Iterator<Item = &PathBuf> {
         self.aggregate_path_buf(|l| &l.include_paths)
     }
 
-    /// An iterator returning each [Library::defines] of each library, removing duplicates.
-    pub fn all_defines(&self) -> impl Iterator<Item = (&str, &Option<String>)> {
+    /// An iterator returning each [Library::include_paths] and [Library::private_include_paths]
+    /// of each library, removing duplicates.
+    pub fn all_include_paths_with_private(&self) -> impl Iterator<Item = &PathBuf> {
         self.libs
-            .values()
-            .map(|l| l.defines.iter())
-            .flatten()
-            .map(|(k, v)| (k.as_str(), v))
+            .iter()
+            .flat_map(|(_, l)| l.include_paths.iter().chain(l.private_include_paths.iter()))
             .sorted()
             .dedup()
     }
 
+    /// An iterator returning each [Library::defines] of each library, sorted by name and removing duplicates.
+    ///
+    /// A macro defined with different values by several libraries is only returned once, with the value of
+    /// the first library defining it in probing order. [Config::probe] emits a warning for such conflicts.
+    pub fn all_defines(&self) -> impl Iterator<Item = (&str, &Option<String>)> {
+        self.libs
+            .iter()
+            .flat_map(|(_, l)| l.defines.iter())
+            .map(|(k, v)| (k.as_str(), v))
+            // the sort is stable so the first library defining a macro comes first
+            .sorted_by_key(|(k, _)| *k)
+            .dedup_by(|(a, _), (b, _)| a == b)
+    }
+
+    /// An iterator returning each [Library::defines] of each library, keeping only the first value
+    /// of the macros defined by several libraries.
+    ///
+    /// The libraries are iterated in the order their dependencies are declared in `Cargo.toml`,
+    /// so a dependency can shadow the defines of the ones declared after it. The defines of each
+    /// library are returned sorted by name.
+    pub fn all_defines_first_wins(&self) -> impl Iterator<Item = (&str, &Option<String>)> {
+        let mut seen = std::collections::HashSet::new();
+        self.libs
+            .iter()
+            .flat_map(|(_, l)| l.defines.iter().sorted())
+            .map(|(k, v)| (k.as_str(), v))
+            .filter(move |(k, _)| seen.insert(*k))
+    }
+
+    // Warnings for the macros defined with different values by several libraries
+    fn conflicting_defines(&self) -> Vec<String> {
+        let mut defines: BTreeMap<&str, Vec<(&str, &Option<String>)>> = BTreeMap::new();
+        for (name, lib) in self.libs.iter() {
+            for (define, value) in lib.defines.iter() {
+                defines
+                    .entry(define)
+                    .or_default()
+                    .push((name.as_str(), value));
+            }
+        }
+
+        let format = |define: &str, value: &Option<String>| match value {
+            Some(value) => format!("{}={}", define, value),
+            None => define.to_string(),
+        };
+
+        defines
+            .into_iter()
+            .filter(|(_, values)| values.iter().map(|(_, v)| v).dedup().count() > 1)
+            .map(|(define, values)| {
+                format!(
+                    "{} is defined with conflicting values: {}; using {}",
+                    define,
+                    values
+                        .iter()
+                        .map(|(name, value)| format!("{} defines {}", name, format(define, value)))
+                        .join(", "),
+                    format(define, values[0].1)
+                )
+            })
+            .collect()
+    }
+
+    /// An iterator returning each [Library::defines] of each library as compiler arguments,
+    /// removing duplicates. See [Library::defines_as_args].
+    pub fn all_defines_as_args(&self) -> impl Iterator<Item = String> + '_ {
+        self.all_defines().map(|(k, v)| define_as_arg(k, v))
+    }
+
+    /// The environment variables and files which make cargo run the build script again
+    /// when they change, matching the `cargo:rerun-if-env-changed` and `cargo:rerun-if-changed`
+    /// instructions emitted by [Config::probe].
+    pub fn rerun_triggers(&self) -> RerunTriggers {
+        RerunTriggers {
+            env_vars: self
+                .rerun_env_vars()
+                .into_iter()
+                .map(|var| var.to_string())
+                .collect(),
+            paths: self.rerun_paths(),
+        }
+    }
+
+    /// The graph of the `pkg-config` modules of the dependencies and of the ones they
+    /// transitively require.
+    ///
+    /// The `Requires` fields are only retrieved if enabled using [Config::capture_requires],
+    /// otherwise the graph only contains the modules of the dependencies.
+    pub fn dependency_graph(&self) -> DependencyGraph {
+        let mut graph = DependencyGraph::default();
+        for (_, lib) in self.libs.iter() {
+            if !graph.nodes.contains(&lib.name) {
+                graph.nodes.push(lib.name.clone());
+            }
+        }
+        for (module, requires) in self.libs.iter().flat_map(|(_, l)| &l.requires_graph) {
+            for required in requires {
+                if !graph.nodes.contains(required) {
+                    graph.nodes.push(required.clone());
+                }
+                let edge = (module.clone(), required.clone());
+                if !graph.edges.contains(&edge) {
+                    graph.edges.push(edge);
+                }
+            }
+        }
+        graph
+    }
+
+    // The files which make cargo run the build script again when they change
+    fn rerun_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.includes.clone();
+        for (_, lib) in self.libs.iter() {
+            extend_dedup(&mut paths, &lib.link_arg_files);
+        }
+        paths
+    }
+
     fn add(&mut self, name: &str, lib: Library) {
-        self.libs.insert(name.to_string(), lib);
+        match self.index.get(name) {
+            Some(&i) => self.libs[i].1 = lib,
+            None => {
+                self.index.insert(name.to_string(), self.libs.len());
+                self.libs.push((name.to_string(), lib));
+            }
+        }
+    }
+
+    // Sort the dependencies by `key`, keeping the index in sync
+    fn sort_by_key<K: Ord>(&mut self, mut key: impl FnMut(&str) -> K) {
+        self.libs.sort_by_key(|(k, _)| key(k));
+        self.index = self
+            .libs
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _))| (k.clone(), i))
+            .collect();
+    }
+
+    fn rewrite_paths(&mut self, rewrite: &FnRewritePath) {
+        for (_, lib) in self.libs.iter_mut() {
+            for paths in [
+                &mut lib.link_paths,
+                &mut lib.framework_paths,
+                &mut lib.include_paths,
+                &mut lib.private_include_paths,
+            ] {
+                *paths = paths.iter().map(|p| rewrite(p)).collect();
+            }
+        }
+    }
+
+    fn add_extra_paths(&mut self, link_paths: &[PathBuf], include_paths: &[PathBuf]) {
+        for (_, lib) in self.libs.iter_mut() {
+            prepend_dedup(&mut lib.link_paths, link_paths);
+            prepend_dedup(&mut lib.include_paths, include_paths);
+        }
     }
 
-    fn override_from_flags(&mut self, env: &EnvVariables) {
+    fn set_include_arg_style(&mut self, style: IncludeArgStyle) {
+        for (_, lib) in self.libs.iter_mut() {
+            lib.include_arg_style = style;
+        }
+    }
+
+    fn override_from_flags(&mut self, env: &EnvVariables) -> Result<(), Error> {
+        let overrides = match env.get(&EnvVariable::Overrides) {
+            Some(value) => parse_overrides(&value)?,
+            None => HashMap::new(),
+        };
+
         for (name, lib) in self.libs.iter_mut() {
-            if let Some(value) = env.get(&EnvVariable::new_search_native(name)) {
+            // Individual env variables take precedence over the bulk overrides
+            if let Some(o) = overrides.get(name) {
+                debug!(
+                    "{}: applying overrides from {}",
+                    name,
+                    EnvVariable::Overrides
+                );
+                o.apply(lib);
+                lib.applied_env_vars
+                    .push(EnvVariable::Overrides.to_string());
+            }
+
+            let var = EnvVariable::new_search_native(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: link_paths overridden by {}", name, var);
                 lib.link_paths = split_paths(&value);
+                lib.applied_env_vars.push(var.to_string());
             }
-            if let Some(value) = env.get(&EnvVariable::new_search_framework(name)) {
+            let var = EnvVariable::new_search_framework(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: framework_paths overridden by {}", name, var);
                 lib.framework_paths = split_paths(&value);
+                lib.applied_env_vars.push(var.to_string());
+            }
+            let var = EnvVariable::new_search(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: search_paths overridden by {}", name, var);
+                lib.search_paths = split_paths(&value);
+                lib.applied_env_vars.push(var.to_string());
             }
-            if let Some(value) = env.get(&EnvVariable::new_lib(name)) {
+            let var = EnvVariable::new_lib(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: libs overridden by {}", name, var);
                 lib.libs = split_string(&value);
+                lib.applied_env_vars.push(var.to_string());
             }
-            if let Some(value) = env.get(&EnvVariable::new_lib_framework(name)) {
+            let var = EnvVariable::new_lib_remove(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: libs removed by {}", name, var);
+                let remove = split_string(&value);
+                lib.libs.retain(|l| !remove.contains(l));
+                lib.applied_env_vars.push(var.to_string());
+            }
+            let var = EnvVariable::new_lib_framework(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: frameworks overridden by {}", name, var);
                 lib.frameworks = split_string(&value);
+                lib.applied_env_vars.push(var.to_string());
+            }
+            let var = EnvVariable::new_framework_append(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: frameworks appended by {}", name, var);
+                extend_dedup(&mut lib.frameworks, &split_string(&value));
+                lib.applied_env_vars.push(var.to_string());
             }
-            if let Some(value) = env.get(&EnvVariable::new_include(name)) {
+            let var = EnvVariable::new_include(name);
+            if let Some(value) = env.get(&var) {
+                debug!("{}: include_paths overridden by {}", name, var);
                 lib.include_paths = split_paths(&value);
+                lib.applied_env_vars.push(var.to_string());
             }
         }
+
+        Ok(())
     }
 
-    fn gen_flags(&self) -> Result<BuildFlags, Error> {
+    fn gen_flags(&self, config: &Config) -> Result<BuildFlags, Error> {
         let mut flags = BuildFlags::new();
         let mut include_paths = Vec::new();
+        let mut link_args = LinkArgs::default();
+        let emit_rpath = config.emit_rpath && !config.target_is_windows();
+        let link_groups = config.target_supports_link_groups();
+        // rustc already looks up the paths passed using `RUSTFLAGS`
+        let rustflags_link_paths = config.rustflags_link_paths();
 
-        for (name, lib) in self.libs.iter() {
+        // Flags are emitted in the order documented in the "Build flags order" section.
+        // Aliases share the flags of their original dependency.
+        for (name, lib) in self
+            .libs
+            .iter()
+            .filter(|(_, lib)| lib.same_as.is_none() && lib.emit)
+        {
             include_paths.extend(lib.include_paths.clone());
 
             if lib.source == Source::EnvVariables
+                && !lib.defines_only
                 && lib.libs.is_empty()
                 && lib.frameworks.is_empty()
             {
@@ -343,47 +1317,222 @@ impl Dependencies {
 
             lib.link_paths
                 .iter()
+                .filter(|l| !rustflags_link_paths.contains(l))
                 .for_each(|l| flags.add(BuildFlag::SearchNative(l.to_string_lossy().to_string())));
             lib.framework_paths.iter().for_each(|f| {
                 flags.add(BuildFlag::SearchFramework(f.to_string_lossy().to_string()))
             });
-            lib.libs
-                .iter()
-                .for_each(|l| flags.add(BuildFlag::Lib(l.clone())));
-            lib.frameworks
+            lib.search_paths
                 .iter()
-                .for_each(|f| flags.add(BuildFlag::LibFramework(f.clone())));
+                .for_each(|s| flags.add(BuildFlag::Search(s.to_string_lossy().to_string())));
+            if lib.link_group && link_groups {
+                // rustc cannot group libs so they are passed to the linker directly
+                flags.add(BuildFlag::LinkArg("-Wl,--start-group".into()));
+                lib.libs.iter().for_each(|l| {
+                    let whole_archive = lib.whole_archive_libs.contains(l);
+                    let statik = whole_archive || lib.static_libs.contains(l);
+                    let mut args = vec![format!("-l{}", l)];
+                    if whole_archive {
+                        args.insert(0, "-Wl,--whole-archive".into());
+                        args.push("-Wl,--no-whole-archive".into());
+                    }
+                    if statik {
+                        args.insert(0, "-Wl,-Bstatic".into());
+                        args.push("-Wl,-Bdynamic".into());
+                    }
+                    args.into_iter()
+                        .for_each(|a| flags.add(BuildFlag::LinkArg(a)));
+                });
+                flags.add(BuildFlag::LinkArg("-Wl,--end-group".into()));
+            } else {
+                lib.libs.iter().for_each(|l| {
+                    if lib.whole_archive_libs.contains(l) {
+                        flags.add(BuildFlag::LibWholeArchive(l.clone()))
+                    } else if lib.static_libs.contains(l) {
+                        flags.add(BuildFlag::LibStatic(l.clone()))
+                    } else if lib.dylib_libs.contains(l) {
+                        flags.add(BuildFlag::LibDylib(l.clone()))
+                    } else {
+                        flags.add(BuildFlag::Lib(l.clone()))
+                    }
+                });
+            }
+            lib.verbatim_libs.iter().for_each(|l| {
+                let kind = if lib.statik || lib.static_libs.contains(l) {
+                    LinkKind::Static
+                } else {
+                    LinkKind::Dylib
+                };
+                flags.add(BuildFlag::VerbatimLib(l.clone(), kind))
+            });
+            lib.frameworks.iter().for_each(|f| {
+                if config.is_weak_framework(lib, f) {
+                    flags.add(BuildFlag::LinkArg(format!("-Wl,-weak_framework,{}", f)))
+                } else {
+                    flags.add(BuildFlag::LibFramework(f.clone()))
+                }
+            });
+            if lib.statik {
+                // frameworks are always linked dynamically
+                lib.frameworks.iter().for_each(|f| {
+                    flags.add(BuildFlag::Warning(format!(
+                        "{}: framework {} cannot be linked statically, it is linked dynamically",
+                        name, f
+                    )))
+                });
+            }
+
+            // available as `DEP_$LINKS_$NAME_$VARIABLE` to the build scripts of the dependent crates
+            for (variable, value) in lib.exported_variables.iter() {
+                flags.add(BuildFlag::Metadata(
+                    format!("{}_{}", name, variable),
+                    value.clone(),
+                ));
+            }
+
+            link_args.extend(lib);
+            if emit_rpath {
+                link_args.extend_rpath(lib);
+            }
         }
 
+        link_args.add_flags(&mut flags);
+
         // Export DEP_$CRATE_INCLUDE env variable with the headers paths,
         // see https://kornel.ski/rust-sys-crate#headers
-        if !include_paths.is_empty() {
+        if config.emit_include && !include_paths.is_empty() {
             if let Ok(paths) = std::env::join_paths(include_paths) {
                 flags.add(BuildFlag::Include(paths.to_string_lossy().to_string()));
             }
         }
 
+        // Only one of the values is returned by `all_defines`
+        for warning in self.conflicting_defines() {
+            flags.add(BuildFlag::Warning(warning));
+        }
+
         // Export cargo:rerun-if-env-changed instructions for all env variables affecting system-deps behaviour
-        flags.add(BuildFlag::RerunIfEnvChanged(
+        for var in self.rerun_env_vars() {
+            flags.add(BuildFlag::RerunIfEnvChanged(var));
+        }
+
+        Ok(flags)
+    }
+
+    fn rerun_env_vars(&self) -> Vec<EnvVariable> {
+        let mut vars = vec![
             EnvVariable::new_build_internal(None),
-        ));
+            EnvVariable::new_allow_older(None),
+            EnvVariable::Overrides,
+            EnvVariable::PkgConfigLibdir,
+        ];
 
         for (name, _lib) in self.libs.iter() {
             for var in EnvVariable::iter() {
                 let var = match var {
                     EnvVariable::Lib(_) => EnvVariable::new_lib(name),
+                    EnvVariable::LibRemove(_) => EnvVariable::new_lib_remove(name),
                     EnvVariable::LibFramework(_) => EnvVariable::new_lib_framework(name),
+                    EnvVariable::FrameworkAppend(_) => EnvVariable::new_framework_append(name),
                     EnvVariable::SearchNative(_) => EnvVariable::new_search_native(name),
                     EnvVariable::SearchFramework(_) => EnvVariable::new_search_framework(name),
+                    EnvVariable::Search(_) => EnvVariable::new_search(name),
                     EnvVariable::Include(_) => EnvVariable::new_include(name),
                     EnvVariable::NoPkgConfig(_) => EnvVariable::new_no_pkg_config(name),
+                    EnvVariable::ForceMissing(_) => EnvVariable::new_force_missing(name),
+                    EnvVariable::NoEmit(_) => EnvVariable::new_no_emit(name),
+                    EnvVariable::ResolveOrder(_) => EnvVariable::new_resolve_order(name),
                     EnvVariable::BuildInternal(_) => EnvVariable::new_build_internal(Some(name)),
+                    EnvVariable::AllowOlder(_) => EnvVariable::new_allow_older(Some(name)),
+                    EnvVariable::Overrides
+                    | EnvVariable::RefreshPinnedVersions
+                    | EnvVariable::PkgConfigLibdir
+                    | EnvVariable::PkgConfigEnv(_) => continue,
                 };
-                flags.add(BuildFlag::RerunIfEnvChanged(var));
+                vars.push(var);
             }
         }
+        vars.extend(self.config_env_vars.iter().cloned());
 
-        Ok(flags)
+        vars
+    }
+}
+
+// Env variables defining the minimum OS version of each Apple OS, as found in target triples
+const APPLE_DEPLOYMENT_TARGETS: &[(&str, &str)] = &[
+    ("darwin", "MACOSX_DEPLOYMENT_TARGET"),
+    ("ios", "IPHONEOS_DEPLOYMENT_TARGET"),
+    ("tvos", "TVOS_DEPLOYMENT_TARGET"),
+    ("watchos", "WATCHOS_DEPLOYMENT_TARGET"),
+    ("visionos", "XROS_DEPLOYMENT_TARGET"),
+];
+
+// `cfg()` expressions evaluated while probing the dependencies
+#[derive(Debug, Default)]
+struct CfgCache {
+    // the builtin target, resolved when evaluating the first expression
+    target: Option<Option<&'static cfg_expr::targets::TargetInfo<'static>>>,
+    // result of each expression
+    results: HashMap<String, bool>,
+}
+
+// Version of the libs whose `.pc` file does not define it
+const UNKNOWN_VERSION: &str = "unknown";
+
+fn extend_dedup<T: PartialEq + Clone>(values: &mut Vec<T>, new: &[T]) {
+    for value in new {
+        if !values.contains(value) {
+            values.push(value.clone());
+        }
+    }
+}
+
+fn prepend_dedup<T: PartialEq + Clone>(values: &mut Vec<T>, new: &[T]) {
+    let mut res = Vec::new();
+    extend_dedup(&mut res, new);
+    extend_dedup(&mut res, values);
+    *values = res;
+}
+
+// Link arguments of all the libraries, deduplicated independently for each scope
+#[derive(Debug, Default)]
+struct LinkArgs {
+    all: Vec<String>,
+    bins: Vec<String>,
+    tests: Vec<String>,
+    benches: Vec<String>,
+}
+
+impl LinkArgs {
+    fn extend(&mut self, lib: &Library) {
+        extend_dedup(&mut self.all, &lib.link_args);
+        extend_dedup(&mut self.bins, &lib.link_args_bins);
+        extend_dedup(&mut self.tests, &lib.link_args_tests);
+        extend_dedup(&mut self.benches, &lib.link_args_benches);
+    }
+
+    fn extend_rpath(&mut self, lib: &Library) {
+        let rpaths = lib
+            .link_paths
+            .iter()
+            .map(|p| format!("-Wl,-rpath,{}", p.to_string_lossy()))
+            .collect::<Vec<_>>();
+        extend_dedup(&mut self.all, &rpaths);
+    }
+
+    fn add_flags(self, flags: &mut BuildFlags) {
+        self.all
+            .into_iter()
+            .for_each(|a| flags.add(BuildFlag::LinkArg(a)));
+        self.bins
+            .into_iter()
+            .for_each(|a| flags.add(BuildFlag::LinkArgBins(a)));
+        self.tests
+            .into_iter()
+            .for_each(|a| flags.add(BuildFlag::LinkArgTests(a)));
+        self.benches
+            .into_iter()
+            .for_each(|a| flags.add(BuildFlag::LinkArgBenches(a)));
     }
 }
 
@@ -392,12 +1541,18 @@ impl Dependencies {
 pub enum BuildInternalClosureError {
     /// `pkg-config` error
     #[error(transparent)]
-    PkgConfig(#[from] pkg_config::Error),
+    PkgConfig(Box<pkg_config::Error>),
     /// General failure
     #[error("{0}")]
     Failed(String),
 }
 
+impl From<pkg_config::Error> for BuildInternalClosureError {
+    fn from(err: pkg_config::Error) -> Self {
+        Self::PkgConfig(Box::new(err))
+    }
+}
+
 impl BuildInternalClosureError {
     /// Create a new `BuildInternalClosureError::Failed` representing a general
     /// failure.
@@ -411,15 +1566,28 @@ impl BuildInternalClosureError {
 }
 
 // enums representing the environment variables user can define to tune system-deps
-#[derive(Debug, PartialEq, EnumIter)]
+#[derive(Debug, Clone, PartialEq, EnumIter)]
 enum EnvVariable {
     Lib(String),
+    LibRemove(String),
     LibFramework(String),
+    FrameworkAppend(String),
     SearchNative(String),
     SearchFramework(String),
+    Search(String),
     Include(String),
     NoPkgConfig(String),
+    ForceMissing(String),
+    NoEmit(String),
+    ResolveOrder(String),
     BuildInternal(Option<String>),
+    AllowOlder(Option<String>),
+    Overrides,
+    RefreshPinnedVersions,
+    // read by pkg-config itself
+    PkgConfigLibdir,
+    // set using `Config::pkg_config_env`
+    PkgConfigEnv(String),
 }
 
 impl EnvVariable {
@@ -427,10 +1595,18 @@ impl EnvVariable {
         Self::Lib(lib.to_string())
     }
 
+    fn new_lib_remove(lib: &str) -> Self {
+        Self::LibRemove(lib.to_string())
+    }
+
     fn new_lib_framework(lib: &str) -> Self {
         Self::LibFramework(lib.to_string())
     }
 
+    fn new_framework_append(lib: &str) -> Self {
+        Self::FrameworkAppend(lib.to_string())
+    }
+
     fn new_search_native(lib: &str) -> Self {
         Self::SearchNative(lib.to_string())
     }
@@ -439,6 +1615,10 @@ impl EnvVariable {
         Self::SearchFramework(lib.to_string())
     }
 
+    fn new_search(lib: &str) -> Self {
+        Self::Search(lib.to_string())
+    }
+
     fn new_include(lib: &str) -> Self {
         Self::Include(lib.to_string())
     }
@@ -447,19 +1627,46 @@ impl EnvVariable {
         Self::NoPkgConfig(lib.to_string())
     }
 
+    fn new_force_missing(lib: &str) -> Self {
+        Self::ForceMissing(lib.to_string())
+    }
+
+    fn new_no_emit(lib: &str) -> Self {
+        Self::NoEmit(lib.to_string())
+    }
+
+    fn new_resolve_order(lib: &str) -> Self {
+        Self::ResolveOrder(lib.to_string())
+    }
+
     fn new_build_internal(lib: Option<&str>) -> Self {
         Self::BuildInternal(lib.map(|l| l.to_string()))
     }
 
+    fn new_allow_older(lib: Option<&str>) -> Self {
+        Self::AllowOlder(lib.map(|l| l.to_string()))
+    }
+
     fn suffix(&self) -> &'static str {
         match self {
             EnvVariable::Lib(_) => "LIB",
+            EnvVariable::LibRemove(_) => "LIB_REMOVE",
             EnvVariable::LibFramework(_) => "LIB_FRAMEWORK",
+            EnvVariable::FrameworkAppend(_) => "FRAMEWORK_APPEND",
             EnvVariable::SearchNative(_) => "SEARCH_NATIVE",
             EnvVariable::SearchFramework(_) => "SEARCH_FRAMEWORK",
+            EnvVariable::Search(_) => "SEARCH",
             EnvVariable::Include(_) => "INCLUDE",
             EnvVariable::NoPkgConfig(_) => "NO_PKG_CONFIG",
+            EnvVariable::ForceMissing(_) => "FORCE_MISSING",
+            EnvVariable::NoEmit(_) => "NO_EMIT",
+            EnvVariable::ResolveOrder(_) => "RESOLVE_ORDER",
             EnvVariable::BuildInternal(_) => "BUILD_INTERNAL",
+            EnvVariable::AllowOlder(_) => "ALLOW_OLDER",
+            EnvVariable::Overrides => "OVERRIDES",
+            EnvVariable::RefreshPinnedVersions => "REFRESH_PINNED_VERSIONS",
+            EnvVariable::PkgConfigLibdir => "PKG_CONFIG_LIBDIR",
+            EnvVariable::PkgConfigEnv(_) => "",
         }
     }
 }
@@ -468,27 +1675,215 @@ impl fmt::Display for EnvVariable {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let suffix = match self {
             EnvVariable::Lib(lib)
+            | EnvVariable::LibRemove(lib)
             | EnvVariable::LibFramework(lib)
+            | EnvVariable::FrameworkAppend(lib)
             | EnvVariable::SearchNative(lib)
             | EnvVariable::SearchFramework(lib)
+            | EnvVariable::Search(lib)
             | EnvVariable::Include(lib)
             | EnvVariable::NoPkgConfig(lib)
-            | EnvVariable::BuildInternal(Some(lib)) => {
+            | EnvVariable::ForceMissing(lib)
+            | EnvVariable::NoEmit(lib)
+            | EnvVariable::ResolveOrder(lib)
+            | EnvVariable::BuildInternal(Some(lib))
+            | EnvVariable::AllowOlder(Some(lib)) => {
                 format!("{}_{}", lib.to_shouty_snake_case(), self.suffix())
             }
-            EnvVariable::BuildInternal(None) => self.suffix().to_string(),
+            EnvVariable::BuildInternal(None)
+            | EnvVariable::AllowOlder(None)
+            | EnvVariable::Overrides
+            | EnvVariable::RefreshPinnedVersions => self.suffix().to_string(),
+            EnvVariable::PkgConfigLibdir => return f.write_str(self.suffix()),
+            EnvVariable::PkgConfigEnv(var) => return f.write_str(var),
         };
         write!(f, "SYSTEM_DEPS_{}", suffix)
     }
 }
 
+// Overrides of a library defined in `SYSTEM_DEPS_OVERRIDES`
+#[derive(Debug, Default, PartialEq)]
+struct LibOverrides {
+    libs: Option<Vec<String>>,
+    link_paths: Option<Vec<PathBuf>>,
+    search_paths: Option<Vec<PathBuf>>,
+    frameworks: Option<Vec<String>>,
+    framework_paths: Option<Vec<PathBuf>>,
+    include_paths: Option<Vec<PathBuf>>,
+}
+
+impl LibOverrides {
+    fn apply(&self, lib: &mut Library) {
+        if let Some(libs) = &self.libs {
+            lib.libs = libs.clone();
+        }
+        if let Some(link_paths) = &self.link_paths {
+            lib.link_paths = link_paths.clone();
+        }
+        if let Some(search_paths) = &self.search_paths {
+            lib.search_paths = search_paths.clone();
+        }
+        if let Some(frameworks) = &self.frameworks {
+            lib.frameworks = frameworks.clone();
+        }
+        if let Some(framework_paths) = &self.framework_paths {
+            lib.framework_paths = framework_paths.clone();
+        }
+        if let Some(include_paths) = &self.include_paths {
+            lib.include_paths = include_paths.clone();
+        }
+    }
+}
+
+fn parse_overrides(value: &str) -> Result<HashMap<String, LibOverrides>, Error> {
+    fn strings(name: &str, key: &str, value: &toml::Value) -> Result<Vec<String>, Error> {
+        value
+            .as_array()
+            .and_then(|a| {
+                a.iter()
+                    .map(|v| v.as_str().map(|s| s.to_string()))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| {
+                Error::InvalidOverrides(format!("{}.{} not an array of strings", name, key))
+            })
+    }
+
+    fn paths(name: &str, key: &str, value: &toml::Value) -> Result<Vec<PathBuf>, Error> {
+        Ok(strings(name, key, value)?
+            .into_iter()
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    let doc = value
+        .parse::<toml::Value>()
+        .map_err(|e| Error::InvalidOverrides(format!("error parsing TOML: {}", e)))?;
+    let doc = doc
+        .as_table()
+        .ok_or_else(|| Error::InvalidOverrides("not a table".into()))?;
+
+    let mut overrides = HashMap::new();
+
+    for (name, settings) in doc {
+        let settings = settings
+            .as_table()
+            .ok_or_else(|| Error::InvalidOverrides(format!("{} not a table", name)))?;
+        let mut o = LibOverrides::default();
+
+        for (key, value) in settings {
+            match key.as_str() {
+                "libs" => o.libs = Some(strings(name, key, value)?),
+                "link_paths" => o.link_paths = Some(paths(name, key, value)?),
+                "search_paths" => o.search_paths = Some(paths(name, key, value)?),
+                "frameworks" => o.frameworks = Some(strings(name, key, value)?),
+                "framework_paths" => o.framework_paths = Some(paths(name, key, value)?),
+                "include_paths" => o.include_paths = Some(paths(name, key, value)?),
+                _ => {
+                    return Err(Error::InvalidOverrides(format!(
+                        "{}: unexpected key {}",
+                        name, key
+                    )))
+                }
+            }
+        }
+
+        overrides.insert(name.clone(), o);
+    }
+
+    Ok(overrides)
+}
+
 type FnBuildInternal =
-    dyn FnOnce(&str, &str) -> std::result::Result<Library, BuildInternalClosureError>;
+    dyn FnOnce(&BuildInternalContext) -> std::result::Result<Library, BuildInternalClosureError>;
+type FnBuildInternalSend = dyn Send
+    + FnOnce(&BuildInternalContext) -> std::result::Result<Library, BuildInternalClosureError>;
+
+// Closures registered to build the libs internally
+enum BuildInternalFn {
+    // called on the current thread
+    Local(Box<FnBuildInternal>),
+    // called on a worker thread so it can time out
+    Send(Box<FnBuildInternalSend>),
+}
+
+#[derive(Debug, Clone)]
+/// Details about the library to build passed to the closures registered using
+/// [Config::add_build_internal_ctx]
+pub struct BuildInternalContext {
+    /// Name of the library
+    pub name: String,
+    /// Minimum version of the library required
+    pub version: String,
+    /// Target triple the crate is built for, from the `TARGET` environment variable
+    pub target: Option<String>,
+    /// Whether the library should be linked statically
+    pub statik: bool,
+    /// Features enabled on the crate being built, in lower case and with `_` instead of `-`,
+    /// as reported by the `CARGO_FEATURE_$NAME` environment variables
+    pub enabled_features: Vec<String>,
+}
+
+/// Check the `package.metadata.system-deps` section of the manifest at `path` without probing.
+///
+/// Returns all the problems found, each error naming the offending key.
+///
+/// # Arguments
+/// * `path`: the path of the `Cargo.toml` to check
+pub fn validate_manifest(path: &Path) -> Result<(), Vec<Error>> {
+    let errors = MetaData::validate_file(path);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+type FnValidate = dyn FnOnce(&Dependencies) -> std::result::Result<(), String>;
+type FnRewritePath = dyn Fn(&Path) -> PathBuf;
+type FnProbeHook = dyn FnMut(&str);
 
 /// Structure used to configure `metadata` before starting to probe for dependencies
 pub struct Config {
     env: EnvVariables,
-    build_internals: HashMap<String, Box<FnBuildInternal>>,
+    build_internals: HashMap<String, BuildInternalFn>,
+    build_internal_timeout: Option<Duration>,
+    emit_have_cfgs: bool,
+    emit_define_cfgs: bool,
+    emit_version_cfgs: bool,
+    emit_include: bool,
+    emit_rpath: bool,
+    prefer_homebrew: bool,
+    respect_rustflags_link_paths: bool,
+    pkg_config_libdir: Option<PathBuf>,
+    // env variables set while probing, sorted by name
+    pkg_config_env: Vec<(String, String)>,
+    include_arg_style: IncludeArgStyle,
+    default_version: Option<String>,
+    // minimum versions set by `set_version`, by dependency key
+    versions: HashMap<String, String>,
+    capture_requires: bool,
+    strict_feature_versions: bool,
+    print_system_libs: Option<bool>,
+    extra_link_paths: Vec<PathBuf>,
+    extra_include_paths: Vec<PathBuf>,
+    validate: Option<Box<FnValidate>>,
+    rewrite_path: Option<Box<FnRewritePath>>,
+    before_probe: Option<Box<FnProbeHook>>,
+    after_probe: Option<Box<FnProbeHook>>,
+    // keys of the only dependencies to probe, set by `probe_only`
+    only: Option<Vec<String>>,
+    // target overriding `TARGET`, set by `probe_for_targets`
+    target: Option<String>,
+    // features overriding the `CARGO_FEATURE_*` env variables
+    features: Option<Vec<String>>,
+    cache_file: Option<PathBuf>,
+    check_orig_manifest: bool,
+    // manifest overriding the one in `$CARGO_MANIFEST_DIR`
+    manifest_path: Option<PathBuf>,
+    pinned_versions: Option<PathBuf>,
+    // warnings emitted while probing the dependencies
+    warnings: Vec<String>,
 }
 
 impl Default for Config {
@@ -507,6 +1902,36 @@ impl Config {
         Self {
             env,
             build_internals: HashMap::new(),
+            build_internal_timeout: None,
+            emit_have_cfgs: true,
+            emit_define_cfgs: false,
+            emit_version_cfgs: false,
+            emit_include: true,
+            emit_rpath: false,
+            prefer_homebrew: false,
+            respect_rustflags_link_paths: false,
+            pkg_config_libdir: None,
+            pkg_config_env: Vec::new(),
+            include_arg_style: IncludeArgStyle::Normal,
+            default_version: None,
+            versions: HashMap::new(),
+            capture_requires: false,
+            strict_feature_versions: false,
+            print_system_libs: None,
+            extra_link_paths: Vec::new(),
+            extra_include_paths: Vec::new(),
+            validate: None,
+            rewrite_path: None,
+            before_probe: None,
+            after_probe: None,
+            only: None,
+            target: None,
+            features: None,
+            cache_file: None,
+            check_orig_manifest: false,
+            manifest_path: None,
+            pinned_versions: None,
+            warnings: Vec::new(),
         }
     }
 
@@ -515,142 +1940,1580 @@ impl Config {
     ///
     /// The returned hash is using the the `toml` key defining the dependency as key.
     pub fn probe(self) -> Result<Dependencies, Error> {
-        let libraries = self.probe_full()?;
-        let flags = libraries.gen_flags()?;
+        let (libraries, flags) = self.probe_to_flags()?;
 
         // Output cargo flags
-        println!("{}", flags);
-
-        for (name, _) in libraries.iter() {
-            println!("cargo:rustc-cfg=system_deps_have_{}", name.to_snake_case());
+        for flag in flags {
+            println!("{}", flag);
         }
 
         Ok(libraries)
     }
 
-    /// Add hook so system-deps can internally build library `name` if requested by user.
+    /// Probe only the libraries defined by the `names` keys in the Cargo.toml
+    /// `[package.metadata.system-deps]` section, the other dependencies being ignored.
     ///
-    /// It will only be triggered if the environment variable
-    /// `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` is defined with either `always` or
-    /// `auto` as value. In the latter case, `func` is called only if the requested
-    /// version of the library was not found on the system.
+    /// The build flags are only emitted for those dependencies.
+    /// Fails with [Error::InvalidMetadata] if some of the `names` are not declared.
     ///
     /// # Arguments
-    /// * `name`: the name of the library, as defined in `Cargo.toml`
-    /// * `func`: closure called when internally building the library.
-    /// It receives as argument the library name and the minimum version required.
-    pub fn add_build_internal<F>(self, name: &str, func: F) -> Self
-    where
-        F: 'static + FnOnce(&str, &str) -> std::result::Result<Library, BuildInternalClosureError>,
-    {
-        let mut build_internals = self.build_internals;
-        build_internals.insert(name.to_string(), Box::new(func));
-
+    /// * `names`: the `toml` keys of the dependencies to probe
+    pub fn probe_only(self, names: &[&str]) -> Result<Dependencies, Error> {
         Self {
-            env: self.env,
-            build_internals,
+            only: Some(names.iter().map(|n| n.to_string()).collect()),
+            ..self
         }
+        .probe()
     }
 
-    fn probe_full(mut self) -> Result<Dependencies, Error> {
-        let mut libraries = self.probe_pkg_config()?;
-        libraries.override_from_flags(&self.env);
-
-        Ok(libraries)
-    }
-
-    fn probe_pkg_config(&mut self) -> Result<Dependencies, Error> {
-        let dir = self
-            .env
-            .get("CARGO_MANIFEST_DIR")
-            .ok_or_else(|| Error::InvalidMetadata("$CARGO_MANIFEST_DIR not set".into()))?;
-        let mut path = PathBuf::from(dir);
-        path.push("Cargo.toml");
+    /// Check that all the dependencies are available in the required versions, without emitting
+    /// any `cargo:` instruction.
+    ///
+    /// Unlike [Config::probe], the dependencies are all probed even if some of them fail,
+    /// so all the failures are returned. Optional dependencies, `cfg()` tables and features
+    /// are handled as when probing. The cache file and the pinned versions are neither read nor written.
+    ///
+    /// This is meant to check the system libraries of a machine, for example from a `cargo xtask`.
+    pub fn check(mut self) -> Result<(), Vec<Error>> {
+        let metadata = self.load_metadata().map_err(|e| vec![e])?;
+        // only called once all the dependencies have been probed
+        let validate = self.validate.take();
 
-        let metadata = MetaData::from_file(&path)?;
+        // aliases are probed along with their original dependency
+        let groups = metadata
+            .deps
+            .iter()
+            .map(|dep| metadata.same_as_target(dep).to_string())
+            .collect::<Vec<_>>();
+        let mut subsets: Vec<(String, MetaData)> = Vec::new();
+        for (group, dep) in groups.into_iter().zip(metadata.deps) {
+            match subsets.iter_mut().find(|(g, _)| *g == group) {
+                Some((_, subset)) => subset.deps.push(dep),
+                None => subsets.push((
+                    group,
+                    MetaData {
+                        deps: vec![dep],
+                        includes: metadata.includes.clone(),
+                    },
+                )),
+            }
+        }
 
         let mut libraries = Dependencies::default();
-
-        for dep in metadata.deps.iter() {
-            if let Some(cfg) = &dep.cfg {
-                // Check if `cfg()` expression matches the target settings
-                if !self.check_cfg(cfg)? {
-                    continue;
-                }
+        let mut errors = Vec::new();
+        for (_, subset) in subsets.iter() {
+            match self.probe_metadata(subset) {
+                Ok(libs) => libs
+                    .libs
+                    .into_iter()
+                    .for_each(|(name, lib)| libraries.add(&name, lib)),
+                Err(e) => errors.push(e),
             }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
 
-            let mut enabled_feature_overrides = Vec::new();
+        let metadata = MetaData {
+            deps: subsets
+                .into_iter()
+                .flat_map(|(_, subset)| subset.deps)
+                .collect(),
+            includes: metadata.includes,
+        };
+        check_conflicts(&metadata, &libraries).map_err(|e| vec![e])?;
+        if let Some(validate) = validate {
+            validate(&libraries).map_err(|e| vec![Error::ValidationFailed(e)])?;
+        }
 
-            for o in dep.version_overrides.iter() {
-                if self.has_feature(&o.key) {
-                    enabled_feature_overrides.push(o);
-                }
-            }
+        Ok(())
+    }
 
-            if let Some(feature) = dep.feature.as_ref() {
-                if !self.has_feature(&feature) {
-                    continue;
-                }
-            }
+    /// Probe all libraries like [Config::probe] but return the `cargo:` instructions
+    /// instead of printing them.
+    ///
+    /// This is useful when the build script is not run by cargo, so the caller can
+    /// forward those instructions to its own build system.
+    pub fn probe_to_flags(self) -> Result<(Dependencies, Vec<String>), Error> {
+        let (libraries, flags) = self.probe_flags()?;
 
-            let (version, lib_name, optional) = {
-                // Pick the highest feature enabled version
-                if !enabled_feature_overrides.is_empty() {
-                    enabled_feature_overrides.sort_by(|a, b| {
-                        VersionCompare::compare(&a.version, &b.version)
-                            .expect("failed to compare versions")
-                            .ord()
-                            .expect("invalid version")
+        Ok((libraries, flags.lines()))
+    }
+
+    /// Probe all libraries for each of the `targets`, such as the `x86_64-apple-darwin`
+    /// and `aarch64-apple-darwin` slices of a universal macOS binary.
+    ///
+    /// The `cfg()` tables are evaluated for each target and the `pkg-config` files are also looked up
+    /// in the directories listed in `PKG_CONFIG_PATH_$TARGET`, if set.
+    /// No `cargo:` instruction is emitted, it is up to the caller to emit the flags of the targets.
+    ///
+    /// The closures registered using [Config::add_build_internal] and [Config::validate] are only called
+    /// once, so only for the first target needing them.
+    ///
+    /// The returned hash is using the target triples as key.
+    ///
+    /// # Arguments
+    /// * `targets`: the target triples to probe the libraries for
+    pub fn probe_for_targets(
+        mut self,
+        targets: &[&str],
+    ) -> Result<HashMap<String, Dependencies>, Error> {
+        if let Some(target) = targets
+            .iter()
+            .find(|t| cfg_expr::targets::get_builtin_target_by_triple(t).is_none())
+        {
+            return Err(Error::UnsupportedTarget(target.to_string()));
+        }
+
+        let metadata = self.load_metadata()?;
+        let mut res = HashMap::new();
+        for target in targets {
+            self.target = Some(target.to_string());
+            let libraries = self.probe_metadata(&metadata)?;
+            res.insert(target.to_string(), libraries);
+        }
+
+        Ok(res)
+    }
+
+    /// Control whether `probe` should emit a `cargo:rustc-cfg=system_deps_have_$DEP`
+    /// instruction for each dependency which has been found.
+    ///
+    /// Those cfgs are emitted by default.
+    ///
+    /// # Arguments
+    /// * `emit`: `false` to not emit the cfgs
+    pub fn emit_have_cfgs(self, emit: bool) -> Self {
+        Self {
+            emit_have_cfgs: emit,
+            ..self
+        }
+    }
+
+    /// Control whether `probe` should emit a `cargo:rustc-cfg` instruction for each
+    /// `HAVE_*` macro of [Library::defines], in snake case: `HAVE_FOO` is emitted as `have_foo`.
+    ///
+    /// A matching `cargo:rustc-check-cfg` instruction is emitted for each of those cfgs.
+    /// They are not emitted by default.
+    ///
+    /// # Arguments
+    /// * `emit`: `true` to emit the cfgs
+    pub fn emit_define_cfgs(self, emit: bool) -> Self {
+        Self {
+            emit_define_cfgs: emit,
+            ..self
+        }
+    }
+
+    /// Control whether `probe` should emit a `cargo:rustc-cfg=system_deps_$DEP_$VERSION` instruction
+    /// for each dependency with feature versions, `$VERSION` being the highest version of its feature
+    /// versions satisfied by the version found, such as `system_deps_gstreamer_1_0_1_18`.
+    /// No cfg is emitted if the version found is unknown, as for the libraries defined using env variables.
+    ///
+    /// A matching `cargo:rustc-check-cfg` instruction is emitted for each of the feature versions.
+    /// They are not emitted by default.
+    ///
+    /// # Arguments
+    /// * `emit`: `true` to emit the cfgs
+    pub fn emit_version_cfgs(self, emit: bool) -> Self {
+        Self {
+            emit_version_cfgs: emit,
+            ..self
+        }
+    }
+
+    /// Control whether `probe` should emit a `cargo:include` instruction with the include
+    /// paths of all the dependencies, exported to dependent crates as `DEP_$CRATE_INCLUDE`.
+    ///
+    /// This instruction is emitted by default. The include paths are still available
+    /// in the returned [Dependencies] if disabled.
+    ///
+    /// # Arguments
+    /// * `emit`: `false` to not emit the instruction
+    pub fn emit_include(self, emit: bool) -> Self {
+        Self {
+            emit_include: emit,
+            ..self
+        }
+    }
+
+    /// Control whether `probe` should embed the link paths of the dependencies as rpaths
+    /// so their shared libraries can be found at runtime, by emitting a
+    /// `cargo:rustc-link-arg=-Wl,-rpath,$PATH` instruction for each of them.
+    ///
+    /// Rpaths are not emitted by default, and never when targeting Windows.
+    ///
+    /// # Arguments
+    /// * `emit`: `true` to emit the rpaths
+    pub fn emit_rpath(self, emit: bool) -> Self {
+        Self {
+            emit_rpath: emit,
+            ..self
+        }
+    }
+
+    /// List the `toml` keys of all the dependencies declared in `Cargo.toml`
+    /// in declaration order, without probing them.
+    ///
+    /// Dependencies are returned regardless of the current target and enabled features.
+    pub fn declared_deps(&self) -> Result<Vec<String>, Error> {
+        let metadata = self.load_metadata()?;
+
+        Ok(metadata
+            .deps
+            .into_iter()
+            .map(|dep| dep.key)
+            .unique()
+            .collect())
+    }
+
+    /// Count the dependencies declared in `Cargo.toml` by category, without probing them.
+    ///
+    /// Dependencies are counted regardless of the current target and enabled features.
+    pub fn dep_summary(&self) -> Result<DepSummary, Error> {
+        let metadata = self.load_metadata()?;
+        let count = |f: &dyn Fn(&metadata::Dependency) -> bool| {
+            metadata
+                .deps
+                .iter()
+                .filter(|dep| f(dep))
+                .map(|dep| &dep.key)
+                .unique()
+                .count()
+        };
+
+        Ok(DepSummary {
+            total: count(&|_| true),
+            optional: count(&|dep| dep.optional),
+            feature_gated: count(&|dep| dep.feature.is_some()),
+            cfg_gated: count(&|dep| dep.cfg.is_some()),
+            with_version_overrides: count(&|dep| !dep.version_overrides.is_empty()),
+        })
+    }
+
+    /// Retrieve the `pkg-config` modules required by each dependency, available using [Library::requires]
+    /// and [Dependencies::dependency_graph].
+    ///
+    /// This is disabled by default as it runs `pkg-config --print-requires` for each dependency
+    /// and each module it transitively requires.
+    ///
+    /// # Arguments
+    ///
+    /// * `capture`: `true` to retrieve the required modules
+    pub fn capture_requires(self, capture: bool) -> Self {
+        Self {
+            capture_requires: capture,
+            ..self
+        }
+    }
+
+    /// Check that the feature versions of each dependency require increasing versions,
+    /// in their declaration order, failing with [Error::InvalidMetadata] otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict`: `true` to check the feature versions
+    pub fn strict_feature_versions(self, strict: bool) -> Self {
+        Self {
+            strict_feature_versions: strict,
+            ..self
+        }
+    }
+
+    /// Probe the dependencies as if only the `features` were enabled, rather than the ones
+    /// defined by the `CARGO_FEATURE_*` environment variables.
+    ///
+    /// This is used to check the `feature` of the dependencies, their feature versions
+    /// and the `cfg(feature = "...")` tables.
+    ///
+    /// # Arguments
+    ///
+    /// * `features`: the names of the enabled features
+    pub fn enabled_features(self, features: Vec<String>) -> Self {
+        Self {
+            features: Some(features),
+            ..self
+        }
+    }
+
+    /// Store the probed dependencies in `path`, and reuse them instead of probing again
+    /// as long as the cache is still valid.
+    /// See [caching](index.html#caching) for the invalidation rules.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: the file storing the probed dependencies, usually in `OUT_DIR`
+    pub fn cache_file<P: Into<PathBuf>>(self, path: P) -> Self {
+        Self {
+            cache_file: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Read the metadata from the `Cargo.toml.orig` file next to `Cargo.toml` if `Cargo.toml`
+    /// has no `package.metadata.system-deps` section.
+    ///
+    /// Crates built from a published `.crate` file use the manifest normalized by `cargo package`,
+    /// the original one being kept as `Cargo.toml.orig`. When enabled, a `cargo:rerun-if-changed`
+    /// instruction is emitted for the manifest the metadata has been read from.
+    ///
+    /// # Arguments
+    ///
+    /// * `check`: `true` to fall back to `Cargo.toml.orig`
+    pub fn check_orig_manifest(self, check: bool) -> Self {
+        Self {
+            check_orig_manifest: check,
+            ..self
+        }
+    }
+
+    /// Read the metadata from the manifest at `path` instead of the `Cargo.toml` in `$CARGO_MANIFEST_DIR`.
+    ///
+    /// Allows using system-deps outside of a build script. The relative paths declared in the
+    /// metadata are resolved from the directory containing the manifest.
+    /// Probing fails with [Error::MissingManifest] if no manifest path is defined and
+    /// `$CARGO_MANIFEST_DIR` is not set.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: the path of the manifest
+    pub fn manifest_path<P: Into<PathBuf>>(self, path: P) -> Self {
+        Self {
+            manifest_path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Pin the versions of the dependencies in `path`, failing with [Error::PinnedVersionMismatch]
+    /// if the version found for a dependency differs from the pinned one.
+    ///
+    /// The file is created when first probing and the dependencies which are not pinned yet are added to it,
+    /// so it can be committed to detect version drifts across machines.
+    /// Define `SYSTEM_DEPS_REFRESH_PINNED_VERSIONS` to pin the versions currently found instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: the file storing the pinned versions, usually next to `Cargo.toml`
+    pub fn pin_versions<P: Into<PathBuf>>(self, path: P) -> Self {
+        Self {
+            pinned_versions: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Ask `pkg-config` to also report the libraries and paths of the system.
+    ///
+    /// By default system libraries are reported only for the dependencies linked statically,
+    /// see [Library::is_static], as static linking requires those as well.
+    /// The value defined using this method is used for all the dependencies instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `print`: `true` to report the system libraries
+    pub fn print_system_libs(self, print: bool) -> Self {
+        Self {
+            print_system_libs: Some(print),
+            ..self
+        }
+    }
+
+    /// Look for dependencies in the `pkg-config` directory of the Homebrew installation
+    /// before the other `PKG_CONFIG_PATH` directories.
+    ///
+    /// The Homebrew prefix is retrieved using `brew --prefix`. This is a no-op if
+    /// not building on macOS or if `brew` is not installed.
+    pub fn prefer_homebrew(self) -> Self {
+        Self {
+            prefer_homebrew: true,
+            ..self
+        }
+    }
+
+    /// Add a callback rewriting the paths found by `pkg-config`, for example to replace
+    /// the prefix baked into the `.pc` files of a relocated SDK by its actual location.
+    ///
+    /// The callback is applied to the [Library::link_paths], [Library::framework_paths] and
+    /// [Library::include_paths] of all the dependencies, before applying the paths
+    /// defined by environment variables or using [Config::add_link_path] and [Config::add_include_path].
+    ///
+    /// # Arguments
+    /// * `func`: closure returning the path to use instead of the path passed as argument
+    pub fn rewrite_paths<F>(self, func: F) -> Self
+    where
+        F: 'static + Fn(&Path) -> PathBuf,
+    {
+        Self {
+            rewrite_path: Some(Box::new(func)),
+            ..self
+        }
+    }
+
+    /// Add a callback called with the key of each dependency right before it is looked up
+    /// using `pkg-config`, for example to set up the environment needed to find it.
+    ///
+    /// The dependencies are probed one after the other, in their declaration order, so the callback
+    /// can set environment variables. Note that changing the environment of the build script is
+    /// racy if other threads read it meanwhile.
+    ///
+    /// # Arguments
+    /// * `func`: closure called with the key of the dependency
+    pub fn before_probe<F>(self, func: F) -> Self
+    where
+        F: 'static + FnMut(&str),
+    {
+        Self {
+            before_probe: Some(Box::new(func)),
+            ..self
+        }
+    }
+
+    /// Add a callback called with the key of each dependency right after it has been looked up
+    /// using `pkg-config`, whether it has been found or not, for example to undo the changes made
+    /// by the [Config::before_probe] callback.
+    ///
+    /// # Arguments
+    /// * `func`: closure called with the key of the dependency
+    pub fn after_probe<F>(self, func: F) -> Self
+    where
+        F: 'static + FnMut(&str),
+    {
+        Self {
+            after_probe: Some(Box::new(func)),
+            ..self
+        }
+    }
+
+    /// Add a callback validating the whole set of dependencies once they have all been probed,
+    /// before emitting any cargo flag.
+    ///
+    /// This can be used to check invariants involving several dependencies, such as
+    /// compatible versions.
+    ///
+    /// # Arguments
+    /// * `func`: closure called with the probed dependencies.
+    ///   Returning an error message makes probing fail with [Error::ValidationFailed].
+    pub fn validate<F>(self, func: F) -> Self
+    where
+        F: 'static + FnOnce(&Dependencies) -> std::result::Result<(), String>,
+    {
+        Self {
+            validate: Some(Box::new(func)),
+            ..self
+        }
+    }
+
+    /// Add hook so system-deps can internally build library `name` if requested by user.
+    ///
+    /// It will only be triggered if the environment variable
+    /// `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` is defined with either `always` or
+    /// `auto` as value. In the latter case, `func` is called only if the requested
+    /// version of the library was not found on the system.
+    ///
+    /// # Arguments
+    /// * `name`: the name of the library, as defined in `Cargo.toml`
+    /// * `func`: closure called when internally building the library.
+    ///   It receives as argument the library name and the minimum version required.
+    pub fn add_build_internal<F>(self, name: &str, func: F) -> Self
+    where
+        F: 'static + FnOnce(&str, &str) -> std::result::Result<Library, BuildInternalClosureError>,
+    {
+        self.add_build_internal_ctx(name, move |ctx| func(&ctx.name, &ctx.version))
+    }
+
+    /// Add hook so system-deps can internally build library `name` if requested by user.
+    ///
+    /// Same as [Config::add_build_internal] but `func` receives more details about
+    /// the configuration the library is built for.
+    ///
+    /// # Arguments
+    /// * `name`: the name of the library, as defined in `Cargo.toml`
+    /// * `func`: closure called when internally building the library.
+    pub fn add_build_internal_ctx<F>(self, name: &str, func: F) -> Self
+    where
+        F: 'static
+            + FnOnce(&BuildInternalContext) -> std::result::Result<Library, BuildInternalClosureError>,
+    {
+        let mut build_internals = self.build_internals;
+        build_internals.insert(name.to_string(), BuildInternalFn::Local(Box::new(func)));
+
+        Self {
+            build_internals,
+            ..self
+        }
+    }
+
+    /// Add hook so system-deps can internally build library `name` if requested by user.
+    ///
+    /// Same as [Config::add_build_internal_ctx] but `func` has to be `Send` so it can
+    /// be called on a worker thread, allowing [Config::build_internal_timeout] to apply to it.
+    ///
+    /// # Arguments
+    /// * `name`: the name of the library, as defined in `Cargo.toml`
+    /// * `func`: closure called when internally building the library.
+    pub fn add_build_internal_send<F>(self, name: &str, func: F) -> Self
+    where
+        F: 'static
+            + Send
+            + FnOnce(&BuildInternalContext) -> std::result::Result<Library, BuildInternalClosureError>,
+    {
+        let mut build_internals = self.build_internals;
+        build_internals.insert(name.to_string(), BuildInternalFn::Send(Box::new(func)));
+
+        Self {
+            build_internals,
+            ..self
+        }
+    }
+
+    /// Remove the hook registered to internally build library `name`, if any.
+    ///
+    /// # Arguments
+    /// * `name`: the name of the library, as defined in `Cargo.toml`
+    pub fn remove_build_internal(self, name: &str) -> Self {
+        let mut build_internals = self.build_internals;
+        build_internals.remove(name);
+
+        Self {
+            build_internals,
+            ..self
+        }
+    }
+
+    /// Set the maximum duration of the closures building the libraries internally.
+    ///
+    /// Probing fails with [Error::BuildInternalTimeout] if a closure takes longer than `timeout`.
+    /// The timeout only applies to closures registered using [Config::add_build_internal_send],
+    /// as the other ones are not `Send` and so cannot be moved to a worker thread.
+    /// The worker thread of a closure which timed out is left running in the background.
+    /// A closure panicking on the worker thread makes probing panic with the same payload.
+    ///
+    /// # Arguments
+    /// * `timeout`: the maximum duration of each closure
+    pub fn build_internal_timeout(self, timeout: Duration) -> Self {
+        Self {
+            build_internal_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Set the minimum version required for the dependencies which do not define one
+    /// in `Cargo.toml`, instead of failing with [Error::InvalidMetadata].
+    ///
+    /// The versions defined by the dependencies, including the ones of the
+    /// [feature versions](index.html#feature-versions), take precedence.
+    ///
+    /// # Arguments
+    /// * `version`: the default minimum version
+    pub fn default_version(self, version: &str) -> Self {
+        Self {
+            default_version: Some(version.to_string()),
+            ..self
+        }
+    }
+
+    /// Set the minimum version required for the dependency `key`, overriding the one defined in `Cargo.toml`.
+    ///
+    /// This is useful when the required version is computed by the build script, for example from the
+    /// version of an SDK. It takes precedence over the versions of the enabled
+    /// [feature versions](index.html#feature-versions), while the other settings of the selected feature version,
+    /// such as its `name`, still apply. Groups declared using `one_of` are not affected.
+    ///
+    /// # Arguments
+    /// * `key`: the name of the `toml` key defining the dependency in `Cargo.toml`
+    /// * `version`: the minimum version required
+    pub fn set_version(self, key: &str, version: &str) -> Self {
+        let mut versions = self.versions;
+        versions.insert(key.to_string(), version.to_string());
+
+        Self { versions, ..self }
+    }
+
+    /// Take into account the `-L` link search paths passed using `RUSTFLAGS`, as read from
+    /// `CARGO_ENCODED_RUSTFLAGS`.
+    ///
+    /// The `pkgconfig` directory of each of those paths, if any, is searched for `.pc` files after
+    /// the other directories added by `system-deps` but before the ones of `PKG_CONFIG_PATH`,
+    /// so libraries only installed there can be found.
+    /// Those paths are also no longer emitted as `cargo:rustc-link-search` as rustc already uses them.
+    ///
+    /// Only the `-L PATH`, `-LPATH` and `-L KIND=PATH` arguments with the `native` or `all` kinds
+    /// are considered, link arguments such as `-C link-arg=-L/path` are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `respect`: `true` to use the link search paths of `RUSTFLAGS`
+    pub fn respect_rustflags_link_paths(self, respect: bool) -> Self {
+        Self {
+            respect_rustflags_link_paths: respect,
+            ..self
+        }
+    }
+
+    /// Select how [Library::cflags] passes the include paths to the compiler,
+    /// using `-I` by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `style`: [IncludeArgStyle::ISystem] to use `-isystem`, so the compiler does not report
+    ///   warnings from the headers of the dependencies
+    pub fn include_arg_style(self, style: IncludeArgStyle) -> Self {
+        Self {
+            include_arg_style: style,
+            ..self
+        }
+    }
+
+    /// Set `PKG_CONFIG_LIBDIR` to `libdir` while probing the dependencies.
+    ///
+    /// `PKG_CONFIG_LIBDIR` replaces the default search path of `pkg-config` so, unlike
+    /// `PKG_CONFIG_PATH` which is searched before it, the `.pc` files installed on the host are not used.
+    /// Its previous value is restored once probing is done.
+    ///
+    /// # Arguments
+    /// * `libdir`: the directories where `pkg-config` should look for `.pc` files,
+    ///   separated as in `PKG_CONFIG_PATH` if there are several
+    pub fn pkg_config_libdir<P: Into<PathBuf>>(self, libdir: P) -> Self {
+        Self {
+            pkg_config_libdir: Some(libdir.into()),
+            ..self
+        }
+    }
+
+    /// Set the environment variables `env` while probing the dependencies, for example
+    /// `PKG_CONFIG_ALLOW_SYSTEM_CFLAGS` or `PKG_CONFIG_ALLOW_SYSTEM_LIBS` to change the behavior of `pkg-config`.
+    ///
+    /// Their previous values are restored once probing is done, the variables which were not
+    /// set being removed rather than set to an empty string, so they don't leak into the rest of the build script.
+    /// A `cargo:rerun-if-env-changed` instruction is emitted for each of them.
+    ///
+    /// # Arguments
+    /// * `env`: the names and values of the environment variables
+    pub fn pkg_config_env(self, env: HashMap<String, String>) -> Self {
+        Self {
+            pkg_config_env: env.into_iter().sorted().collect(),
+            ..self
+        }
+    }
+
+    /// Add a directory where the linker should look for the libraries of all the dependencies.
+    ///
+    /// Those directories are added before the [Library::link_paths] of each dependency,
+    /// removing duplicates.
+    ///
+    /// # Arguments
+    /// * `path`: the directory to add
+    pub fn add_link_path<P: Into<PathBuf>>(self, path: P) -> Self {
+        let mut extra_link_paths = self.extra_link_paths;
+        extra_link_paths.push(path.into());
+
+        Self {
+            extra_link_paths,
+            ..self
+        }
+    }
+
+    /// Add a directory where the compiler should look for the header files of all the dependencies.
+    ///
+    /// Those directories are added before the [Library::include_paths] of each dependency,
+    /// removing duplicates.
+    ///
+    /// # Arguments
+    /// * `path`: the directory to add
+    pub fn add_include_path<P: Into<PathBuf>>(self, path: P) -> Self {
+        let mut extra_include_paths = self.extra_include_paths;
+        extra_include_paths.push(path.into());
+
+        Self {
+            extra_include_paths,
+            ..self
+        }
+    }
+
+    // The env variables making cargo run the build script again because of the settings of the config
+    fn rerun_env_vars(&self) -> Vec<EnvVariable> {
+        let mut vars = Vec::new();
+        if self.pinned_versions.is_some() {
+            vars.push(EnvVariable::RefreshPinnedVersions);
+        }
+        for (var, _) in self.pkg_config_env.iter() {
+            vars.push(EnvVariable::PkgConfigEnv(var.clone()));
+        }
+        vars
+    }
+
+    fn probe_flags(mut self) -> Result<(Dependencies, BuildFlags), Error> {
+        let metadata = self.load_metadata()?;
+        let mut libraries = match self.cache_file.clone() {
+            Some(path) => self.probe_metadata_cached(&path, &metadata)?,
+            None => self.probe_metadata(&metadata)?,
+        };
+        if let Some(path) = self.pinned_versions.clone() {
+            self.check_pinned_versions(&path, &libraries)?;
+        }
+        libraries.config_env_vars = self.rerun_env_vars();
+        let mut flags = libraries.gen_flags(&self)?;
+
+        for warning in self.warnings.iter() {
+            flags.add(BuildFlag::Warning(warning.clone()));
+        }
+
+        for path in libraries.rerun_paths() {
+            flags.add(BuildFlag::RerunIfChanged(
+                path.to_string_lossy().to_string(),
+            ));
+        }
+
+        if self.emit_have_cfgs {
+            // Declare all the cfgs which may be emitted, including the ones of skipped
+            // dependencies, so rustc doesn't warn about unexpected cfgs in the code using them.
+            for key in metadata.deps.iter().map(|dep| &dep.key).unique() {
+                flags.add(BuildFlag::RustcCheckCfg(format!(
+                    "cfg(system_deps_have_{})",
+                    metadata.cfg_name(key)
+                )));
+            }
+
+            for (name, _) in libraries.iter() {
+                flags.add(BuildFlag::RustcCfg(format!(
+                    "system_deps_have_{}",
+                    metadata.cfg_name(name)
+                )));
+            }
+        }
+
+        if self.emit_define_cfgs {
+            for (define, _) in libraries
+                .all_defines()
+                .filter(|(define, _)| define.starts_with("HAVE_"))
+            {
+                let cfg = define.to_snake_case();
+                flags.add(BuildFlag::RustcCheckCfg(format!("cfg({})", cfg)));
+                flags.add(BuildFlag::RustcCfg(cfg));
+            }
+        }
+
+        if self.emit_version_cfgs {
+            for key in metadata.deps.iter().map(|dep| &dep.key).unique() {
+                let thresholds = metadata
+                    .deps
+                    .iter()
+                    .filter(|dep| &dep.key == key)
+                    .flat_map(|dep| dep.version_overrides.iter().map(|o| &o.version))
+                    .unique()
+                    .collect::<Vec<_>>();
+                let version_cfg = |version: &str| {
+                    let version = version.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+                    format!("system_deps_{}_{}", metadata.cfg_name(key), version)
+                };
+
+                for version in thresholds.iter() {
+                    flags.add(BuildFlag::RustcCheckCfg(format!(
+                        "cfg({})",
+                        version_cfg(version)
+                    )));
+                }
+
+                let lib = match libraries.get_by_name(key) {
+                    Some(lib) => lib,
+                    None => continue,
+                };
+                let compare =
+                    |a: &str, b: &str| VersionCompare::compare(a, b).ok().and_then(|op| op.ord());
+                // no cfg is emitted if the version found cannot be compared, such as the
+                // unknown version of the libs defined by env variables
+                if lib.version.is_empty() {
+                    continue;
+                }
+                let satisfied = match thresholds
+                    .iter()
+                    .map(|version| compare(&lib.version, version).map(|ord| (version, ord)))
+                    .collect::<Option<Vec<_>>>()
+                {
+                    Some(satisfied) => satisfied,
+                    None => continue,
+                };
+                // the highest threshold satisfied by the version found
+                let highest = satisfied
+                    .into_iter()
+                    .filter(|(_, ord)| *ord != std::cmp::Ordering::Less)
+                    .map(|(version, _)| version)
+                    .max_by(|a, b| compare(a, b).unwrap_or(std::cmp::Ordering::Equal));
+                if let Some(version) = highest {
+                    flags.add(BuildFlag::RustcCfg(version_cfg(version)));
+                }
+            }
+        }
+
+        Ok((libraries, flags))
+    }
+
+    #[cfg(test)]
+    fn probe_full(&mut self) -> Result<Dependencies, Error> {
+        let metadata = self.load_metadata()?;
+        self.probe_metadata(&metadata)
+    }
+
+    fn probe_metadata(&mut self, metadata: &MetaData) -> Result<Dependencies, Error> {
+        let libdir = self.pkg_config_libdir.clone();
+        let env = self.pkg_config_env.clone();
+        let mut libraries = with_env_vars(&env, || {
+            with_env_var("PKG_CONFIG_LIBDIR", libdir.as_deref(), || {
+                self.probe_pkg_config(metadata)
+            })
+        })?;
+        libraries.includes = metadata.includes.clone();
+        if let Some(rewrite) = &self.rewrite_path {
+            libraries.rewrite_paths(rewrite);
+        }
+        libraries.override_from_flags(&self.env)?;
+        self.check_static_libs(&libraries);
+        libraries.add_extra_paths(&self.extra_link_paths, &self.extra_include_paths);
+        libraries.set_include_arg_style(self.include_arg_style);
+        check_conflicts(metadata, &libraries)?;
+
+        if let Some(validate) = self.validate.take() {
+            validate(&libraries).map_err(Error::ValidationFailed)?;
+        }
+
+        Ok(libraries)
+    }
+
+    // Warn about the `static_libs` which are not libraries of their dependency, such as typos,
+    // as those would otherwise be silently linked dynamically
+    fn check_static_libs(&mut self, libraries: &Dependencies) {
+        for (name, lib) in libraries
+            .iter_ordered()
+            .filter(|(_, lib)| lib.same_as.is_none())
+        {
+            for l in lib
+                .static_libs
+                .iter()
+                .filter(|l| !lib.libs.contains(l) && !lib.verbatim_libs.contains(l))
+            {
+                self.warnings.push(format!(
+                    "{}: static_libs lists {} which is not one of its libraries, it is ignored",
+                    name, l
+                ));
+            }
+        }
+    }
+
+    // Load the dependencies from the cache at `path` if it is valid, or probe and cache them
+    fn probe_metadata_cached(
+        &mut self,
+        path: &Path,
+        metadata: &MetaData,
+    ) -> Result<Dependencies, Error> {
+        let state = self.cache_state(metadata);
+        let mut sources = vec![self.manifest_file()?];
+        sources.extend(metadata.includes.iter().cloned());
+
+        if let Some((mut libraries, warnings)) = cache::load(path, &sources, &state) {
+            debug!("using the dependencies cached in {}", path.display());
+            libraries.includes = metadata.includes.clone();
+            libraries.set_include_arg_style(self.include_arg_style);
+            self.warnings.extend(warnings);
+            if let Some(validate) = self.validate.take() {
+                validate(&libraries).map_err(Error::ValidationFailed)?;
+            }
+            return Ok(libraries);
+        }
+
+        let libraries = self.probe_metadata(metadata)?;
+        if let Err(e) = cache::store(path, &state, &libraries, &self.warnings) {
+            self.warnings.push(format!(
+                "Failed to write cache file {}: {}",
+                path.display(),
+                e
+            ));
+        }
+
+        Ok(libraries)
+    }
+
+    // Check the versions of `libraries` against the ones pinned in `path`, pinning the new ones
+    fn check_pinned_versions(
+        &mut self,
+        path: &Path,
+        libraries: &Dependencies,
+    ) -> Result<(), Error> {
+        let refresh = self.env.contains(&EnvVariable::RefreshPinnedVersions);
+        let mut pinned = match refresh {
+            true => None,
+            false => cache::load_versions(path)
+                .map_err(|e| Error::InvalidPinnedVersions(path.to_string_lossy().to_string(), e))?,
+        };
+        let mut changed = pinned.is_none();
+        let pinned = pinned.get_or_insert_with(BTreeMap::new);
+
+        // the version of libraries defined using env variables is not known
+        for (key, lib) in libraries
+            .iter()
+            .filter(|(_, lib)| lib.source != Source::EnvVariables)
+        {
+            match pinned.get(key) {
+                Some(version) if *version != lib.version => {
+                    return Err(Error::PinnedVersionMismatch(
+                        key.to_string(),
+                        version.clone(),
+                        lib.version.clone(),
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    pinned.insert(key.to_string(), lib.version.clone());
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            if let Err(e) = cache::store_versions(path, pinned) {
+                self.warnings.push(format!(
+                    "Failed to write pinned versions file {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // What the cached dependencies depend on, other than the metadata files
+    fn cache_state(&self, metadata: &MetaData) -> cache::State {
+        let env_refs = metadata.env_refs();
+        let env = self
+            .env
+            .vars()
+            .into_iter()
+            .filter(|(var, _)| affects_probing(var) || env_refs.contains(var))
+            .collect();
+        let config = format!(
+            "{:?} {:?}",
+            (
+                env!("CARGO_PKG_VERSION"),
+                self.build_internals.keys().sorted().collect::<Vec<_>>(),
+                self.build_internal_timeout,
+                self.rewrite_path.is_some(),
+                self.versions.iter().sorted().collect::<Vec<_>>(),
+            ),
+            (
+                self.prefer_homebrew,
+                self.respect_rustflags_link_paths,
+                &self.features,
+                &self.pkg_config_libdir,
+                &self.pkg_config_env,
+                &self.default_version,
+                self.capture_requires,
+                self.strict_feature_versions,
+                self.print_system_libs,
+                &self.extra_link_paths,
+                &self.extra_include_paths,
+            )
+        );
+
+        cache::State {
+            env,
+            config,
+            deps: metadata.deps.iter().map(|dep| dep.key.clone()).collect(),
+        }
+    }
+
+    fn manifest_dir(&self) -> Result<PathBuf, Error> {
+        if let Some(path) = &self.manifest_path {
+            return Ok(path.parent().map(Path::to_path_buf).unwrap_or_default());
+        }
+        let dir = self
+            .env
+            .get("CARGO_MANIFEST_DIR")
+            .ok_or(Error::MissingManifest)?;
+        Ok(PathBuf::from(dir))
+    }
+
+    fn manifest_file(&self) -> Result<PathBuf, Error> {
+        match &self.manifest_path {
+            Some(path) => Ok(path.clone()),
+            None => Ok(self.manifest_dir()?.join("Cargo.toml")),
+        }
+    }
+
+    // Read the `export_variables` of `dep` from the `.pc` file of `lib`
+    fn export_variables(&mut self, dep: &metadata::Dependency, lib: &mut Library) {
+        if dep.export_variables.is_empty() || lib.source == Source::EnvVariables {
+            return;
+        }
+
+        for variable in dep.export_variables.iter().sorted().dedup() {
+            let value = with_pkg_config_paths(&lib.pkg_config_paths, || {
+                pkg_config::get_variable(&lib.name, variable)
+            });
+            match value {
+                Ok(value) if !value.is_empty() => {
+                    lib.exported_variables.push((variable.clone(), value));
+                }
+                _ => self.warnings.push(format!(
+                    "{}: variable {} is not defined by {}, it is not exported",
+                    dep.describe(&dep.key),
+                    variable,
+                    lib.name
+                )),
+            }
+        }
+    }
+
+    // Call `f` between the `before_probe` and `after_probe` callbacks of the dependency `key`
+    fn with_probe_hooks<T>(&mut self, key: &str, f: impl FnOnce(&mut Self) -> T) -> T {
+        if let Some(hook) = self.before_probe.as_mut() {
+            hook(key);
+        }
+        let res = f(self);
+        if let Some(hook) = self.after_probe.as_mut() {
+            hook(key);
+        }
+        res
+    }
+
+    // Library defined by the `fallback` settings of `dep`, if declared for the target
+    fn fallback_library(
+        &self,
+        cfg_cache: &mut CfgCache,
+        dep: &metadata::Dependency,
+    ) -> Result<Option<Library>, Error> {
+        let fallback = match &dep.fallback {
+            Some(fallback) => fallback,
+            None => return Ok(None),
+        };
+        if let Some(cfg) = &fallback.cfg {
+            if !self.check_cfg(cfg_cache, cfg)? {
+                return Ok(None);
+            }
+        }
+
+        let expand = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|p| self.expand_env_vars(&dep.key, p))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut lib = Library::from_env_variables(&dep.key);
+        lib.libs = fallback.libs.clone();
+        lib.link_paths = expand(&fallback.link_paths)?;
+        lib.include_paths = expand(&fallback.include_paths)?;
+        lib.frameworks = fallback.frameworks.clone();
+        lib.framework_paths = expand(&fallback.framework_paths)?;
+
+        Ok(Some(lib))
+    }
+
+    // Replace the `${VAR}` references in the `path` declared by the dependency `key`
+    fn expand_env_vars(&self, key: &str, path: &Path) -> Result<PathBuf, Error> {
+        let path = path.to_string_lossy();
+        let invalid =
+            |msg: String| Error::InvalidMetadata(format!("{}: {} in path {}", key, msg, path));
+
+        let mut res = String::new();
+        let mut rest = path.as_ref();
+        while let Some(start) = rest.find("${") {
+            res.push_str(&rest[..start]);
+            rest = &rest[start + 2..];
+            let end = rest
+                .find('}')
+                .ok_or_else(|| invalid("unterminated variable".to_string()))?;
+            let var = &rest[..end];
+            let value = self
+                .env
+                .get(var)
+                .ok_or_else(|| invalid(format!("environment variable {} is not set", var)))?;
+            res.push_str(&value);
+            rest = &rest[end + 1..];
+        }
+        res.push_str(rest);
+
+        Ok(PathBuf::from(res))
+    }
+
+    fn load_metadata(&self) -> Result<MetaData, Error> {
+        let path = self.manifest_file()?;
+        let mut metadata = MetaData::from_file(&path, self.check_orig_manifest)?;
+        if let Some(only) = &self.only {
+            let unknown = only
+                .iter()
+                .filter(|name| !metadata.deps.iter().any(|dep| &&dep.key == name))
+                .collect::<Vec<_>>();
+            if !unknown.is_empty() {
+                return Err(Error::InvalidMetadata(format!(
+                    "Unknown dependencies: {}",
+                    unknown.iter().join(", ")
+                )));
+            }
+            metadata.deps.retain(|dep| only.contains(&dep.key));
+        }
+
+        Ok(metadata)
+    }
+
+    fn probe_pkg_config(&mut self, metadata: &MetaData) -> Result<Dependencies, Error> {
+        let mut libraries = Dependencies::default();
+
+        let mut search_paths = Vec::new();
+        if self.prefer_homebrew {
+            search_paths.extend(homebrew_pkg_config_dir());
+        }
+        search_paths.extend(
+            self.rustflags_link_paths()
+                .into_iter()
+                .map(|p| p.join("pkgconfig"))
+                .filter(|p| p.is_dir()),
+        );
+        if let Some(target) = &self.target {
+            // the `pkg-config` crate only looks up the paths of `TARGET`
+            let paths = self
+                .env
+                .get(format!("PKG_CONFIG_PATH_{}", target).as_str())
+                .or_else(|| {
+                    self.env
+                        .get(format!("PKG_CONFIG_PATH_{}", target.replace('-', "_")).as_str())
+                });
+            search_paths.extend(paths.iter().flat_map(|p| split_paths(p)));
+        }
+
+        let mut aliases = Vec::new();
+        let mut cfg_cache = CfgCache::default();
+        // tables of the dependencies to probe, by key
+        let mut active: HashMap<&str, String> = HashMap::new();
+
+        for dep in metadata.deps.iter() {
+            if let Some(cfg) = &dep.cfg {
+                // Check if `cfg()` expression matches the target settings
+                if !self.check_cfg(&mut cfg_cache, cfg)? {
+                    debug!(
+                        "{}: skipped as {} does not match the target",
+                        dep.key,
+                        cfg.original()
+                    );
+                    continue;
+                }
+            }
+
+            if self.strict_feature_versions {
+                check_feature_versions(dep)?;
+            }
+
+            let mut enabled_feature_overrides = Vec::new();
+
+            for o in dep.version_overrides.iter() {
+                if self.has_feature(&o.key) {
+                    enabled_feature_overrides.push(o);
+                }
+            }
+
+            if let Some(feature) = dep.feature.as_ref() {
+                if !dep.always_probe && !self.has_feature(feature) {
+                    debug!("{}: skipped as feature {} is disabled", dep.key, feature);
+                    continue;
+                }
+            }
+
+            if dep.require_version_feature
+                && !dep.version_overrides.is_empty()
+                && enabled_feature_overrides.is_empty()
+            {
+                return Err(Error::MissingVersionFeature(
+                    dep.describe(&dep.key),
+                    dep.version_overrides.iter().map(|o| &o.key).join(", "),
+                ));
+            }
+
+            // A key declared in several tables matching the target would silently override the others
+            let table = match &dep.cfg {
+                Some(cfg) => format!("cfg({})", cfg.original()),
+                None => "package.metadata.system-deps".to_string(),
+            };
+            if let Some(other) = active.insert(&dep.key, table.clone()) {
+                return Err(Error::InvalidMetadata(format!(
+                    "{} is declared in both {} and {} which match the target",
+                    dep.key, other, table
+                )));
+            }
+
+            // Aliases reuse the result of their target once all the deps have been probed
+            if dep.same_as.is_some() {
+                debug!("{}: alias of {}", dep.key, metadata.same_as_target(dep));
+                aliases.push(dep);
+                continue;
+            }
+
+            if let Err(e) = self.check_min_os_version(dep) {
+                if dep.optional {
+                    debug!("{}: skipped: {}", dep.key, e);
+                    continue;
+                }
+                return Err(e);
+            }
+
+            let (version, lib_name, optional, selected_override) = {
+                // Pick the highest feature enabled version
+                if !enabled_feature_overrides.is_empty() {
+                    enabled_feature_overrides.sort_by(|a, b| {
+                        VersionCompare::compare(&a.version, &b.version)
+                            .expect("failed to compare versions")
+                            .ord()
+                            .expect("invalid version")
                     });
                     let highest = enabled_feature_overrides.into_iter().last().unwrap();
                     (
                         Some(&highest.version),
                         highest.name.clone().unwrap_or_else(|| dep.lib_name()),
                         highest.optional.unwrap_or(dep.optional),
+                        Some(highest),
                     )
                 } else {
-                    (dep.version.as_ref(), dep.lib_name(), dep.optional)
+                    (dep.version.as_ref(), dep.lib_name(), dep.optional, None)
                 }
             };
+            // the version set by the build script takes precedence
+            let version = match self.versions.get(&dep.key) {
+                Some(v) if dep.one_of.is_empty() && dep.modules.is_empty() => Some(v),
+                _ => version,
+            };
+            let override_build_internal = selected_override.and_then(|o| o.build_internal);
+            let version_op = selected_override
+                .and_then(|o| o.version_op)
+                .unwrap_or(dep.version_op);
+            let name = &dep.key;
+            let (resolve_order, resolve_order_var) =
+                self.resolve_order(dep, override_build_internal)?;
 
-            let version = version.ok_or_else(|| {
-                Error::InvalidMetadata(format!("No version defined for {}", dep.key))
-            })?;
+            // Optional deps using `any_version` are only checked for existence,
+            // as well as the ones using `skip_version_check`
+            let any_version = dep.any_version && optional || dep.skip_version_check;
+            let version = match version {
+                Some(v) if v == "crate" => self.crate_version()?,
+                Some(v) => v.clone(),
+                // the versions of a group or of modules are the ones of each module
+                None if any_version || !dep.one_of.is_empty() || !dep.modules.is_empty() => {
+                    String::new()
+                }
+                // the system version does not matter if the lib is always built internally
+                None if resolve_order == [ResolveStage::Internal] => String::new(),
+                None if self.default_version.is_some() => self.default_version.clone().unwrap(),
+                None if dep.any_version => {
+                    return Err(Error::InvalidMetadata(format!(
+                        "{} uses any_version but is not optional: a version is required",
+                        dep.describe(&dep.key)
+                    )));
+                }
+                None => {
+                    return Err(Error::InvalidMetadata(format!(
+                        "No version defined for {}",
+                        dep.describe(&dep.key)
+                    )));
+                }
+            };
 
-            let name = &dep.key;
-            let build_internal = self.get_build_internal_status(name)?;
+            debug!(
+                "{}: looking for {} {} (resolve order: {:?})",
+                name, lib_name, version, resolve_order
+            );
 
-            let library = if self.env.contains(&EnvVariable::new_no_pkg_config(name)) {
-                Library::from_env_variables(name)
-            } else if build_internal == BuildInternal::Always {
-                self.call_build_internal(&lib_name, &version)?
-            } else {
-                match pkg_config::Config::new()
-                    .atleast_version(&version)
-                    .print_system_libs(false)
-                    .cargo_metadata(false)
-                    .probe(&lib_name)
+            let mut library = if self.env.contains(&EnvVariable::new_no_pkg_config(name)) {
+                debug!(
+                    "{}: defined by env variables as {} is set",
+                    name,
+                    EnvVariable::new_no_pkg_config(name)
+                );
+                // the env variables, applied later on, take precedence over the fallback
+                let mut lib = self
+                    .fallback_library(&mut cfg_cache, dep)?
+                    .unwrap_or_else(|| Library::from_env_variables(name));
+                lib.applied_env_vars
+                    .push(EnvVariable::new_no_pkg_config(name).to_string());
+                lib
+            } else if !dep.one_of.is_empty() {
+                match self
+                    .with_probe_hooks(name, |config| config.probe_one_of(dep, &search_paths))?
                 {
-                    Ok(lib) => Library::from_pkg_config(&lib_name, lib),
-                    Err(e) => {
-                        if build_internal == BuildInternal::Auto {
-                            // Try building the lib internally as a fallback
-                            self.call_build_internal(name, &version)?
-                        } else if optional {
-                            // If the dep is optional just skip it
-                            continue;
-                        } else {
-                            return Err(e.into());
+                    Some(lib) => lib,
+                    None => continue,
+                }
+            } else if !dep.modules.is_empty() {
+                match self.probe_modules(dep, any_version, &search_paths)? {
+                    Some(lib) => lib,
+                    None => continue,
+                }
+            } else {
+                let mut res = None;
+                let mut errors = Vec::new();
+                for stage in resolve_order.iter() {
+                    let r = match stage {
+                        ResolveStage::PkgConfig => self.with_probe_hooks(name, |config| {
+                            config.probe_pkg_config_lib(
+                                dep,
+                                &lib_name,
+                                &version,
+                                version_op,
+                                any_version,
+                                &search_paths,
+                            )
+                        }),
+                        ResolveStage::Internal => {
+                            self.call_build_internal(&lib_name, dep, &version)
+                        }
+                    };
+                    match r {
+                        Ok(lib) => {
+                            debug!("{}: resolved using {}", name, stage);
+                            res = Some(lib);
+                            break;
+                        }
+                        // errors in the metadata are not a failure to find the dependency
+                        Err(e)
+                            if *stage == ResolveStage::PkgConfig
+                                && !matches!(e, Error::PkgConfig(_)) =>
+                        {
+                            return Err(e)
+                        }
+                        Err(e) => {
+                            debug!("{}: not resolved using {}: {}", name, stage, e);
+                            errors.push((*stage, e));
+                        }
+                    }
+                }
+                match res {
+                    Some(mut lib) => {
+                        lib.applied_env_vars
+                            .extend(resolve_order_var.map(|var| var.to_string()));
+                        lib
+                    }
+                    None => {
+                        // Optional deps are skipped if they have not been found,
+                        // but not if building them internally failed
+                        let build_failed = errors
+                            .iter()
+                            .rposition(|(stage, _)| *stage == ResolveStage::Internal);
+                        let (_, e) = match build_failed {
+                            None if optional => continue,
+                            Some(i) if optional => return Err(errors.swap_remove(i).1),
+                            _ => errors.pop().expect("resolve_order cannot be empty"),
+                        };
+                        match self.fallback_library(&mut cfg_cache, dep)? {
+                            Some(lib) => {
+                                debug!("{}: using the fallback settings: {}", name, e);
+                                lib
+                            }
+                            None => return Err(e),
                         }
                     }
                 }
             };
 
+            library.link_args = dep.link_args.clone();
+            library.link_args_bins = dep.link_args_bins.clone();
+            library.link_args_tests = dep.link_args_tests.clone();
+            library.link_args_benches = dep.link_args_benches.clone();
+            if !dep.link_arg_files.is_empty() {
+                let dir = self.manifest_dir()?;
+                library.link_arg_files = dep.link_arg_files.iter().map(|f| dir.join(f)).collect();
+                library.link_args.extend(
+                    library
+                        .link_arg_files
+                        .iter()
+                        .map(|f| format!("-T{}", f.to_string_lossy())),
+                );
+            }
+            library.static_libs = dep.static_libs.clone();
+            if dep.replace_libs {
+                library.libs.clear();
+            }
+            for (l, kind) in dep.libs.iter() {
+                extend_dedup(&mut library.libs, std::slice::from_ref(l));
+                match kind {
+                    Some(LinkKind::Static) => {
+                        extend_dedup(&mut library.static_libs, std::slice::from_ref(l))
+                    }
+                    Some(LinkKind::Dylib) => {
+                        extend_dedup(&mut library.dylib_libs, std::slice::from_ref(l))
+                    }
+                    None => {}
+                }
+            }
+            library.defines_only = dep.defines_only;
+            library.link_group = dep.link_group;
+            library.whole_archive_libs = dep.whole_archive_libs.clone();
+            library.verbatim_libs = dep.verbatim_libs.clone();
+            library.libs.retain(|l| !dep.exclude_libs.contains(l));
+            let frameworks = dep
+                .frameworks
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            extend_dedup(&mut library.frameworks, &frameworks);
+            library.weak_frameworks = dep
+                .frameworks
+                .iter()
+                .filter_map(|(name, weak_below)| Some((name.clone(), weak_below.clone()?)))
+                .collect();
+            if let Some(o) = selected_override {
+                // Frameworks required by the selected version on top of the ones from pkg-config
+                extend_dedup(&mut library.frameworks, &o.frameworks);
+                let framework_paths = o
+                    .framework_paths
+                    .iter()
+                    .map(|p| self.expand_env_vars(&dep.key, p))
+                    .collect::<Result<Vec<_>, _>>()?;
+                extend_dedup(&mut library.framework_paths, &framework_paths);
+            }
+
+            check_required_defines(dep, &library)?;
+            self.export_variables(dep, &mut library);
+
+            let no_emit = EnvVariable::new_no_emit(name);
+            library.emit = dep.emit && !self.env.contains(&no_emit);
+            if self.env.contains(&no_emit) {
+                library.applied_env_vars.push(no_emit.to_string());
+            }
+            if !library.emit {
+                debug!("{}: build flags are left to the caller", name);
+            }
             libraries.add(name, library);
         }
+
+        for dep in aliases {
+            let target = metadata.same_as_target(dep);
+            match libraries.get_by_name(target) {
+                Some(lib) => {
+                    let mut lib = lib.clone();
+                    lib.same_as = Some(target.to_string());
+                    libraries.add(&dep.key, lib);
+                }
+                None if dep.optional => {}
+                None => {
+                    return Err(Error::InvalidMetadata(format!(
+                        "{} is the same as {} which has not been found",
+                        dep.key, target
+                    )));
+                }
+            }
+        }
+        // keep the declaration order
+        libraries.sort_by_key(|k| metadata.deps.iter().position(|d| d.key == k));
+
         Ok(libraries)
     }
 
+    // Probe the members of the group `dep` and use the first one found
+    fn probe_one_of(
+        &self,
+        dep: &metadata::Dependency,
+        search_paths: &[PathBuf],
+    ) -> Result<Option<Library>, Error> {
+        let mut attempts = Vec::new();
+
+        for (lib_name, version) in dep.one_of.iter() {
+            let statik = self.is_static(lib_name);
+            let res = with_pkg_config_paths(search_paths, || {
+                pkg_config::Config::new()
+                    .atleast_version(version)
+                    .print_system_libs(self.should_print_system_libs(statik))
+                    .cargo_metadata(false)
+                    .statik(statik)
+                    .probe(lib_name)
+            });
+
+            match res {
+                Ok(lib) => {
+                    let mut lib = with_pkg_config_paths(search_paths, || {
+                        Library::from_pkg_config(lib_name, lib)
+                    });
+                    lib.statik = statik;
+                    lib.probe_command = Some(probe_command(
+                        lib_name,
+                        Some((VersionOp::Atleast, version)),
+                        statik,
+                        search_paths,
+                    ));
+                    return Ok(Some(lib));
+                }
+                Err(e) => attempts.push(format!("{} {}: {}", lib_name, version, e)),
+            }
+        }
+
+        if dep.optional {
+            Ok(None)
+        } else {
+            Err(Error::NoGroupMember(
+                dep.describe(&dep.key),
+                attempts.join("\n"),
+            ))
+        }
+    }
+
+    // Probe all the modules of `dep` and aggregate them in a single library
+    fn probe_modules(
+        &mut self,
+        dep: &metadata::Dependency,
+        any_version: bool,
+        search_paths: &[PathBuf],
+    ) -> Result<Option<Library>, Error> {
+        let mut library: Option<Library> = None;
+        let mut missing = Vec::new();
+
+        for (module, version) in dep.modules.iter() {
+            match self.probe_pkg_config_lib(
+                dep,
+                module,
+                version,
+                dep.version_op,
+                any_version,
+                search_paths,
+            ) {
+                Ok(lib) => match library.as_mut() {
+                    Some(library) => library.add_module(lib),
+                    None => library = Some(lib),
+                },
+                // only the modules which cannot be found are skipped
+                Err(e @ Error::PkgConfig(_)) if dep.allow_partial => {
+                    debug!("{}: skipping {} {}: {}", dep.key, module, version, e);
+                    missing.push((module, version, e));
+                }
+                Err(_) if dep.optional => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+
+        match library {
+            Some(library) => {
+                for (module, version, _) in missing {
+                    self.warnings.push(format!(
+                        "{} {} cannot be found, {} is used without it",
+                        module,
+                        version,
+                        dep.describe(&dep.key)
+                    ));
+                }
+                Ok(Some(library))
+            }
+            None if dep.optional => Ok(None),
+            None => Err(missing.swap_remove(0).2),
+        }
+    }
+
+    // Static libs need the system libs unless the user decided otherwise
+    fn should_print_system_libs(&self, statik: bool) -> bool {
+        self.print_system_libs.unwrap_or(statik)
+    }
+
+    // The env variable allowing to use an older version of `name` than required, if defined
+    fn allow_older(&self, name: &str) -> Option<EnvVariable> {
+        vec![
+            EnvVariable::new_allow_older(Some(name)),
+            EnvVariable::new_allow_older(None),
+        ]
+        .into_iter()
+        .find(|var| self.env.contains(var))
+    }
+
     fn get_build_internal_env_var(&self, var: EnvVariable) -> Result<Option<BuildInternal>, Error> {
         match self.env.get(&var).as_deref() {
             Some(s) => {
@@ -666,27 +3529,260 @@ impl Config {
         }
     }
 
-    fn get_build_internal_status(&self, name: &str) -> Result<BuildInternal, Error> {
+    // The ways of resolving `dep` to try in order, `build_internal` being the value defined in the metadata
+    // of the selected feature version, if any. Also returns the env variable defining this order, if any.
+    fn resolve_order(
+        &self,
+        dep: &metadata::Dependency,
+        build_internal: Option<BuildInternal>,
+    ) -> Result<(Vec<ResolveStage>, Option<EnvVariable>), Error> {
+        let var = EnvVariable::new_resolve_order(&dep.key);
+        if let Some(value) = self.env.get(&var) {
+            let order = value
+                .split(',')
+                .map(|s| {
+                    ResolveStage::from_str(s.trim()).map_err(|_| {
+                        Error::ResolveOrderInvalid(format!(
+                            "Invalid value in {}: {} (allowed: 'pkg-config', 'internal')",
+                            var, value
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            return Ok((order, Some(var)));
+        }
+
+        // the `BUILD_INTERNAL` env variables take precedence over the metadata
+        let var = vec![
+            EnvVariable::new_build_internal(Some(&dep.key)),
+            EnvVariable::new_build_internal(None),
+        ]
+        .into_iter()
+        .find(|var| self.env.contains(var));
+        if !dep.resolve_order.is_empty() && var.is_none() {
+            return Ok((dep.resolve_order.clone(), None));
+        }
+
+        let order = match self.get_build_internal_status(&dep.key, build_internal)? {
+            BuildInternal::Never => vec![ResolveStage::PkgConfig],
+            BuildInternal::Auto => vec![ResolveStage::PkgConfig, ResolveStage::Internal],
+            BuildInternal::Always => vec![ResolveStage::Internal],
+        };
+        Ok((order, var))
+    }
+
+    // `default` is the value defined in the metadata of the selected feature version, if any
+    fn get_build_internal_status(
+        &self,
+        name: &str,
+        default: Option<BuildInternal>,
+    ) -> Result<BuildInternal, Error> {
         match self.get_build_internal_env_var(EnvVariable::new_build_internal(Some(name)))? {
             Some(b) => Ok(b),
             None => Ok(self
                 .get_build_internal_env_var(EnvVariable::new_build_internal(None))?
+                .or(default)
                 .unwrap_or_default()),
         }
     }
 
-    fn call_build_internal(&mut self, name: &str, version: &str) -> Result<Library, Error> {
-        let lib = match self.build_internals.remove(name) {
+    // Probe `lib_name` using pkg-config for `dep`
+    fn probe_pkg_config_lib(
+        &mut self,
+        dep: &metadata::Dependency,
+        lib_name: &str,
+        version: &str,
+        version_op: VersionOp,
+        any_version: bool,
+        search_paths: &[PathBuf],
+    ) -> Result<Library, Error> {
+        let name = &dep.key;
+        let statik = self.is_static(lib_name);
+        // the `pkg_config_paths` of the dep are looked up first
+        let dep_paths = if dep.pkg_config_paths.is_empty() {
+            Vec::new()
+        } else {
+            let dir = self.manifest_dir()?;
+            dep.pkg_config_paths
+                .iter()
+                .map(|p| Ok(dir.join(self.expand_env_vars(&dep.key, p)?)))
+                .collect::<Result<_, Error>>()?
+        };
+        let paths = dep_paths
+            .iter()
+            .chain(search_paths.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        let capture_requires = self.capture_requires;
+        let print_system_libs = self.should_print_system_libs(statik);
+        let probe = |version: Option<&str>| {
+            with_pkg_config_paths(&paths, || {
+                let mut config = pkg_config::Config::new();
+                if let Some(version) = version {
+                    version_op.apply(&mut config, version);
+                }
+                let lib = config
+                    .print_system_libs(print_system_libs)
+                    .cargo_metadata(false)
+                    .statik(statik)
+                    .probe(lib_name)?;
+                let vendored = !dep_paths.is_empty()
+                    && pkg_config::get_variable(lib_name, "pcfiledir")
+                        .is_ok_and(|dir| dep_paths.iter().any(|p| p == Path::new(&dir)));
+                let requires = if capture_requires {
+                    pkg_config_requires_graph(lib_name)?
+                } else {
+                    Vec::new()
+                };
+                Ok((lib, vendored, requires))
+            })
+        };
+
+        let required = (!any_version).then_some(version);
+        let force_missing = EnvVariable::new_force_missing(name);
+        let res = if self.env.contains(&force_missing) {
+            // Behave as if pkg-config did not find the library
+            Err(pkg_config::Error::EnvNoPkgConfig(force_missing.to_string()))
+        } else {
+            match (probe(required), self.allow_older(name)) {
+                // Use an older version of the lib, if any, when explicitly allowed
+                (Err(e), Some(allow_older)) if required.is_some() => match probe(None) {
+                    Ok((lib, vendored, requires)) => {
+                        self.warnings.push(format!(
+                            "{} {} is used while {} is required as {} is set",
+                            dep.describe(lib_name),
+                            lib.version,
+                            version,
+                            allow_older
+                        ));
+                        Ok((lib, vendored, requires, None))
+                    }
+                    Err(_) => Err(e),
+                },
+                (res, _) => {
+                    res.map(|(lib, vendored, requires)| (lib, vendored, requires, required))
+                }
+            }
+        };
+        match res {
+            Ok((lib, vendored, requires, version)) => {
+                let mut lib =
+                    with_pkg_config_paths(&paths, || Library::from_pkg_config(lib_name, lib));
+                debug!(
+                    "{}: found {} {} using pkg-config",
+                    name, lib_name, lib.version
+                );
+                match required {
+                    Some(required) if lib.version == UNKNOWN_VERSION && !required.is_empty() => {
+                        self.warnings.push(format!(
+                            "{} does not report its version, cannot check that {} is available",
+                            dep.describe(lib_name),
+                            required
+                        ));
+                    }
+                    _ => {}
+                }
+                if let Some(precision) = dep.requires_same_version {
+                    with_pkg_config_paths(&paths, || {
+                        check_requires_version(dep, lib_name, &lib.version, precision)
+                    })?;
+                }
+                if statik {
+                    // `pkg-config --static` also reports the `Cflags.private`, `statik` is set
+                    // explicitly as pkg-config would otherwise infer it from `$NAME_STATIC`
+                    let public = with_pkg_config_paths(&paths, || {
+                        pkg_config::Config::new()
+                            .cargo_metadata(false)
+                            .env_metadata(false)
+                            .statik(false)
+                            .probe(lib_name)
+                    });
+                    if let Ok(public) = public {
+                        lib.split_private_include_paths(&public.include_paths);
+                    }
+                }
+                lib.statik = statik;
+                lib.vendored = vendored;
+                lib.requires = requires.first().map(|(_, r)| r.clone()).unwrap_or_default();
+                lib.requires_graph = requires;
+                lib.probe_command = Some(probe_command(
+                    lib_name,
+                    version.map(|v| (version_op, v)),
+                    statik,
+                    &paths,
+                ));
+                lib.pkg_config_paths = paths;
+                Ok(lib)
+            }
+            Err(e) => {
+                debug!("{}: pkg-config failed: {}", name, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn call_build_internal(
+        &mut self,
+        name: &str,
+        dep: &metadata::Dependency,
+        version: &str,
+    ) -> Result<Library, Error> {
+        let display_name = &dep.describe(name);
+        // the closure registered for the key takes precedence over the one of the module name
+        let build_internal = self
+            .build_internals
+            .remove(&dep.key)
+            .or_else(|| self.build_internals.remove(name));
+        let mut lib = match build_internal {
             Some(f) => {
-                f(name, version).map_err(|e| Error::BuildInternalClosureError(name.into(), e))?
+                let ctx = BuildInternalContext {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    target: self.target(),
+                    statik: self.is_static(name),
+                    enabled_features: self.feature_names(),
+                };
+                let res = match (f, self.build_internal_timeout) {
+                    (BuildInternalFn::Send(f), Some(timeout)) => {
+                        let (sender, receiver) = mpsc::channel();
+                        let worker = thread::spawn(move || {
+                            // the receiver is gone if the closure timed out
+                            let _ = sender.send(f(&ctx));
+                        });
+                        match receiver.recv_timeout(timeout) {
+                            Ok(res) => res,
+                            Err(mpsc::RecvTimeoutError::Timeout) => {
+                                return Err(Error::BuildInternalTimeout(display_name.into()))
+                            }
+                            // the sender is only dropped without sending if the closure panicked
+                            Err(mpsc::RecvTimeoutError::Disconnected) => match worker.join() {
+                                Err(panic) => std::panic::resume_unwind(panic),
+                                Ok(()) => unreachable!("the closure did not return its result"),
+                            },
+                        }
+                    }
+                    (BuildInternalFn::Send(f), None) => f(&ctx),
+                    (BuildInternalFn::Local(f), _) => f(&ctx),
+                };
+                res.map_err(|e| Error::BuildInternalClosureError(display_name.into(), e))?
+            }
+            None => {
+                return Err(Error::BuildInternalNoClosure(
+                    display_name.into(),
+                    version.into(),
+                ))
             }
-            None => return Err(Error::BuildInternalNoClosure(name.into(), version.into())),
         };
+        lib.source = Source::BuildInternal;
+
+        if dep.skip_version_check {
+            return Ok(lib);
+        }
 
         // Check that the lib built internally matches the required version
         match VersionCompare::compare(&lib.version, version) {
             Ok(version_compare::CompOp::Lt) => Err(Error::BuildInternalWrongVersion(
-                name.into(),
+                display_name.into(),
                 lib.version.clone(),
                 version.into(),
             )),
@@ -694,43 +3790,267 @@ impl Config {
         }
     }
 
+    // Check that the Apple deployment target is not older than the `min_os_version` of `dep`
+    fn check_min_os_version(&self, dep: &metadata::Dependency) -> Result<(), Error> {
+        let min = match &dep.min_os_version {
+            Some(min) => min,
+            None => return Ok(()),
+        };
+        let var = match self.deployment_target_var() {
+            Some(var) => var,
+            None => return Ok(()),
+        };
+        // the default deployment target of rustc is used if not set
+        let deployment_target = match self.env.get(var) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        match VersionCompare::compare(&deployment_target, min) {
+            Ok(version_compare::CompOp::Lt) => Err(Error::DeploymentTargetTooOld(
+                dep.describe(&dep.key),
+                min.clone(),
+                var.to_string(),
+                deployment_target,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // The env variable defining the deployment target, if building for an Apple target
+    fn deployment_target_var(&self) -> Option<&'static str> {
+        let target = self.target().unwrap_or_default();
+        APPLE_DEPLOYMENT_TARGETS
+            .iter()
+            .find(|(os, _)| target.contains(&format!("-apple-{}", os)))
+            .map(|(_, var)| *var)
+    }
+
+    // Whether `framework` is declared with a `weak_below` version newer than the deployment target.
+    // The default deployment target of rustc may be older, so frameworks are weakly linked if it is not set.
+    fn is_weak_framework(&self, lib: &Library, framework: &str) -> bool {
+        let weak_below = match lib.weak_frameworks.iter().find(|(f, _)| f == framework) {
+            Some((_, weak_below)) => weak_below,
+            None => return false,
+        };
+        let var = match self.deployment_target_var() {
+            Some(var) => var,
+            None => return false,
+        };
+
+        match self.env.get(var) {
+            Some(deployment_target) => matches!(
+                VersionCompare::compare(&deployment_target, weak_below),
+                Ok(version_compare::CompOp::Lt)
+            ),
+            None => true,
+        }
+    }
+
+    fn target_is_windows(&self) -> bool {
+        self.target()
+            .is_some_and(|target| target.contains("-windows"))
+    }
+
+    // MSVC does not need link groups as it resolves circular references between libs,
+    // while Apple's ld64 does not support them
+    fn target_supports_link_groups(&self) -> bool {
+        !self
+            .target()
+            .is_some_and(|target| target.ends_with("-msvc") || target.contains("-apple-"))
+    }
+
+    // The native link search paths passed using `RUSTFLAGS`, if enabled
+    fn rustflags_link_paths(&self) -> Vec<PathBuf> {
+        if !self.respect_rustflags_link_paths {
+            return Vec::new();
+        }
+        let rustflags = self.env.get("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+
+        let mut paths = Vec::new();
+        let mut args = rustflags.split('\x1f').filter(|a| !a.is_empty());
+        while let Some(arg) = args.next() {
+            let value = match arg.strip_prefix("-L") {
+                Some("") => args.next(),
+                Some(value) => Some(value),
+                None => None,
+            };
+            let path = match value.map(|v| v.split_once('=').unwrap_or(("all", v))) {
+                Some(("native", path)) | Some(("all", path)) => path,
+                _ => continue,
+            };
+            paths.push(PathBuf::from(path));
+        }
+
+        paths
+    }
+
+    // The target being probed, `TARGET` unless overridden by `probe_for_targets`
+    fn target(&self) -> Option<String> {
+        self.target.clone().or_else(|| self.env.get("TARGET"))
+    }
+
+    // The `major.minor` version of the crate being built
+    fn crate_version(&self) -> Result<String, Error> {
+        let get = |var: &str| {
+            self.env
+                .get(var)
+                .ok_or_else(|| Error::InvalidMetadata(format!("${} not set", var)))
+        };
+
+        Ok(format!(
+            "{}.{}",
+            get("CARGO_PKG_VERSION_MAJOR")?,
+            get("CARGO_PKG_VERSION_MINOR")?
+        ))
+    }
+
+    // Same logic as the one used by pkg-config to decide if a library should be linked statically
+    fn is_static(&self, lib_name: &str) -> bool {
+        let name = lib_name.to_ascii_uppercase().replace('-', "_");
+
+        if self.env.contains(format!("{}_STATIC", name).as_str()) {
+            true
+        } else if self.env.contains(format!("{}_DYNAMIC", name).as_str()) {
+            false
+        } else {
+            self.env.contains("PKG_CONFIG_ALL_STATIC")
+        }
+    }
+
+    fn feature_names(&self) -> Vec<String> {
+        if let Some(features) = &self.features {
+            return features
+                .iter()
+                .map(|f| f.to_lowercase().replace('-', "_"))
+                .sorted()
+                .collect();
+        }
+
+        self.env
+            .vars()
+            .into_iter()
+            .filter_map(|(k, _)| {
+                k.strip_prefix("CARGO_FEATURE_")
+                    .map(|feature| feature.to_lowercase())
+            })
+            .sorted()
+            .collect()
+    }
+
     fn has_feature(&self, feature: &str) -> bool {
-        let var: &str = &format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
-        self.env.contains(var)
+        let var = feature.to_uppercase().replace('-', "_");
+        match &self.features {
+            Some(features) => features
+                .iter()
+                .any(|f| f.to_uppercase().replace('-', "_") == var),
+            None => self.env.contains(format!("CARGO_FEATURE_{}", var).as_str()),
+        }
     }
 
-    fn check_cfg(&self, cfg: &cfg_expr::Expression) -> Result<bool, Error> {
-        use cfg_expr::{targets::get_builtin_target_by_triple, Predicate};
+    fn check_cfg(&self, cache: &mut CfgCache, cfg: &cfg_expr::Expression) -> Result<bool, Error> {
+        use cfg_expr::{Predicate, TargetPredicate};
 
-        let target = self
-            .env
-            .get("TARGET")
-            .expect("no TARGET env variable defined");
-        let target = get_builtin_target_by_triple(&target)
-            .unwrap_or_else(|| panic!("Invalid TARGET: {}", target));
+        if let Some(res) = cache.results.get(cfg.original()) {
+            return Ok(*res);
+        }
 
         let res = cfg.eval(|pred| match pred {
-            Predicate::Target(tp) => Some(tp.matches(target)),
+            Predicate::Feature(feature) => Some(self.has_feature(feature)),
+            Predicate::Target(tp) => {
+                // the target is only needed by target predicates
+                let builtin = *cache.target.get_or_insert_with(|| self.cfg_target());
+                match (tp, builtin) {
+                    // bare-metal targets have no OS
+                    (TargetPredicate::Os(os), Some(target)) if os.0 == "none" => {
+                        Some(target.os.is_none())
+                    }
+                    (tp, Some(target)) => Some(tp.matches(target)),
+                    (tp, None) => self.cargo_cfg_matches(tp),
+                }
+            }
             _ => None,
         });
 
-        res.ok_or_else(|| Error::UnsupportedCfg(cfg.original().to_string()))
+        let res = res.ok_or_else(|| Error::UnsupportedCfg(cfg.original().to_string()))?;
+        trace!("{} evaluated to {}", cfg.original(), res);
+        cache.results.insert(cfg.original().to_string(), res);
+
+        Ok(res)
+    }
+
+    // The builtin target matching the target being probed, if any
+    fn cfg_target(&self) -> Option<&'static cfg_expr::targets::TargetInfo<'static>> {
+        let target = self.target();
+        let builtin = target
+            .as_deref()
+            .and_then(cfg_expr::targets::get_builtin_target_by_triple);
+        // Targets unknown to cfg-expr are described by the cfgs cargo passes to build scripts
+        let use_cargo_cfgs = builtin.is_none() && self.env.contains("CARGO_CFG_TARGET_OS");
+        if builtin.is_none() && !use_cargo_cfgs {
+            let target = target.expect("no TARGET env variable defined");
+            panic!("Invalid TARGET: {}", target);
+        }
+
+        builtin
+    }
+
+    // Evaluate `tp` using the `CARGO_CFG_TARGET_*` env variables
+    fn cargo_cfg_matches(&self, tp: &cfg_expr::TargetPredicate) -> Option<bool> {
+        use cfg_expr::{
+            targets::{Endian, Family},
+            TargetPredicate,
+        };
+
+        let get = |key: &str| self.env.get(format!("CARGO_CFG_TARGET_{}", key).as_str());
+
+        let res = match tp {
+            TargetPredicate::Arch(arch) => get("ARCH")? == arch.0,
+            TargetPredicate::Endian(endian) => get("ENDIAN")?.parse::<Endian>().ok()? == *endian,
+            // the environment and family are not defined on all targets
+            TargetPredicate::Env(env) => get("ENV").unwrap_or_default() == env.0,
+            TargetPredicate::Family(family) => get("FAMILY")
+                .unwrap_or_default()
+                .split(',')
+                .any(|f| f.parse::<Family>().ok() == Some(*family)),
+            TargetPredicate::Os(os) => get("OS")? == os.0,
+            TargetPredicate::PointerWidth(width) => {
+                get("POINTER_WIDTH")?.parse::<u8>().ok()? == *width
+            }
+            TargetPredicate::Vendor(vendor) => get("VENDOR")? == vendor.0,
+        };
+
+        Some(res)
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 /// From where the library settings have been retrieved
 pub enum Source {
     /// Settings have been retrieved from `pkg-config`
     PkgConfig,
     /// Settings have been defined using user defined environment variables
     EnvVariables,
+    /// Settings have been returned by the closure building the library internally,
+    /// see [Config::add_build_internal]
+    BuildInternal,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// How the include paths are passed to the compiler by [Library::cflags], see [Config::include_arg_style]
+pub enum IncludeArgStyle {
+    /// Using `-I`
+    #[default]
+    Normal,
+    /// Using `-isystem`, so the compiler does not report warnings from the headers of the library
+    ISystem,
+}
+
+#[derive(Debug, Clone)]
 /// A system dependency
 pub struct Library {
-    /// Name of the library
+    /// Name of the library, for groups declared using `one_of` the name of the member found
+    /// and for dependencies declared using `modules` the name of the first module found
     pub name: String,
     /// From where the library settings have been retrieved
     pub source: Source,
@@ -742,26 +4062,118 @@ pub struct Library {
     pub frameworks: Vec<String>,
     /// directories where the compiler should look for frameworks
     pub framework_paths: Vec<PathBuf>,
-    /// directories where the compiler should look for header files
+    /// directories where the compiler should look for all kinds of libraries
+    pub search_paths: Vec<PathBuf>,
+    /// directories where the compiler should look for header files, from the `Cflags` of the `.pc` file
     pub include_paths: Vec<PathBuf>,
+    /// directories where the compiler should look for header files only needed when linking
+    /// statically, from the `Cflags.private` of the `.pc` file
+    pub private_include_paths: Vec<PathBuf>,
     /// macros that should be defined by the compiler
     pub defines: HashMap<String, Option<String>>,
-    /// library version
+    /// library version, `unknown` if it is not defined by the `.pc` file
     pub version: String,
+    /// whether the library is linked statically
+    pub statik: bool,
+    /// arguments passed to the linker for all targets
+    pub link_args: Vec<String>,
+    /// arguments passed to the linker for binary targets only
+    pub link_args_bins: Vec<String>,
+    /// arguments passed to the linker for test targets only
+    pub link_args_tests: Vec<String>,
+    /// arguments passed to the linker for benchmark targets only
+    pub link_args_benches: Vec<String>,
+    // linker scripts passed in `link_args`
+    link_arg_files: Vec<PathBuf>,
+    /// libraries from [Library::libs] or [Library::verbatim_libs] which should be linked statically
+    pub static_libs: Vec<String>,
+    /// libraries from [Library::libs] which should be linked dynamically, using the `dylib` kind
+    pub dylib_libs: Vec<String>,
+    /// libraries from [Library::libs] which should be linked statically, including all their object files
+    pub whole_archive_libs: Vec<String>,
+    /// file names of libraries linked as they are, without adding any prefix or suffix
+    pub verbatim_libs: Vec<String>,
+    /// whether the library has been found in one of the `pkg_config_paths` of the dependency
+    /// rather than in the default `pkg-config` search path
+    pub vendored: bool,
+    probe_command: Option<String>,
+    requires: Vec<String>,
+    // `Requires` of the library module and of all the modules it transitively requires
+    requires_graph: Vec<(String, Vec<String>)>,
+    // declared as only providing defines and include paths
+    defines_only: bool,
+    // whether the build flags of the library are emitted by `Config::probe`
+    emit: bool,
+    // whether the libs are linked in a group, resolving their circular references
+    link_group: bool,
+    // the `SYSTEM_DEPS_*` env variables which changed how the library has been resolved or its settings
+    applied_env_vars: Vec<String>,
+    // (framework, version) of the frameworks weakly linked if the deployment target is older than version
+    weak_frameworks: Vec<(String, String)>,
+    // (variable, value) of the `export_variables` of the dependency, sorted by variable
+    exported_variables: Vec<(String, String)>,
+    prefix: Option<PathBuf>,
+    // directories looked up before the default `pkg-config` search path when probing the library
+    pkg_config_paths: Vec<PathBuf>,
+    // set using `Config::include_arg_style`
+    include_arg_style: IncludeArgStyle,
+    /// key of the dependency this one has been copied from if declared using `same_as`,
+    /// build flags are only emitted for the original dependency
+    pub same_as: Option<String>,
 }
 
 impl Library {
     fn from_pkg_config(name: &str, l: pkg_config::Library) -> Self {
+        // some `.pc` files only define the version as a variable
+        let version = if l.version.is_empty() {
+            pkg_config::get_variable(name, "version")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| UNKNOWN_VERSION.to_string())
+        } else {
+            l.version
+        };
+        let prefix = pkg_config::get_variable(name, "prefix")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
+
         Self {
             name: name.to_string(),
             source: Source::PkgConfig,
             libs: l.libs,
             link_paths: l.link_paths,
             include_paths: l.include_paths,
+            private_include_paths: Vec::new(),
             frameworks: l.frameworks,
             framework_paths: l.framework_paths,
+            search_paths: Vec::new(),
             defines: l.defines,
-            version: l.version,
+            version,
+            statik: false,
+            link_args: Vec::new(),
+            link_args_bins: Vec::new(),
+            link_args_tests: Vec::new(),
+            link_args_benches: Vec::new(),
+            link_arg_files: Vec::new(),
+            static_libs: Vec::new(),
+            dylib_libs: Vec::new(),
+            whole_archive_libs: Vec::new(),
+            verbatim_libs: Vec::new(),
+            vendored: false,
+            probe_command: None,
+            requires: Vec::new(),
+            requires_graph: Vec::new(),
+            defines_only: false,
+            emit: true,
+            link_group: false,
+            applied_env_vars: Vec::new(),
+            weak_frameworks: Vec::new(),
+            exported_variables: Vec::new(),
+            prefix,
+            pkg_config_paths: Vec::new(),
+            include_arg_style: IncludeArgStyle::Normal,
+            same_as: None,
         }
     }
 
@@ -772,11 +4184,203 @@ impl Library {
             libs: Vec::new(),
             link_paths: Vec::new(),
             include_paths: Vec::new(),
+            private_include_paths: Vec::new(),
             frameworks: Vec::new(),
             framework_paths: Vec::new(),
+            search_paths: Vec::new(),
             defines: HashMap::new(),
             version: String::new(),
+            statik: false,
+            link_args: Vec::new(),
+            link_args_bins: Vec::new(),
+            link_args_tests: Vec::new(),
+            link_args_benches: Vec::new(),
+            link_arg_files: Vec::new(),
+            static_libs: Vec::new(),
+            dylib_libs: Vec::new(),
+            whole_archive_libs: Vec::new(),
+            verbatim_libs: Vec::new(),
+            vendored: false,
+            probe_command: None,
+            requires: Vec::new(),
+            requires_graph: Vec::new(),
+            defines_only: false,
+            emit: true,
+            link_group: false,
+            applied_env_vars: Vec::new(),
+            weak_frameworks: Vec::new(),
+            exported_variables: Vec::new(),
+            prefix: None,
+            pkg_config_paths: Vec::new(),
+            include_arg_style: IncludeArgStyle::Normal,
+            same_as: None,
+        }
+    }
+
+    // Aggregate the settings of another module of the dependency
+    fn add_module(&mut self, module: Library) {
+        extend_dedup(&mut self.libs, &module.libs);
+        extend_dedup(&mut self.link_paths, &module.link_paths);
+        extend_dedup(&mut self.frameworks, &module.frameworks);
+        extend_dedup(&mut self.framework_paths, &module.framework_paths);
+        extend_dedup(&mut self.include_paths, &module.include_paths);
+        extend_dedup(
+            &mut self.private_include_paths,
+            &module.private_include_paths,
+        );
+        for (name, value) in module.defines {
+            self.defines.entry(name).or_insert(value);
+        }
+        extend_dedup(&mut self.requires, &module.requires);
+        extend_dedup(&mut self.requires_graph, &module.requires_graph);
+        self.vendored |= module.vendored;
+    }
+
+    // Move the include paths which are not `public` to the private ones
+    fn split_private_include_paths(&mut self, public: &[PathBuf]) {
+        let (public, private): (Vec<_>, Vec<_>) = self
+            .include_paths
+            .drain(..)
+            .partition(|p| public.contains(p));
+        self.include_paths = public;
+        // the `--static` flags may list the same path several times
+        self.private_include_paths.clear();
+        extend_dedup(&mut self.private_include_paths, &private);
+    }
+
+    /// Return the [Library::defines] formatted as compiler arguments, sorted by name:
+    /// `-DKEY` for macros without value and `-DKEY=VALUE` for the others.
+    ///
+    /// Each string is a single argument, as expected by `cc::Build::flag` or
+    /// `bindgen::Builder::clang_args`, so values are not shell-quoted: `FOO` defined as `a b`
+    /// results in `-DFOO=a b`.
+    pub fn defines_as_args(&self) -> Vec<String> {
+        self.defines
+            .iter()
+            .sorted()
+            .map(|(k, v)| define_as_arg(k, v))
+            .collect()
+    }
+
+    /// Return the [Library::include_paths] formatted as compiler arguments: `-IPATH` for each path.
+    pub fn include_args(&self) -> Vec<String> {
+        self.include_paths
+            .iter()
+            .map(|p| format!("-I{}", p.display()))
+            .collect()
+    }
+
+    /// Return the [Library::include_paths] formatted as compiler arguments using `-isystem`,
+    /// so the compiler does not report warnings from the headers of the library.
+    ///
+    /// Each path is returned as two arguments, `-isystem` and the path, as expected by
+    /// `bindgen::Builder::clang_args` for example.
+    pub fn isystem_args(&self) -> Vec<String> {
+        self.include_paths
+            .iter()
+            .flat_map(|p| vec!["-isystem".to_string(), p.display().to_string()])
+            .collect()
+    }
+
+    /// Return the compiler arguments needed to use the library: its include paths, passed
+    /// using [Library::include_args] or [Library::isystem_args] depending on [Config::include_arg_style],
+    /// followed by [Library::defines_as_args].
+    pub fn cflags(&self) -> Vec<String> {
+        let mut args = match self.include_arg_style {
+            IncludeArgStyle::Normal => self.include_args(),
+            IncludeArgStyle::ISystem => self.isystem_args(),
+        };
+        args.extend(self.defines_as_args());
+        args
+    }
+
+    /// Return `true` if the library is linked statically.
+    ///
+    /// Libraries found using `pkg-config` are linked statically if either
+    /// `$LIB_STATIC` or `PKG_CONFIG_ALL_STATIC` is defined, and `$LIB_DYNAMIC` is not,
+    /// with `$LIB` being the upper case name of the `pkg-config` module.
+    ///
+    /// Frameworks cannot be linked statically: a warning is emitted for each
+    /// [Library::frameworks] of a static library.
+    pub fn is_static(&self) -> bool {
+        self.statik
+    }
+
+    /// Return the `SYSTEM_DEPS_*` environment variables which have been applied to the library,
+    /// in the order they have been applied.
+    ///
+    /// Those are the variables overriding its build flags, such as `SYSTEM_DEPS_$NAME_LIB` or
+    /// `SYSTEM_DEPS_OVERRIDES`, as well as the ones selecting how it has been resolved,
+    /// such as `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` or `SYSTEM_DEPS_$NAME_NO_PKG_CONFIG`.
+    pub fn applied_env_vars(&self) -> &[String] {
+        &self.applied_env_vars
+    }
+
+    /// Return the `pkg-config` variables listed in the `export_variables` of the dependency,
+    /// as `(variable, value)` pairs sorted by variable.
+    ///
+    /// [Config::probe] emits them as `cargo:$NAME_$VARIABLE=$VALUE`.
+    pub fn exported_variables(&self) -> &[(String, String)] {
+        &self.exported_variables
+    }
+
+    /// Return `true` if the build flags of the library are emitted by [Config::probe].
+    ///
+    /// Those of a dependency declared with `emit = false`, or for which `SYSTEM_DEPS_$NAME_NO_EMIT`
+    /// is defined, are left to the caller.
+    pub fn is_emitted(&self) -> bool {
+        self.emit
+    }
+
+    /// Return the `pkg-config` command line used to find the library, or `None` if
+    /// it has not been found using `pkg-config`, for debugging purposes.
+    ///
+    /// For example: `pkg-config --libs --cflags --atleast-version=1.2 testlib`
+    pub fn probe_command(&self) -> Option<String> {
+        self.probe_command.clone()
+    }
+
+    /// Return the `pkg-config` modules listed in the `Requires` field of the library `.pc` file.
+    ///
+    /// Those are only retrieved if enabled using [Config::capture_requires].
+    pub fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    /// Return the installation prefix of the library, as defined by the `prefix` variable
+    /// of its `.pc` file, or `None` if it has not been found using `pkg-config`.
+    ///
+    /// Useful to locate the data files or tools installed with the library.
+    pub fn prefix(&self) -> Option<PathBuf> {
+        self.prefix.clone()
+    }
+
+    /// Return the path of a tool installed with the library, as defined by the `variable`
+    /// of its `.pc` file, such as `glib_mkenums` for `glib-2.0`.
+    ///
+    /// Relative paths are resolved against [Library::prefix].
+    /// Returns `None` if the library has not been found using `pkg-config`, if the variable is not defined,
+    /// or if the file it references does not exist or is not executable.
+    ///
+    /// # Arguments
+    /// * `variable`: the name of the `pkg-config` variable defining the path of the tool
+    pub fn tool_path(&self, variable: &str) -> Option<PathBuf> {
+        if self.source == Source::EnvVariables {
+            return None;
         }
+        let value = with_pkg_config_paths(&self.pkg_config_paths, || {
+            pkg_config::get_variable(&self.name, variable)
+        })
+        .ok()
+        .filter(|v| !v.is_empty())?;
+
+        let path = PathBuf::from(value);
+        let path = if path.is_relative() {
+            self.prefix.as_ref()?.join(path)
+        } else {
+            path
+        };
+        is_executable(&path).then_some(path)
     }
 
     /// Create a `Library` by probing `pkg-config` on an internal directory.
@@ -820,15 +4424,19 @@ impl Library {
         }
 
         let pkg_lib = pkg_config::Config::new()
-            .atleast_version(&version)
+            .atleast_version(version)
             .print_system_libs(false)
             .cargo_metadata(false)
             .probe(lib);
 
-        env::set_var("PKG_CONFIG_PATH", &old.unwrap_or_else(|_| "".into()));
+        env::set_var("PKG_CONFIG_PATH", old.unwrap_or_else(|_| "".into()));
 
         match pkg_lib {
-            Ok(pkg_lib) => Ok(Self::from_pkg_config(&lib, pkg_lib)),
+            Ok(pkg_lib) => {
+                let mut lib = Self::from_pkg_config(lib, pkg_lib);
+                lib.pkg_config_paths = vec![pkg_config_dir.as_ref().to_path_buf()];
+                Ok(lib)
+            }
             Err(e) => Err(e.into()),
         }
     }
@@ -848,6 +4456,19 @@ trait EnvVariablesExt<T> {
     fn get(&self, var: T) -> Option<String>;
 }
 
+impl EnvVariables {
+    fn vars(&self) -> Vec<(String, String)> {
+        match self {
+            EnvVariables::Environnement => env::vars().collect(),
+            #[cfg(test)]
+            EnvVariables::Mock(vars) => vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        }
+    }
+}
+
 impl EnvVariablesExt<&str> for EnvVariables {
     fn get(&self, var: &str) -> Option<String> {
         match self {
@@ -866,26 +4487,60 @@ impl EnvVariablesExt<&EnvVariable> for EnvVariables {
     }
 }
 
-// TODO: add support for "rustc-link-lib=static=" ?
 #[derive(Debug, PartialEq)]
 enum BuildFlag {
     Include(String),
+    Metadata(String, String),
     SearchNative(String),
     SearchFramework(String),
+    Search(String),
     Lib(String),
+    LibStatic(String),
+    LibDylib(String),
+    LibWholeArchive(String),
+    VerbatimLib(String, LinkKind),
     LibFramework(String),
+    LinkArg(String),
+    LinkArgBins(String),
+    LinkArgTests(String),
+    LinkArgBenches(String),
     RerunIfEnvChanged(EnvVariable),
+    RerunIfChanged(String),
+    RustcCfg(String),
+    RustcCheckCfg(String),
+    Warning(String),
 }
 
 impl fmt::Display for BuildFlag {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             BuildFlag::Include(paths) => write!(f, "include={}", paths),
+            BuildFlag::Metadata(key, value) => write!(f, "{}={}", key, value),
             BuildFlag::SearchNative(lib) => write!(f, "rustc-link-search=native={}", lib),
             BuildFlag::SearchFramework(lib) => write!(f, "rustc-link-search=framework={}", lib),
+            BuildFlag::Search(lib) => write!(f, "rustc-link-search={}", lib),
             BuildFlag::Lib(lib) => write!(f, "rustc-link-lib={}", lib),
+            BuildFlag::LibStatic(lib) => write!(f, "rustc-link-lib=static={}", lib),
+            BuildFlag::LibDylib(lib) => write!(f, "rustc-link-lib=dylib={}", lib),
+            BuildFlag::LibWholeArchive(lib) => {
+                write!(f, "rustc-link-lib=static:+whole-archive={}", lib)
+            }
+            BuildFlag::VerbatimLib(lib, LinkKind::Static) => {
+                write!(f, "rustc-link-lib=static:+verbatim={}", lib)
+            }
+            BuildFlag::VerbatimLib(lib, LinkKind::Dylib) => {
+                write!(f, "rustc-link-lib=dylib:+verbatim={}", lib)
+            }
             BuildFlag::LibFramework(lib) => write!(f, "rustc-link-lib=framework={}", lib),
+            BuildFlag::LinkArg(arg) => write!(f, "rustc-link-arg={}", arg),
+            BuildFlag::LinkArgBins(arg) => write!(f, "rustc-link-arg-bins={}", arg),
+            BuildFlag::LinkArgTests(arg) => write!(f, "rustc-link-arg-tests={}", arg),
+            BuildFlag::LinkArgBenches(arg) => write!(f, "rustc-link-arg-benches={}", arg),
             BuildFlag::RerunIfEnvChanged(env) => write!(f, "rerun-if-env-changed={}", env),
+            BuildFlag::RerunIfChanged(path) => write!(f, "rerun-if-changed={}", path),
+            BuildFlag::RustcCfg(cfg) => write!(f, "rustc-cfg={}", cfg),
+            BuildFlag::RustcCheckCfg(cfg) => write!(f, "rustc-check-cfg={}", cfg),
+            BuildFlag::Warning(msg) => write!(f, "warning={}", msg),
         }
     }
 }
@@ -901,17 +4556,330 @@ impl BuildFlags {
     fn add(&mut self, flag: BuildFlag) {
         self.0.push(flag);
     }
+
+    fn lines(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|flag| format!("cargo:{}", flag))
+            .collect()
+    }
 }
 
 impl fmt::Display for BuildFlags {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for flag in self.0.iter() {
-            writeln!(f, "cargo:{}", flag)?;
+        for line in self.lines() {
+            writeln!(f, "{}", line)?;
         }
         Ok(())
     }
 }
 
+fn is_executable(path: &Path) -> bool {
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    {
+        metadata.is_file()
+    }
+}
+
+// Call `f` with `paths` prepended to `PKG_CONFIG_PATH`, restoring it afterward
+fn with_pkg_config_paths<T, F: FnOnce() -> T>(paths: &[PathBuf], f: F) -> T {
+    if paths.is_empty() {
+        return f();
+    }
+
+    let old = env::var_os("PKG_CONFIG_PATH");
+    let mut new_paths = paths.to_vec();
+    if let Some(ref old) = old {
+        new_paths.extend(env::split_paths(old));
+    }
+    env::set_var("PKG_CONFIG_PATH", env::join_paths(new_paths).unwrap());
+
+    let res = f();
+
+    match old {
+        Some(old) => env::set_var("PKG_CONFIG_PATH", old),
+        None => env::remove_var("PKG_CONFIG_PATH"),
+    }
+
+    res
+}
+
+// Check that the modules required by `lib_name` have the same `version` as it, up to `precision`
+fn check_requires_version(
+    dep: &metadata::Dependency,
+    lib_name: &str,
+    version: &str,
+    precision: VersionPrecision,
+) -> Result<(), Error> {
+    let truncate = |v: &str| {
+        v.split('.')
+            .take(precision as usize)
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    for module in pkg_config_requires(lib_name)? {
+        let module_version = pkg_config_query(&["--modversion"], &module)?;
+        let module_version = module_version.trim();
+        if truncate(module_version) != truncate(version) {
+            return Err(Error::MismatchedVersion(
+                format!("{} {}", dep.describe(lib_name), version),
+                module,
+                module_version.to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Check that the feature versions of `dep` do not require a version older than the previous ones
+fn check_feature_versions(dep: &metadata::Dependency) -> Result<(), Error> {
+    let base = dep
+        .version
+        .as_ref()
+        .filter(|v| *v != "crate")
+        .map(|v| (&dep.key, v));
+    let versions = base
+        .into_iter()
+        .chain(dep.version_overrides.iter().map(|o| (&o.key, &o.version)));
+
+    for ((prev_key, prev), (key, version)) in versions.tuple_windows() {
+        if let Ok(version_compare::CompOp::Lt) = VersionCompare::compare(version, prev) {
+            return Err(Error::InvalidMetadata(format!(
+                "{}: {} requires version {} which is older than the {} required by {}",
+                dep.describe(&dep.key),
+                key,
+                version,
+                prev,
+                prev_key
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Check that none of the found `libraries` conflicts with another one
+fn check_conflicts(metadata: &MetaData, libraries: &Dependencies) -> Result<(), Error> {
+    for dep in metadata.deps.iter() {
+        if libraries.get_by_name(&dep.key).is_none() {
+            continue;
+        }
+        if let Some(other) = dep
+            .conflicts_with
+            .iter()
+            .find(|other| libraries.get_by_name(other).is_some())
+        {
+            let other = match metadata.deps.iter().find(|d| &d.key == other) {
+                Some(d) => d.describe(other),
+                None => other.clone(),
+            };
+            return Err(Error::ConflictingDeps(dep.describe(&dep.key), other));
+        }
+    }
+
+    Ok(())
+}
+
+// Check that `lib` has all the defines required by `dep`
+fn check_required_defines(dep: &metadata::Dependency, lib: &Library) -> Result<(), Error> {
+    for (name, value) in dep.require_defines.iter() {
+        let found = match (lib.defines.get(name), value) {
+            (Some(found), Some(value)) => found.as_ref() == Some(value),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if !found {
+            let define = match value {
+                Some(value) => format!("{}={}", name, value),
+                None => name.clone(),
+            };
+            return Err(Error::MissingDefine(dep.describe(&dep.key), define));
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the env variable `var` may change the result of probing
+fn affects_probing(var: &str) -> bool {
+    const PREFIXES: &[&str] = &[
+        "SYSTEM_DEPS_",
+        "PKG_CONFIG",
+        "CARGO_FEATURE_",
+        "CARGO_CFG_TARGET_",
+        "CARGO_PKG_VERSION",
+    ];
+    const SUFFIXES: &[&str] = &["_STATIC", "_DYNAMIC", "_DEPLOYMENT_TARGET"];
+
+    var == "TARGET"
+        || var == "HOST"
+        || var == "CARGO_ENCODED_RUSTFLAGS"
+        || PREFIXES.iter().any(|p| var.starts_with(p))
+        || SUFFIXES.iter().any(|s| var.ends_with(s))
+}
+
+// Call `f` with the env variable `name` set to `value`, if any, then restore its previous value
+fn with_env_var<T, F: FnOnce() -> T>(name: &str, value: Option<&Path>, f: F) -> T {
+    let value = match value {
+        Some(value) => value,
+        None => return f(),
+    };
+
+    let old = env::var_os(name);
+    env::set_var(name, value);
+
+    let res = f();
+
+    match old {
+        Some(old) => env::set_var(name, old),
+        None => env::remove_var(name),
+    }
+
+    res
+}
+
+// Call `f` with the env variables `vars` set, then restore their previous values
+fn with_env_vars<T, F: FnOnce() -> T>(vars: &[(String, String)], f: F) -> T {
+    let old = vars
+        .iter()
+        .map(|(name, value)| {
+            let old = env::var_os(name);
+            env::set_var(name, value);
+            (name, old)
+        })
+        .collect::<Vec<_>>();
+
+    let res = f();
+
+    for (name, old) in old.into_iter().rev() {
+        match old {
+            Some(old) => env::set_var(name, old),
+            None => env::remove_var(name),
+        }
+    }
+
+    res
+}
+
+// Run `pkg-config` with `args` for `lib_name`, returning its output
+fn pkg_config_query(args: &[&str], lib_name: &str) -> Result<String, pkg_config::Error> {
+    let exe = env::var_os("PKG_CONFIG").unwrap_or_else(|| "pkg-config".into());
+    let mut cmd = std::process::Command::new(exe);
+    cmd.args(args).arg(lib_name);
+    let command = format!("{:?}", cmd);
+
+    let output = cmd.output().map_err(|cause| pkg_config::Error::Command {
+        command: command.clone(),
+        cause,
+    })?;
+    if !output.status.success() {
+        return Err(pkg_config::Error::Failure { command, output });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The modules required by `lib_name`, as reported by `pkg-config --print-requires`
+fn pkg_config_requires(lib_name: &str) -> Result<Vec<String>, pkg_config::Error> {
+    // each line is a module name, optionally followed by a version constraint
+    Ok(pkg_config_query(&["--print-requires"], lib_name)?
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .map(|m| m.to_string())
+        .collect())
+}
+
+// The modules required by `lib_name` and, recursively, by each of them, `lib_name` coming first
+fn pkg_config_requires_graph(
+    lib_name: &str,
+) -> Result<Vec<(String, Vec<String>)>, pkg_config::Error> {
+    let mut graph: Vec<(String, Vec<String>)> = Vec::new();
+    let mut todo = vec![lib_name.to_string()];
+    while let Some(module) = todo.pop() {
+        // `.pc` files may require each other
+        if graph.iter().any(|(m, _)| *m == module) {
+            continue;
+        }
+        let requires = pkg_config_requires(&module)?;
+        todo.extend(requires.iter().rev().cloned());
+        graph.push((module, requires));
+    }
+    Ok(graph)
+}
+
+// Reconstruct the pkg-config invocation probing `lib_name`
+fn probe_command(
+    lib_name: &str,
+    version: Option<(VersionOp, &str)>,
+    statik: bool,
+    pkg_config_paths: &[PathBuf],
+) -> String {
+    let mut args = Vec::new();
+    if !pkg_config_paths.is_empty() {
+        let paths = pkg_config_paths
+            .iter()
+            .map(|p| p.as_os_str())
+            .chain(std::iter::once("$PKG_CONFIG_PATH".as_ref()));
+        if let Ok(paths) = env::join_paths(paths) {
+            args.push(format!("PKG_CONFIG_PATH={}", paths.to_string_lossy()));
+        }
+    }
+    args.extend(
+        ["pkg-config", "--libs", "--cflags"]
+            .iter()
+            .map(|a| a.to_string()),
+    );
+    if statik {
+        args.push("--static".to_string());
+    }
+    if let Some((op, version)) = version {
+        args.push(format!("{}={}", op.arg(), version));
+    }
+    args.push(lib_name.to_string());
+
+    args.join(" ")
+}
+
+// The pkg-config directory of the Homebrew installation, if any
+fn homebrew_pkg_config_dir() -> Option<PathBuf> {
+    if !cfg!(target_os = "macos") {
+        return None;
+    }
+
+    let output = std::process::Command::new("brew")
+        .arg("--prefix")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let prefix = String::from_utf8(output.stdout).ok()?;
+    let dir = Path::new(prefix.trim()).join("lib").join("pkgconfig");
+    if dir.is_dir() {
+        Some(dir)
+    } else {
+        None
+    }
+}
+
+fn define_as_arg(key: &str, value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("-D{}={}", key, value),
+        None => format!("-D{}", key),
+    }
+}
+
 fn split_paths(value: &str) -> Vec<PathBuf> {
     if !value.is_empty() {
         let paths = env::split_paths(&value);
@@ -929,16 +4897,68 @@ fn split_string(value: &str) -> Vec<String> {
     }
 }
 
-#[derive(Debug, PartialEq, EnumString)]
+// Ways of resolving a dependency, see `resolve_order`
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, strum_macros::Display)]
+enum ResolveStage {
+    #[strum(serialize = "pkg-config")]
+    PkgConfig,
+    #[strum(serialize = "internal")]
+    Internal,
+}
+
+// Kind of the libraries declared using `libs`
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+enum LinkKind {
+    Static,
+    Dylib,
+}
+
+// How the version found is compared to the required one, see `version_op`
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Default)]
+#[strum(serialize_all = "snake_case")]
+enum VersionOp {
+    #[default]
+    Atleast,
+    Exact,
+    Max,
+}
+
+impl VersionOp {
+    // Require `version` when probing using `config`
+    fn apply(self, config: &mut pkg_config::Config, version: &str) {
+        match self {
+            VersionOp::Atleast => config.atleast_version(version),
+            VersionOp::Exact => config.exactly_version(version),
+            VersionOp::Max => config.range_version(..=version),
+        };
+    }
+
+    // The matching `pkg-config` argument
+    fn arg(self) -> &'static str {
+        match self {
+            VersionOp::Atleast => "--atleast-version",
+            VersionOp::Exact => "--exact-version",
+            VersionOp::Max => "--max-version",
+        }
+    }
+}
+
+// Number of version components compared by `requires_same_version`
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+enum VersionPrecision {
+    Major = 1,
+    Minor = 2,
+    Patch = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
 #[strum(serialize_all = "snake_case")]
+#[derive(Default)]
 enum BuildInternal {
     Auto,
     Always,
+    #[default]
     Never,
 }
-
-impl Default for BuildInternal {
-    fn default() -> Self {
-        BuildInternal::Never
-    }
-}
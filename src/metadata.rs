@@ -1,13 +1,26 @@
 // Parse system-deps metadata from Cargo.toml
 
-use std::{fs, io::Read, path::Path};
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::{anyhow, bail, Error};
+use heck::SnakeCase;
+use itertools::Itertools;
 use toml::{map::Map, Value};
 
-#[derive(Debug, PartialEq)]
+use crate::{BuildInternal, LinkKind, ResolveStage, VersionOp, VersionPrecision};
+
+const SECTION: &str = "package.metadata.system-deps";
+
+#[derive(Debug, Default, PartialEq)]
 pub(crate) struct MetaData {
     pub(crate) deps: Vec<Dependency>,
+    // external files the dependencies have been read from
+    pub(crate) includes: Vec<PathBuf>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -17,8 +30,52 @@ pub(crate) struct Dependency {
     pub(crate) name: Option<String>,
     pub(crate) feature: Option<String>,
     pub(crate) optional: bool,
+    pub(crate) always_probe: bool,
+    pub(crate) any_version: bool,
     pub(crate) cfg: Option<cfg_expr::Expression>,
     pub(crate) version_overrides: Vec<VersionOverride>,
+    pub(crate) link_args: Vec<String>,
+    pub(crate) link_args_bins: Vec<String>,
+    pub(crate) link_args_tests: Vec<String>,
+    pub(crate) link_args_benches: Vec<String>,
+    pub(crate) link_arg_files: Vec<PathBuf>,
+    pub(crate) static_libs: Vec<String>,
+    // (name, kind) of the libraries linked on top of, or instead of, the ones from pkg-config
+    pub(crate) libs: Vec<(String, Option<LinkKind>)>,
+    pub(crate) replace_libs: bool,
+    pub(crate) whole_archive_libs: Vec<String>,
+    pub(crate) verbatim_libs: Vec<String>,
+    pub(crate) exclude_libs: Vec<String>,
+    pub(crate) pkg_config_paths: Vec<PathBuf>,
+    pub(crate) conflicts_with: Vec<String>,
+    pub(crate) same_as: Option<String>,
+    // (name, version) of the members of a group, in declaration order
+    pub(crate) one_of: Vec<(String, String)>,
+    // (name, version) of the modules all providing the dependency, in declaration order
+    pub(crate) modules: Vec<(String, String)>,
+    // whether the modules which cannot be found are skipped rather than failing
+    pub(crate) allow_partial: bool,
+    pub(crate) description: Option<String>,
+    pub(crate) cfg_name: Option<String>,
+    pub(crate) defines_only: bool,
+    pub(crate) emit: bool,
+    pub(crate) link_group: bool,
+    pub(crate) require_version_feature: bool,
+    // precision up to which the modules required by the library must have the same version
+    pub(crate) requires_same_version: Option<VersionPrecision>,
+    // ways of resolving the dependency to try in order, empty if not declared
+    pub(crate) resolve_order: Vec<ResolveStage>,
+    pub(crate) skip_version_check: bool,
+    // `pkg-config` variables exported to the build scripts of the dependent crates
+    pub(crate) export_variables: Vec<String>,
+    pub(crate) version_op: VersionOp,
+    pub(crate) min_os_version: Option<String>,
+    // (name, weak_below) of the frameworks linked on top of the ones from pkg-config
+    pub(crate) frameworks: Vec<(String, Option<String>)>,
+    // (name, value) of the defines the library must have
+    pub(crate) require_defines: Vec<(String, Option<String>)>,
+    // settings used if the library cannot be resolved
+    pub(crate) fallback: Option<Fallback>,
 }
 
 impl Dependency {
@@ -32,6 +89,14 @@ impl Dependency {
     pub(crate) fn lib_name(&self) -> String {
         self.name.as_ref().unwrap_or(&self.key).to_string()
     }
+
+    // `name` prefixed with the description of the dep, if any, to be displayed to users
+    pub(crate) fn describe(&self, name: &str) -> String {
+        match &self.description {
+            Some(description) => format!("{} ({})", description, name),
+            None => name.to_string(),
+        }
+    }
 }
 
 impl Default for Dependency {
@@ -42,25 +107,80 @@ impl Default for Dependency {
             name: None,
             feature: None,
             optional: false,
+            always_probe: false,
+            any_version: false,
             cfg: None,
             version_overrides: Vec::new(),
+            link_args: Vec::new(),
+            link_args_bins: Vec::new(),
+            link_args_tests: Vec::new(),
+            link_args_benches: Vec::new(),
+            link_arg_files: Vec::new(),
+            static_libs: Vec::new(),
+            libs: Vec::new(),
+            replace_libs: false,
+            whole_archive_libs: Vec::new(),
+            verbatim_libs: Vec::new(),
+            exclude_libs: Vec::new(),
+            pkg_config_paths: Vec::new(),
+            conflicts_with: Vec::new(),
+            same_as: None,
+            one_of: Vec::new(),
+            modules: Vec::new(),
+            allow_partial: false,
+            description: None,
+            cfg_name: None,
+            defines_only: false,
+            emit: true,
+            link_group: false,
+            require_version_feature: false,
+            requires_same_version: None,
+            resolve_order: Vec::new(),
+            skip_version_check: false,
+            export_variables: Vec::new(),
+            version_op: VersionOp::Atleast,
+            min_os_version: None,
+            frameworks: Vec::new(),
+            require_defines: Vec::new(),
+            fallback: None,
         }
     }
 }
 
+// Build flags of a dependency declared using `fallback`, used if it cannot be resolved
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Fallback {
+    // only used if the target matches
+    pub(crate) cfg: Option<cfg_expr::Expression>,
+    pub(crate) libs: Vec<String>,
+    pub(crate) link_paths: Vec<PathBuf>,
+    pub(crate) include_paths: Vec<PathBuf>,
+    pub(crate) frameworks: Vec<String>,
+    pub(crate) framework_paths: Vec<PathBuf>,
+}
+
 #[derive(Debug, PartialEq)]
 pub(crate) struct VersionOverride {
     pub(crate) key: String,
     pub(crate) version: String,
     pub(crate) name: Option<String>,
     pub(crate) optional: Option<bool>,
+    pub(crate) build_internal: Option<BuildInternal>,
+    pub(crate) frameworks: Vec<String>,
+    pub(crate) framework_paths: Vec<PathBuf>,
+    // set if the override declares its own comparison, using `version = { exact = "1.0" }`
+    pub(crate) version_op: Option<VersionOp>,
 }
 
 struct VersionOverrideBuilder {
     version_id: String,
     version: Option<String>,
+    version_op: Option<VersionOp>,
     full_name: Option<String>,
     optional: Option<bool>,
+    build_internal: Option<BuildInternal>,
+    frameworks: Vec<String>,
+    framework_paths: Vec<PathBuf>,
 }
 
 impl VersionOverrideBuilder {
@@ -68,8 +188,12 @@ impl VersionOverrideBuilder {
         Self {
             version_id: version_id.to_string(),
             version: None,
+            version_op: None,
             full_name: None,
             optional: None,
+            build_internal: None,
+            frameworks: Vec::new(),
+            framework_paths: Vec::new(),
         }
     }
 
@@ -83,75 +207,355 @@ impl VersionOverrideBuilder {
             version,
             name: self.full_name,
             optional: self.optional,
+            build_internal: self.build_internal,
+            frameworks: self.frameworks,
+            framework_paths: self.framework_paths,
+            version_op: self.version_op,
         })
     }
 }
 
 impl MetaData {
-    pub(crate) fn from_file(path: &Path) -> Result<Self, crate::Error> {
-        let mut manifest = fs::File::open(&path).map_err(|e| {
+    // Parse the metadata of the manifest at `path`. If `check_orig` is set and the manifest has no metadata section,
+    // the one of the `Cargo.toml.orig` next to it is used, the manifest used being then listed in `includes`.
+    pub(crate) fn from_file(path: &Path, check_orig: bool) -> Result<Self, crate::Error> {
+        // A missing manifest or metadata section means that no dependency is declared,
+        // so they can still be fully defined using env variables.
+        let manifest_str = match Self::read_file(path) {
+            Err(crate::Error::FailToRead(_, e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            res => res?,
+        };
+        let invalid =
+            |e: Error| crate::Error::InvalidMetadata(format!("{}: {}", path.display(), e));
+
+        let (meta, mut includes) = match Self::read_metadata(path, manifest_str)? {
+            Some(res) => res,
+            // `cargo package` may have stripped the section from the normalized manifest
+            None if check_orig => {
+                let orig = path.with_file_name("Cargo.toml.orig");
+                if !orig.exists() {
+                    return Ok(Self::default());
+                }
+                let mut meta = Self::from_file(&orig, false)?;
+                meta.includes.insert(0, orig);
+                return Ok(meta);
+            }
+            None => return Ok(Self::default()),
+        };
+        if check_orig {
+            includes.insert(0, path.to_path_buf());
+        }
+
+        let mut errors = Vec::new();
+        let deps = Self::parse_deps_table(&meta, SECTION, true, &mut errors);
+        if let Some(e) = errors.into_iter().next() {
+            return Err(invalid(e));
+        }
+
+        let meta = MetaData { deps, includes };
+        meta.check_same_as().map_err(invalid)?;
+
+        Ok(meta)
+    }
+
+    // Check the metadata of the manifest at `path` without probing, reporting all the problems found
+    pub(crate) fn validate_file(path: &Path) -> Vec<crate::Error> {
+        let invalid =
+            |e: Error| crate::Error::InvalidMetadata(format!("{}: {}", path.display(), e));
+
+        let meta = match Self::read_file(path).and_then(|s| Self::read_metadata(path, s)) {
+            Ok(Some((meta, _))) => meta,
+            Ok(None) => return Vec::new(),
+            Err(e) => return vec![e],
+        };
+
+        let mut errors = Vec::new();
+        let deps = Self::parse_deps_table(&meta, SECTION, true, &mut errors);
+        let meta = MetaData {
+            deps,
+            includes: Vec::new(),
+        };
+        errors.extend(meta.check_same_as().err());
+        errors.extend(meta.check_versions());
+        errors.extend(meta.check_conflicts_with());
+
+        errors.into_iter().map(invalid).collect()
+    }
+
+    // The metadata section of the manifest, merged with the file it includes, as well as the included files
+    fn read_metadata(
+        path: &Path,
+        manifest_str: String,
+    ) -> Result<Option<(Value, Vec<PathBuf>)>, crate::Error> {
+        let invalid =
+            |e: Error| crate::Error::InvalidMetadata(format!("{}: {}", path.display(), e));
+
+        let mut meta = match Self::parse_manifest(manifest_str).map_err(invalid)? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+
+        let mut includes = Vec::new();
+        if let Some(include) = meta.as_table_mut().and_then(|t| t.remove("include")) {
+            let include = include
+                .as_str()
+                .ok_or_else(|| invalid(anyhow!("{}.include not a string", SECTION)))?;
+            // included files are relative to the manifest
+            let include_path = path.parent().unwrap_or_else(|| Path::new("")).join(include);
+
+            let include_str = Self::read_file(&include_path)?;
+            meta = include_str
+                .parse::<toml::Value>()
+                .map_err(|e| anyhow!("error parsing TOML: {:?}", e))
+                .and_then(|base| Self::merge_tables(base, meta, SECTION))
+                .map_err(|e| {
+                    crate::Error::InvalidMetadata(format!("{}: {}", include_path.display(), e))
+                })?;
+            includes.push(include_path);
+        }
+
+        Ok(Some((meta, includes)))
+    }
+
+    // Check that all the versions can be compared
+    fn check_versions(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for dep in self.deps.iter() {
+            let versions = dep
+                .version
+                .iter()
+                .filter(|v| *v != "crate")
+                .chain(dep.version_overrides.iter().map(|o| &o.version))
+                .chain(dep.one_of.iter().map(|(_, v)| v))
+                .chain(dep.modules.iter().map(|(_, v)| v))
+                .chain(dep.min_os_version.iter())
+                .chain(dep.frameworks.iter().filter_map(|(_, v)| v.as_ref()));
+            for version in versions {
+                if version_compare::Version::from(version).is_none() {
+                    errors.push(anyhow!(
+                        "{}: invalid version {}",
+                        Self::dep_path(dep),
+                        version
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    // The full key of `dep` in the manifest
+    fn dep_path(dep: &Dependency) -> String {
+        match &dep.cfg {
+            Some(cfg) => format!("{}.cfg({}).{}", SECTION, cfg.original(), dep.key),
+            None => format!("{}.{}", SECTION, dep.key),
+        }
+    }
+
+    // Check that `conflicts_with` only references declared dependencies
+    fn check_conflicts_with(&self) -> Vec<Error> {
+        self.deps
+            .iter()
+            .flat_map(|dep| {
+                dep.conflicts_with
+                    .iter()
+                    .filter(|other| !self.deps.iter().any(|d| &&d.key == other))
+                    .map(move |other| {
+                        anyhow!(
+                            "{}: conflicts_with references undeclared dependency {}",
+                            Self::dep_path(dep),
+                            other
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    // Environment variables referenced as `${VAR}` in the paths of the dependencies
+    pub(crate) fn env_refs(&self) -> Vec<String> {
+        self.deps
+            .iter()
+            .flat_map(|dep| {
+                dep.pkg_config_paths
+                    .iter()
+                    .chain(
+                        dep.version_overrides
+                            .iter()
+                            .flat_map(|o| &o.framework_paths),
+                    )
+                    .chain(dep.fallback.iter().flat_map(|f| {
+                        f.link_paths
+                            .iter()
+                            .chain(f.include_paths.iter())
+                            .chain(f.framework_paths.iter())
+                    }))
+            })
+            .flat_map(|path| {
+                let path = path.to_string_lossy();
+                path.split("${")
+                    .skip(1)
+                    .filter_map(|s| s.find('}').map(|end| s[..end].to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    // Name used in the `system_deps_have_$NAME` cfg of the dependency `key`
+    pub(crate) fn cfg_name(&self, key: &str) -> String {
+        self.deps
+            .iter()
+            .filter(|dep| dep.key == key)
+            .find_map(|dep| dep.cfg_name.clone())
+            .unwrap_or_else(|| key.to_snake_case())
+    }
+
+    // Follow the `same_as` chain of `dep` up to the key of the dependency actually probed
+    pub(crate) fn same_as_target<'a>(&'a self, dep: &'a Dependency) -> &'a str {
+        let mut key = &dep.key;
+        while let Some(same_as) = self
+            .deps
+            .iter()
+            .find(|d| &d.key == key)
+            .and_then(|d| d.same_as.as_ref())
+        {
+            key = same_as;
+        }
+        key
+    }
+
+    fn check_same_as(&self) -> Result<(), Error> {
+        for dep in self.deps.iter() {
+            let mut chain = vec![&dep.key];
+            let mut same_as = dep.same_as.as_ref();
+
+            while let Some(key) = same_as {
+                if chain.contains(&key) {
+                    chain.push(key);
+                    bail!("same_as cycle: {}", chain.iter().join(" -> "));
+                }
+                chain.push(key);
+
+                same_as = match self.deps.iter().find(|d| &d.key == key) {
+                    Some(d) => d.same_as.as_ref(),
+                    None => bail!(
+                        "{}: same_as references undeclared dependency {}",
+                        dep.key,
+                        key
+                    ),
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_file(path: &Path) -> Result<String, crate::Error> {
+        let mut file = fs::File::open(path).map_err(|e| {
             crate::Error::FailToRead(format!("error opening {}", path.display()), e)
         })?;
 
-        let mut manifest_str = String::new();
-        manifest.read_to_string(&mut manifest_str).map_err(|e| {
+        let mut s = String::new();
+        file.read_to_string(&mut s).map_err(|e| {
             crate::Error::FailToRead(format!("error reading {}", path.display()), e)
         })?;
 
-        Self::from_str(manifest_str)
-            .map_err(|e| crate::Error::InvalidMetadata(format!("{}: {}", path.display(), e)))
+        Ok(s)
     }
 
-    fn from_str(manifest_str: String) -> Result<Self, Error> {
+    fn parse_manifest(manifest_str: String) -> Result<Option<Value>, Error> {
         let toml = manifest_str
             .parse::<toml::Value>()
             .map_err(|e| anyhow!("error parsing TOML: {:?}", e))?;
 
-        let key = "package.metadata.system-deps";
         let meta = toml
             .get("package")
             .and_then(|v| v.get("metadata"))
-            .and_then(|v| v.get("system-deps"))
-            .ok_or_else(|| anyhow!("no {}", key))?;
+            .and_then(|v| v.get("system-deps"));
+
+        Ok(meta.cloned())
+    }
+
+    // Merge the dependencies defined inline in `Cargo.toml` on top of the ones from an included file
+    fn merge_tables(base: Value, inline: Value, key: &str) -> Result<Value, Error> {
+        let mut base = match base {
+            Value::Table(t) => t,
+            _ => bail!("not a table"),
+        };
+        let inline = match inline {
+            Value::Table(t) => t,
+            _ => bail!("{} not a table", key),
+        };
 
-        let deps = Self::parse_deps_table(meta, key, true)?;
+        // deps only declared in the included file come after the ones of `Cargo.toml`
+        let mut merged = Map::new();
+        for (name, value) in inline {
+            match (base.remove(&name), value) {
+                // merge target specific dependencies with the ones of the same cfg() in the included file
+                (Some(Value::Table(base_cfg)), Value::Table(mut inline_cfg))
+                    if name.starts_with("cfg(") =>
+                {
+                    for (dep, value) in base_cfg {
+                        inline_cfg.entry(dep).or_insert(value);
+                    }
+                    merged.insert(name, Value::Table(inline_cfg));
+                }
+                (_, value) => {
+                    merged.insert(name, value);
+                }
+            }
+        }
+        merged.extend(base);
 
-        Ok(MetaData { deps })
+        Ok(Value::Table(merged))
     }
 
+    // Parse the dependencies declared in `table`, pushing the invalid ones to `errors`
     fn parse_deps_table(
         table: &Value,
         key: &str,
         allow_cfg: bool,
-    ) -> Result<Vec<Dependency>, Error> {
-        let table = table
-            .as_table()
-            .ok_or_else(|| anyhow!("{} not a table", key))?;
+        errors: &mut Vec<Error>,
+    ) -> Vec<Dependency> {
+        let table = match table.as_table() {
+            Some(table) => table,
+            None => {
+                errors.push(anyhow!("{} not a table", key));
+                return Vec::new();
+            }
+        };
 
         let mut deps = Vec::new();
 
         for (name, value) in table {
             if name.starts_with("cfg(") {
                 if allow_cfg {
-                    let cfg_exp = cfg_expr::Expression::parse(name)?;
+                    let cfg_exp = match cfg_expr::Expression::parse(name) {
+                        Ok(cfg_exp) => cfg_exp,
+                        Err(e) => {
+                            errors.push(anyhow!("{}.{}: {}", key, name, e));
+                            continue;
+                        }
+                    };
 
                     for mut dep in
-                        Self::parse_deps_table(value, &format!("{}.{}", key, name), false)?
+                        Self::parse_deps_table(value, &format!("{}.{}", key, name), false, errors)
                     {
                         dep.cfg = Some(cfg_exp.clone());
                         deps.push(dep);
                     }
                 } else {
-                    bail!("{}.{}: cfg() cannot be nested", key, name);
+                    errors.push(anyhow!("{}.{}: cfg() cannot be nested", key, name));
                 }
             } else {
-                let dep =
-                    Self::parse_dep(name, value).map_err(|e| anyhow!("{}.{}: {}", key, name, e))?;
-                deps.push(dep);
+                match Self::parse_dep(name, value) {
+                    Ok(dep) => deps.push(dep),
+                    Err(e) => errors.push(anyhow!("{}.{}: {}", key, name, e)),
+                }
             }
         }
 
-        Ok(deps)
+        deps
     }
 
     fn parse_dep(name: &str, value: &Value) -> Result<Dependency, Error> {
@@ -170,40 +574,232 @@ impl MetaData {
             }
         }
 
+        if !dep.one_of.is_empty() && (dep.version.is_some() || dep.name.is_some()) {
+            bail!("one_of cannot be used with version or name");
+        }
+        if !dep.modules.is_empty()
+            && (dep.version.is_some() || dep.name.is_some() || !dep.one_of.is_empty())
+        {
+            bail!("modules cannot be used with version, name or one_of");
+        }
+        if dep.allow_partial && dep.modules.len() < 2 {
+            bail!("allow_partial requires several modules");
+        }
+        if dep.skip_version_check
+            && (dep.lib_name().is_empty() || !dep.one_of.is_empty() || !dep.modules.is_empty())
+        {
+            bail!("skip_version_check requires the name of the module to probe");
+        }
+
         Ok(dep)
     }
 
     fn parse_dep_table(dep: &mut Dependency, t: &Map<String, Value>) -> Result<(), Error> {
         for (key, value) in t {
             match (key.as_str(), value) {
-                ("feature", &toml::Value::String(ref s)) => {
+                ("feature", toml::Value::String(s)) => {
                     dep.feature = Some(s.clone());
                 }
-                ("version", &toml::Value::String(ref s)) => {
+                ("version", toml::Value::String(s)) => {
                     dep.version = Some(s.clone());
                 }
-                ("name", &toml::Value::String(ref s)) => {
+                ("version", toml::Value::Table(t)) => {
+                    dep.version = Some(Self::parse_exact_version(t)?);
+                    dep.version_op = VersionOp::Exact;
+                }
+                ("name", toml::Value::String(s)) => {
                     dep.name = Some(s.clone());
                 }
                 ("optional", &toml::Value::Boolean(optional)) => {
                     dep.optional = optional;
                 }
-                (version_feature, &toml::Value::Table(ref version_settings))
+                ("always_probe", &toml::Value::Boolean(always_probe)) => {
+                    dep.always_probe = always_probe;
+                }
+                ("frameworks", toml::Value::Array(a)) => {
+                    dep.frameworks = Self::parse_frameworks(a)?;
+                }
+                ("min_os_version", toml::Value::String(s)) => {
+                    dep.min_os_version = Some(s.clone());
+                }
+                ("require_defines", toml::Value::Array(a)) => {
+                    dep.require_defines = Self::parse_string_array(key, a)?
+                        .into_iter()
+                        .map(|define| match define.split_once('=') {
+                            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                            None => (define, None),
+                        })
+                        .collect();
+                    if dep.require_defines.iter().any(|(name, _)| name.is_empty()) {
+                        bail!("require_defines items must be a define name");
+                    }
+                }
+                ("skip_version_check", &toml::Value::Boolean(skip_version_check)) => {
+                    dep.skip_version_check = skip_version_check;
+                }
+                ("defines_only", &toml::Value::Boolean(defines_only)) => {
+                    dep.defines_only = defines_only;
+                }
+                ("emit", &toml::Value::Boolean(emit)) => {
+                    dep.emit = emit;
+                }
+                ("link_group", &toml::Value::Boolean(link_group)) => {
+                    dep.link_group = link_group;
+                }
+                ("require_version_feature", &toml::Value::Boolean(require_version_feature)) => {
+                    dep.require_version_feature = require_version_feature;
+                }
+                ("version_op", toml::Value::String(s)) => {
+                    dep.version_op = VersionOp::from_str(s).map_err(|_| {
+                        anyhow!(
+                            "invalid version_op value: {} (allowed: 'atleast', 'exact', 'max')",
+                            s
+                        )
+                    })?;
+                }
+                ("requires_same_version", toml::Value::String(s)) => {
+                    dep.requires_same_version =
+                        Some(VersionPrecision::from_str(s).map_err(|_| {
+                            anyhow!(
+                                "invalid requires_same_version value: {} (allowed: 'major', 'minor', 'patch')",
+                                s
+                            )
+                        })?);
+                }
+                ("resolve_order", toml::Value::Array(a)) => {
+                    dep.resolve_order = Self::parse_string_array(key, a)?
+                        .iter()
+                        .map(|s| {
+                            ResolveStage::from_str(s).map_err(|_| {
+                                anyhow!(
+                                    "invalid resolve_order value: {} (allowed: 'pkg-config', 'internal')",
+                                    s
+                                )
+                            })
+                        })
+                        .collect::<Result<_, _>>()?;
+                    if dep.resolve_order.is_empty() {
+                        bail!("resolve_order cannot be empty");
+                    }
+                }
+                ("any_version", &toml::Value::Boolean(any_version)) => {
+                    dep.any_version = any_version;
+                }
+                ("link_args", toml::Value::Array(a)) => {
+                    dep.link_args = Self::parse_string_array(key, a)?;
+                }
+                ("link_args_bins", toml::Value::Array(a)) => {
+                    dep.link_args_bins = Self::parse_string_array(key, a)?;
+                }
+                ("link_args_tests", toml::Value::Array(a)) => {
+                    dep.link_args_tests = Self::parse_string_array(key, a)?;
+                }
+                ("link_args_benches", toml::Value::Array(a)) => {
+                    dep.link_args_benches = Self::parse_string_array(key, a)?;
+                }
+                ("link_arg_files", toml::Value::Array(a)) => {
+                    dep.link_arg_files = Self::parse_string_array(key, a)?
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect();
+                }
+                ("libs", toml::Value::Array(a)) => {
+                    dep.libs = Self::parse_libs(a)?;
+                }
+                ("replace_libs", &toml::Value::Boolean(replace_libs)) => {
+                    dep.replace_libs = replace_libs;
+                }
+                ("static_libs", toml::Value::Array(a)) => {
+                    dep.static_libs = Self::parse_string_array(key, a)?;
+                }
+                ("whole_archive_libs", toml::Value::Array(a)) => {
+                    dep.whole_archive_libs = Self::parse_string_array(key, a)?;
+                }
+                ("verbatim_libs", toml::Value::Array(a)) => {
+                    dep.verbatim_libs = Self::parse_string_array(key, a)?;
+                }
+                ("exclude_libs", toml::Value::Array(a)) => {
+                    dep.exclude_libs = Self::parse_string_array(key, a)?;
+                }
+                ("pkg_config_paths", toml::Value::Array(a)) => {
+                    dep.pkg_config_paths = Self::parse_string_array(key, a)?
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect();
+                }
+                ("cfg_name", toml::Value::String(s)) => {
+                    let mut chars = s.chars();
+                    let valid = chars
+                        .next()
+                        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    if !valid {
+                        bail!("cfg_name {} is not a valid identifier", s);
+                    }
+                    dep.cfg_name = Some(s.clone());
+                }
+                ("description", toml::Value::String(s)) => {
+                    dep.description = Some(s.clone());
+                }
+                ("same_as", toml::Value::String(s)) => {
+                    dep.same_as = Some(s.clone());
+                }
+                ("export_variables", toml::Value::Array(a)) => {
+                    dep.export_variables = Self::parse_string_array(key, a)?;
+                }
+                ("conflicts_with", toml::Value::Array(a)) => {
+                    dep.conflicts_with = Self::parse_string_array(key, a)?;
+                }
+                ("fallback", toml::Value::Table(t)) => {
+                    dep.fallback = Some(Self::parse_fallback(t)?);
+                }
+                ("one_of", toml::Value::Table(members)) => {
+                    dep.one_of = Self::parse_module_versions(key, members)?;
+                }
+                ("modules", toml::Value::Table(modules)) => {
+                    dep.modules = Self::parse_module_versions(key, modules)?;
+                }
+                ("allow_partial", &toml::Value::Boolean(allow_partial)) => {
+                    dep.allow_partial = allow_partial;
+                }
+                (version_feature, toml::Value::Table(version_settings))
                     if version_feature.starts_with('v') =>
                 {
                     let mut builder = VersionOverrideBuilder::new(version_feature);
 
                     for (k, v) in version_settings {
                         match (k.as_str(), v) {
-                            ("version", &toml::Value::String(ref feat_vers)) => {
+                            ("version", toml::Value::String(feat_vers)) => {
                                 builder.version = Some(feat_vers.into());
                             }
-                            ("name", &toml::Value::String(ref feat_name)) => {
+                            ("version", toml::Value::Table(t)) => {
+                                builder.version = Some(Self::parse_exact_version(t)?);
+                                builder.version_op = Some(VersionOp::Exact);
+                            }
+                            ("name", toml::Value::String(feat_name)) => {
                                 builder.full_name = Some(feat_name.into());
                             }
                             ("optional", &toml::Value::Boolean(optional)) => {
                                 builder.optional = Some(optional);
                             }
+                            ("build_internal", toml::Value::String(b)) => {
+                                builder.build_internal =
+                                    Some(BuildInternal::from_str(b).map_err(|_| {
+                                        anyhow!(
+                                            "invalid build_internal value: {} (allowed: 'auto', 'always', 'never')",
+                                            b
+                                        )
+                                    })?);
+                            }
+                            ("frameworks", toml::Value::Array(a)) => {
+                                builder.frameworks = Self::parse_string_array(k, a)?;
+                            }
+                            ("framework_paths", toml::Value::Array(a)) => {
+                                builder.framework_paths = Self::parse_string_array(k, a)?
+                                    .into_iter()
+                                    .map(PathBuf::from)
+                                    .collect();
+                            }
                             _ => {
                                 bail!(
                                     "unexpected version settings key: {} type: {}",
@@ -223,6 +819,149 @@ impl MetaData {
         }
         Ok(())
     }
+
+    // Libraries declared as `"name"` or `{ name = "name", kind = "static" }`
+    fn parse_libs(a: &[Value]) -> Result<Vec<(String, Option<LinkKind>)>, Error> {
+        a.iter()
+            .map(|v| match v {
+                toml::Value::String(name) => Ok((name.clone(), None)),
+                toml::Value::Table(t) => {
+                    let mut name = None;
+                    let mut kind = None;
+                    for (k, v) in t {
+                        match (k.as_str(), v) {
+                            ("name", toml::Value::String(s)) => name = Some(s.clone()),
+                            ("kind", toml::Value::String(s)) => {
+                                kind = Some(LinkKind::from_str(s).map_err(|_| {
+                                    anyhow!("invalid libs kind: {} (allowed: 'static', 'dylib')", s)
+                                })?);
+                            }
+                            _ => bail!("unexpected libs key {} type {}", k, v.type_str()),
+                        }
+                    }
+                    let name = name.ok_or_else(|| anyhow!("libs items must have a name"))?;
+                    Ok((name, kind))
+                }
+                _ => Err(anyhow!(
+                    "libs items must be strings or tables, got {}",
+                    v.type_str()
+                )),
+            })
+            .collect()
+    }
+
+    // Version declared as `{ exact = "1.0" }`
+    fn parse_exact_version(t: &Map<String, Value>) -> Result<String, Error> {
+        match (t.get("exact"), t.len()) {
+            (Some(toml::Value::String(version)), 1) => Ok(version.clone()),
+            (Some(version), 1) => Err(anyhow!(
+                "version.exact must be a string, got {}",
+                version.type_str()
+            )),
+            _ => Err(anyhow!(
+                "unexpected version keys: {} (allowed: 'exact')",
+                t.keys().join(", ")
+            )),
+        }
+    }
+
+    // Frameworks declared as `"name"` or `{ name = "name", weak_below = "version" }`
+    fn parse_frameworks(a: &[Value]) -> Result<Vec<(String, Option<String>)>, Error> {
+        a.iter()
+            .map(|v| match v {
+                toml::Value::String(name) => Ok((name.clone(), None)),
+                toml::Value::Table(t) => {
+                    let mut name = None;
+                    let mut weak_below = None;
+                    for (k, v) in t {
+                        match (k.as_str(), v) {
+                            ("name", toml::Value::String(s)) => name = Some(s.clone()),
+                            ("weak_below", toml::Value::String(s)) => weak_below = Some(s.clone()),
+                            _ => bail!("unexpected frameworks key {} type {}", k, v.type_str()),
+                        }
+                    }
+                    let name = name.ok_or_else(|| anyhow!("frameworks items must have a name"))?;
+                    Ok((name, weak_below))
+                }
+                _ => Err(anyhow!(
+                    "frameworks items must be strings or tables, got {}",
+                    v.type_str()
+                )),
+            })
+            .collect()
+    }
+
+    fn parse_fallback(t: &Map<String, Value>) -> Result<Fallback, Error> {
+        let mut fallback = Fallback::default();
+        let paths = |k: &str, a: &[Value]| -> Result<Vec<PathBuf>, Error> {
+            Ok(Self::parse_string_array(&format!("fallback.{}", k), a)?
+                .into_iter()
+                .map(PathBuf::from)
+                .collect())
+        };
+
+        for (k, v) in t {
+            match (k.as_str(), v) {
+                ("cfg", toml::Value::String(s)) if s.starts_with("cfg(") => {
+                    fallback.cfg = Some(
+                        cfg_expr::Expression::parse(s)
+                            .map_err(|e| anyhow!("fallback.cfg: {}", e))?,
+                    );
+                }
+                ("libs", toml::Value::Array(a)) => {
+                    fallback.libs = Self::parse_string_array("fallback.libs", a)?;
+                }
+                ("link_paths", toml::Value::Array(a)) => {
+                    fallback.link_paths = paths(k, a)?;
+                }
+                ("include_paths", toml::Value::Array(a)) => {
+                    fallback.include_paths = paths(k, a)?;
+                }
+                ("frameworks", toml::Value::Array(a)) => {
+                    fallback.frameworks = Self::parse_string_array("fallback.frameworks", a)?;
+                }
+                ("framework_paths", toml::Value::Array(a)) => {
+                    fallback.framework_paths = paths(k, a)?;
+                }
+                _ => {
+                    bail!("unexpected fallback key {} type {}", k, v.type_str());
+                }
+            }
+        }
+
+        Ok(fallback)
+    }
+
+    fn parse_string_array(key: &str, a: &[Value]) -> Result<Vec<String>, Error> {
+        a.iter()
+            .map(|v| match v {
+                toml::Value::String(s) => Ok(s.clone()),
+                _ => Err(anyhow!(
+                    "{} items must be strings, got {}",
+                    key,
+                    v.type_str()
+                )),
+            })
+            .collect()
+    }
+
+    // (name, version) of the modules of a `one_of` or `modules` table
+    fn parse_module_versions(
+        key: &str,
+        t: &Map<String, Value>,
+    ) -> Result<Vec<(String, String)>, Error> {
+        t.iter()
+            .map(|(name, version)| match version {
+                toml::Value::String(v) => Ok((name.clone(), v.clone())),
+                _ => Err(anyhow!(
+                    "{}.{}: version must be a string, got {}",
+                    key,
+                    name,
+                    version.type_str()
+                )),
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -240,7 +979,7 @@ mod tests {
         p.push("Cargo.toml");
         assert!(p.exists());
 
-        MetaData::from_file(&p)
+        MetaData::from_file(&p, false)
     }
 
     #[test]
@@ -268,7 +1007,8 @@ mod tests {
                         feature: Some("another-test-feature".into()),
                         ..Default::default()
                     }
-                ]
+                ],
+                includes: vec![],
             }
         )
     }
@@ -297,9 +1037,14 @@ mod tests {
                         version: "1.2".into(),
                         name: None,
                         optional: None,
+                        build_internal: None,
+                        frameworks: vec![],
+                        framework_paths: vec![],
+                        version_op: None,
                     }],
                     ..Default::default()
-                },]
+                },],
+                includes: vec![],
             }
         )
     }
@@ -320,16 +1065,25 @@ mod tests {
                             version: "5".into(),
                             name: None,
                             optional: None,
+                            build_internal: None,
+                            frameworks: vec![],
+                            framework_paths: vec![],
+                            version_op: None,
                         },
                         VersionOverride {
                             key: "v6".into(),
                             version: "6".into(),
                             name: None,
                             optional: None,
+                            build_internal: None,
+                            frameworks: vec![],
+                            framework_paths: vec![],
+                            version_op: None,
                         },
                     ],
                     ..Default::default()
-                },]
+                },],
+                includes: vec![],
             }
         )
     }
@@ -342,12 +1096,6 @@ mod tests {
             m,
             MetaData {
                 deps: vec![
-                    Dependency {
-                        key: "testbadger".into(),
-                        version: Some("1".into()),
-                        optional: true,
-                        ..Default::default()
-                    },
                     Dependency {
                         key: "testlib".into(),
                         version: Some("1.0".into()),
@@ -357,6 +1105,10 @@ mod tests {
                             version: "5.0".into(),
                             name: Some("testlib-5.0".into()),
                             optional: Some(false),
+                            build_internal: None,
+                            frameworks: vec![],
+                            framework_paths: vec![],
+                            version_op: None,
                         },],
                         ..Default::default()
                     },
@@ -368,10 +1120,21 @@ mod tests {
                             version: "3.0".into(),
                             name: None,
                             optional: Some(true),
+                            build_internal: None,
+                            frameworks: vec![],
+                            framework_paths: vec![],
+                            version_op: None,
                         },],
                         ..Default::default()
                     },
-                ]
+                    Dependency {
+                        key: "testbadger".into(),
+                        version: Some("1".into()),
+                        optional: true,
+                        ..Default::default()
+                    },
+                ],
+                includes: vec![],
             }
         )
     }
@@ -385,15 +1148,15 @@ mod tests {
             MetaData {
                 deps: vec![
                     Dependency {
-                        key: "testlib".into(),
+                        key: "testdata".into(),
                         version: Some("1".into()),
-                        cfg: Some(Expression::parse("not(target_os = \"macos\")").unwrap()),
+                        cfg: Some(Expression::parse("target_os = \"linux\"").unwrap()),
                         ..Default::default()
                     },
                     Dependency {
-                        key: "testdata".into(),
+                        key: "testlib".into(),
                         version: Some("1".into()),
-                        cfg: Some(Expression::parse("target_os = \"linux\"").unwrap()),
+                        cfg: Some(Expression::parse("not(target_os = \"macos\")").unwrap()),
                         ..Default::default()
                     },
                     Dependency {
@@ -403,7 +1166,65 @@ mod tests {
                         optional: true,
                         ..Default::default()
                     },
-                ]
+                ],
+                includes: vec![],
+            }
+        )
+    }
+
+    #[test]
+    fn parse_link_args() {
+        let m = parse_file("toml-link-args").unwrap();
+
+        assert_eq!(
+            m,
+            MetaData {
+                deps: vec![
+                    Dependency {
+                        key: "testlib".into(),
+                        version: Some("1".into()),
+                        link_args: vec!["-Wl,--as-needed".into()],
+                        link_args_bins: vec!["-Wl,-rpath,/opt/testlib/lib".into()],
+                        link_args_tests: vec!["-Wl,-z,now".into()],
+                        link_args_benches: vec!["-Wl,-O1".into()],
+                        ..Default::default()
+                    },
+                    Dependency {
+                        key: "testdata".into(),
+                        version: Some("4".into()),
+                        link_args: vec!["-Wl,--as-needed".into()],
+                        link_args_bins: vec!["-Wl,-rpath,/opt/testdata/lib".into()],
+                        ..Default::default()
+                    },
+                ],
+                includes: vec![],
+            }
+        )
+    }
+
+    #[test]
+    fn parse_include() {
+        let m = parse_file("toml-include").unwrap();
+
+        assert_eq!(
+            m,
+            MetaData {
+                deps: vec![
+                    // the inline version overrides the included one
+                    Dependency {
+                        key: "testlib".into(),
+                        version: Some("1".into()),
+                        ..Default::default()
+                    },
+                    Dependency {
+                        key: "testdata".into(),
+                        version: Some("4".into()),
+                        ..Default::default()
+                    },
+                ],
+                includes: vec![["src", "tests", "toml-include", "system-deps.toml"]
+                    .iter()
+                    .collect()],
             }
         )
     }
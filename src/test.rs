@@ -1,16 +1,21 @@
 use itertools::Itertools;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::env;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
 use assert_matches::assert_matches;
 
 use crate::Dependencies;
 
-use super::{BuildFlags, BuildInternalClosureError, Config, EnvVariables, Error, Library};
+use super::{
+    BuildFlags, BuildInternalClosureError, BuildInternalContext, CfgCache, Config, DepSummary,
+    EnvVariables, Error, IncludeArgStyle, Library, Source,
+};
 
 lazy_static! {
     static ref LOCK: Mutex<()> = Mutex::new(());
@@ -22,7 +27,7 @@ fn create_config(path: &str, env: Vec<(&'static str, &'static str)>) -> Config {
         let _l = LOCK.lock();
         env::set_var(
             "PKG_CONFIG_PATH",
-            &env::current_dir().unwrap().join("src").join("tests"),
+            env::current_dir().unwrap().join("src").join("tests"),
         );
     }
 
@@ -50,15 +55,24 @@ fn toml(
     path: &str,
     env: Vec<(&'static str, &'static str)>,
 ) -> Result<(Dependencies, BuildFlags), Error> {
-    let libs = create_config(path, env).probe_full()?;
-    let flags = libs.gen_flags()?;
+    let mut config = create_config(path, env);
+    let libs = config.probe_full()?;
+    let flags = libs.gen_flags(&config)?;
     Ok((libs, flags))
 }
 
+// Probe like `Config::probe` does, including the flags added on top of `Dependencies::gen_flags`
+fn toml_flags(
+    path: &str,
+    env: Vec<(&'static str, &'static str)>,
+) -> Result<(Dependencies, BuildFlags), Error> {
+    create_config(path, env).emit_have_cfgs(false).probe_flags()
+}
+
 fn assert_flags(flags: BuildFlags, expected: &str) {
     // flags ordering isn't guaranteed so sort them out before comparing
     let flags = flags.to_string().split('\n').sorted().join("\n");
-    let expected = expected.to_string().split('\n').sorted().join("\n");
+    let expected = expected.split('\n').sorted().join("\n");
     assert_eq!(flags, expected);
 }
 
@@ -88,19 +102,36 @@ cargo:rustc-link-lib=framework=someframework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 "#,
     );
 }
@@ -129,13 +160,12 @@ fn toml_pkg_config_err_version(
 ) {
     let err = toml(path, env_vars).unwrap_err();
     match err {
-        Error::PkgConfig(e) => match e {
-            pkg_config::Error::Failure {
-                command: cmd,
-                output: _,
-            } => {
-                let s = format!(">= {}\"", expected_version);
-                assert!(cmd.ends_with(&s));
+        Error::PkgConfig(e) => match *e {
+            pkg_config::Error::Failure { command: cmd, .. }
+            | pkg_config::Error::ProbeFailure { command: cmd, .. } => {
+                // pkg-config quotes the version requirement differently depending on its version
+                let s = format!(">= {}", expected_version);
+                assert!(cmd.trim_end_matches(&['"', '\''][..]).ends_with(&s));
             }
             _ => panic!("Wrong pkg-config error type"),
         },
@@ -143,14 +173,138 @@ fn toml_pkg_config_err_version(
     }
 }
 
+#[test]
+fn set_version() {
+    // raising the minimum version rejects the too old system lib
+    let err = create_config("toml-good", vec![])
+        .set_version("testlib", "1.3")
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(_));
+    assert!(err.to_string().contains(">= 1.3"));
+
+    let libraries = create_config("toml-good", vec![])
+        .set_version("testlib", "1.2")
+        .probe_full()
+        .unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+
+    // takes precedence over the feature versions
+    let libraries = create_config("toml-feature-versions", vec![("CARGO_FEATURE_V5", "")])
+        .set_version("testdata", "4.5")
+        .probe_full()
+        .unwrap();
+    assert_eq!(libraries.get_by_name("testdata").unwrap().version, "4.5.6");
+}
+
+#[test]
+fn version_op() {
+    let (libraries, _) = toml("toml-version-op", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.3");
+    assert!(testlib
+        .probe_command()
+        .unwrap()
+        .contains("--exact-version=1.2.3"));
+    let testdata = libraries.get_by_name("testdata").unwrap();
+    assert_eq!(testdata.version, "4.5.6");
+    assert!(testdata
+        .probe_command()
+        .unwrap()
+        .contains("--max-version=5"));
+
+    // a newer version is rejected
+    let err = create_config("toml-version-op", vec![])
+        .set_version("testlib", "1.2")
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(_));
+    let err = create_config("toml-version-op", vec![])
+        .set_version("testdata", "4")
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(_));
+    assert!(err.to_string().contains("<= 4"));
+
+    toml_err_invalid(
+        "toml-version-op-invalid",
+        "invalid version_op value: newer (allowed: 'atleast', 'exact', 'max')",
+    );
+}
+
+#[test]
+fn version_exact() {
+    let (libraries, _) = toml("toml-version-exact", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.3");
+    assert!(testlib
+        .probe_command()
+        .unwrap()
+        .contains("--exact-version=1.2.3"));
+    let testdata = libraries.get_by_name("testdata").unwrap();
+    assert!(testdata
+        .probe_command()
+        .unwrap()
+        .contains("--atleast-version=4"));
+
+    // the feature version declares its own exact version
+    let (libraries, _) = toml("toml-version-exact", vec![("CARGO_FEATURE_V4_5", "")]).unwrap();
+    let testdata = libraries.get_by_name("testdata").unwrap();
+    assert_eq!(testdata.version, "4.5.6");
+    assert!(testdata
+        .probe_command()
+        .unwrap()
+        .contains("--exact-version=4.5.6"));
+
+    let err = toml("toml-version-exact-mismatch", vec![]).unwrap_err();
+    assert_matches!(err, Error::PkgConfig(_));
+    assert!(err.to_string().contains("1.2.3"));
+
+    toml_err_invalid(
+        "toml-version-exact-invalid",
+        "unexpected version keys: min (allowed: 'exact')",
+    );
+}
+
+#[test]
+fn export_variables() {
+    let (libraries, flags) = toml_flags("toml-export-variables", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(
+        testlib.exported_variables(),
+        &[
+            ("includedir".to_string(), "/usr/include/testlib".to_string()),
+            ("libdir".to_string(), "/usr/lib/".to_string())
+        ]
+    );
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:testlib_includedir=/usr/include/testlib\n"));
+    assert!(flags.contains("cargo:testlib_libdir=/usr/lib/\n"));
+    // absent variables only trigger a warning
+    assert!(flags.contains(
+        "cargo:warning=testlib: variable datadir is not defined by testlib, it is not exported\n"
+    ));
+}
+
 #[test]
 fn missing_file() {
-    assert_matches!(toml_err("toml-missing-file"), Error::FailToRead(_, _));
+    // no dependency declared
+    let (libraries, _) = toml("toml-missing-file", vec![]).unwrap();
+    assert_eq!(libraries.iter().count(), 0);
 }
 
 #[test]
 fn missing_key() {
-    toml_err_invalid("toml-missing-key", "no package.metadata.system-deps");
+    let (libraries, _) = toml("toml-missing-key", vec![]).unwrap();
+    assert_eq!(libraries.iter().count(), 0);
+}
+
+#[test]
+fn invalid_toml() {
+    // a manifest which cannot be parsed is still an error
+    let err = toml_err("toml-invalid");
+    assert_matches!(err, Error::InvalidMetadata(_));
+    assert!(err.to_string().contains("error parsing TOML"));
 }
 
 #[test]
@@ -243,6 +397,47 @@ fn feature_versions() {
     assert_eq!(testlib.name, "testlib-3.0");
 }
 
+#[test]
+fn require_version_feature() {
+    let err = toml_err("toml-require-version-feature");
+    assert_matches!(err, Error::MissingVersionFeature(..));
+    assert_eq!(
+        err.to_string(),
+        "testdata requires one of the features v5, v6 to be enabled"
+    );
+
+    // a feature version is enabled, version 5 is not available
+    toml_pkg_config_err_version(
+        "toml-require-version-feature",
+        "5",
+        vec![("CARGO_FEATURE_V5", "")],
+    );
+}
+
+#[test]
+fn strict_feature_versions() {
+    // not checked by default
+    toml("toml-feature-versions-decreasing", vec![]).unwrap();
+
+    let err = create_config("toml-feature-versions-decreasing", vec![])
+        .strict_feature_versions(true)
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::InvalidMetadata(_));
+    assert_eq!(
+        err.to_string(),
+        "testlib: v1_4 requires version 1.1 which is older than the 1.2 required by v1_2"
+    );
+
+    let (libraries, _) = Config {
+        strict_feature_versions: true,
+        ..create_config("toml-feature-versions", vec![])
+    }
+    .probe_flags()
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+}
+
 #[test]
 fn override_search_native() {
     #[cfg(target_os = "windows")]
@@ -271,19 +466,36 @@ cargo:rustc-link-lib=framework=someframework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 "#,
     );
 }
@@ -307,19 +519,36 @@ cargo:rustc-link-lib=framework=someframework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 "#,
     );
 }
@@ -344,19 +573,36 @@ cargo:rustc-link-lib=framework=someframework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 "#,
     );
 }
@@ -380,23 +626,127 @@ cargo:rustc-link-lib=framework=overrided-framework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 "#,
     );
 }
 
+#[test]
+fn applied_env_vars() {
+    let (libraries, _) = toml(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_LIB", "overrided-test"),
+            ("SYSTEM_DEPS_TESTLIB_INCLUDE", "/other/include"),
+            // set for another dependency
+            ("SYSTEM_DEPS_TESTDATA_NO_EMIT", "1"),
+        ],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(
+        testlib.applied_env_vars(),
+        &["SYSTEM_DEPS_TESTLIB_LIB", "SYSTEM_DEPS_TESTLIB_INCLUDE"]
+    );
+    let testdata = libraries.get_by_name("testdata").unwrap();
+    assert_eq!(
+        testdata.applied_env_vars(),
+        &["SYSTEM_DEPS_TESTDATA_NO_EMIT"]
+    );
+
+    // env variables selecting how the library is resolved
+    let (libraries, _) = toml(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG", "1"),
+            ("SYSTEM_DEPS_TESTLIB_LIB", "custom-lib"),
+            ("SYSTEM_DEPS_BUILD_INTERNAL", "never"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().applied_env_vars(),
+        &[
+            "SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG",
+            "SYSTEM_DEPS_TESTLIB_LIB"
+        ]
+    );
+    assert_eq!(
+        libraries
+            .get_by_name("testdata")
+            .unwrap()
+            .applied_env_vars(),
+        &["SYSTEM_DEPS_BUILD_INTERNAL"]
+    );
+
+    // nothing applied by default
+    let (libraries, _) = toml("toml-good", vec![]).unwrap();
+    assert!(libraries
+        .iter()
+        .all(|(_, lib)| lib.applied_env_vars().is_empty()));
+}
+
+#[test]
+fn override_framework_append() {
+    let (libraries, flags) = toml(
+        "toml-good",
+        vec![("SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND", "extra-framework")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.frameworks, vec!["someframework", "extra-framework"]);
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=framework=someframework\n"));
+    assert!(flags.contains("cargo:rustc-link-lib=framework=extra-framework\n"));
+
+    // applied after the frameworks override, without duplicates
+    let (libraries, _) = toml(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK", "overrided-framework"),
+            (
+                "SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND",
+                "overrided-framework extra-framework",
+            ),
+        ],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(
+        testlib.frameworks,
+        vec!["overrided-framework", "extra-framework"]
+    );
+}
+
 #[test]
 fn override_include() {
     let (libraries, flags) = toml(
@@ -416,19 +766,36 @@ cargo:rustc-link-lib=framework=someframework
 cargo:include=/other/include
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 "#,
     );
 }
@@ -457,19 +824,36 @@ fn override_unset() {
         flags,
         r"cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 ",
     );
 }
@@ -496,19 +880,36 @@ fn override_no_pkg_config() {
         r"cargo:rustc-link-lib=custom-lib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
 ",
     );
 }
@@ -526,6 +927,26 @@ fn override_no_pkg_config_error() {
     );
 }
 
+#[test]
+fn override_no_pkg_config_defines_only() {
+    // no lib is required for deps only providing defines
+    let (libraries, flags) = toml(
+        "toml-defines-only",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG", "1"),
+            ("SYSTEM_DEPS_TESTLIB_INCLUDE", "/usr/include/testlib"),
+        ],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert!(testlib.libs.is_empty());
+    assert_eq!(
+        testlib.include_paths,
+        vec![Path::new("/usr/include/testlib")]
+    );
+    assert!(!flags.to_string().contains("rustc-link-lib"));
+}
+
 fn test_build_internal(
     path: &'static str,
     env: Vec<(&'static str, &'static str)>,
@@ -533,17 +954,18 @@ fn test_build_internal(
 ) -> Result<(Dependencies, bool), (Error, bool)> {
     let called = Rc::new(Cell::new(false));
     let called_clone = called.clone();
-    let config = create_config(path, env).add_build_internal(expected_lib, move |lib, version| {
-        called_clone.replace(true);
-        assert_eq!(lib, expected_lib);
-        let mut pkg_lib = pkg_config::Config::new()
-            .print_system_libs(false)
-            .cargo_metadata(false)
-            .probe(lib)
-            .unwrap();
-        pkg_lib.version = version.to_string();
-        Ok(Library::from_pkg_config(&lib, pkg_lib))
-    });
+    let mut config =
+        create_config(path, env).add_build_internal(expected_lib, move |lib, version| {
+            called_clone.replace(true);
+            assert_eq!(lib, expected_lib);
+            let mut pkg_lib = pkg_config::Config::new()
+                .print_system_libs(false)
+                .cargo_metadata(false)
+                .probe(lib)
+                .unwrap();
+            pkg_lib.version = version.to_string();
+            Ok(Library::from_pkg_config(lib, pkg_lib))
+        });
 
     match config.probe_full() {
         Ok(libraries) => Ok((libraries, called.get())),
@@ -560,7 +982,7 @@ fn build_internal_always() {
     )
     .unwrap();
 
-    assert_eq!(called, true);
+    assert!(called);
     assert!(libraries.get_by_name("testlib").is_some());
 }
 
@@ -574,7 +996,7 @@ fn build_internal_auto_not_called() {
     )
     .unwrap();
 
-    assert_eq!(called, false);
+    assert!(!called);
     assert!(libraries.get_by_name("testlib").is_some());
 }
 
@@ -591,7 +1013,7 @@ fn build_internal_auto_called() {
     )
     .unwrap();
 
-    assert_eq!(called, true);
+    assert!(called);
     assert!(libraries.get_by_name("testdata").is_some());
 }
 
@@ -609,12 +1031,26 @@ fn build_internal_auto_never() {
     .unwrap_err();
 
     assert!(matches!(err, Error::PkgConfig(..)));
-    assert_eq!(called, false);
+    assert!(!called);
+}
+
+#[test]
+fn build_internal_always_no_version() {
+    // no version is required when always building internally
+    let (libraries, called) = test_build_internal(
+        "toml-version-missing",
+        vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
+        "testlib",
+    )
+    .unwrap();
+
+    assert!(called);
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "unknown");
 }
 
 #[test]
 fn build_internal_always_no_closure() {
-    let config = create_config(
+    let mut config = create_config(
         "toml-good",
         vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
     );
@@ -623,9 +1059,25 @@ fn build_internal_always_no_closure() {
     assert!(matches!(err, Error::BuildInternalNoClosure(..)));
 }
 
+#[test]
+fn build_internal_removed() {
+    let err = create_config(
+        "toml-good",
+        vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
+    )
+    .add_build_internal("testlib", |_lib, _version| {
+        Err(BuildInternalClosureError::failed("should not be called"))
+    })
+    .remove_build_internal("testlib")
+    .probe_full()
+    .unwrap_err();
+
+    assert!(matches!(err, Error::BuildInternalNoClosure(..)));
+}
+
 #[test]
 fn build_internal_invalid() {
-    let config = create_config(
+    let mut config = create_config(
         "toml-good",
         vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "badger")],
     );
@@ -639,7 +1091,7 @@ fn build_internal_wrong_version() {
     // Require version 5
     let called = Rc::new(Cell::new(false));
     let called_clone = called.clone();
-    let config = create_config(
+    let mut config = create_config(
         "toml-feature-versions",
         vec![
             ("SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL", "auto"),
@@ -654,19 +1106,19 @@ fn build_internal_wrong_version() {
             .cargo_metadata(false)
             .probe(lib)
             .unwrap();
-        Ok(Library::from_pkg_config(&lib, pkg_lib))
+        Ok(Library::from_pkg_config(lib, pkg_lib))
     });
 
     let err = config.probe_full().unwrap_err();
     assert!(matches!(err, Error::BuildInternalWrongVersion(..)));
-    assert_eq!(called.get(), true);
+    assert!(called.get());
 }
 
 #[test]
 fn build_internal_fail() {
     let called = Rc::new(Cell::new(false));
     let called_clone = called.clone();
-    let config = create_config(
+    let mut config = create_config(
         "toml-good",
         vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
     )
@@ -678,7 +1130,46 @@ fn build_internal_fail() {
 
     let err = config.probe_full().unwrap_err();
     assert!(matches!(err, Error::BuildInternalClosureError(..)));
-    assert_eq!(called.get(), true);
+    assert!(called.get());
+}
+
+#[test]
+fn build_internal_timeout() {
+    let build = |delay| {
+        create_config(
+            "toml-good",
+            vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
+        )
+        .build_internal_timeout(Duration::from_millis(500))
+        .add_build_internal_send("testlib", move |ctx| {
+            thread::sleep(delay);
+            let lib = pkg_config::Config::new()
+                .print_system_libs(false)
+                .cargo_metadata(false)
+                .probe(&ctx.name)?;
+            Ok(Library::from_pkg_config(&ctx.name, lib))
+        })
+        .probe_full()
+    };
+
+    let libraries = build(Duration::ZERO).unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    let err = build(Duration::from_secs(5)).unwrap_err();
+    assert_matches!(err, Error::BuildInternalTimeout(ref name) if name == "testlib");
+}
+
+#[test]
+#[should_panic(expected = "closure panicked")]
+fn build_internal_timeout_panic() {
+    // the panic is propagated right away rather than reported as a timeout
+    let _ = create_config(
+        "toml-good",
+        vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
+    )
+    .build_internal_timeout(Duration::from_secs(60))
+    .add_build_internal_send("testlib", |_ctx| panic!("closure panicked"))
+    .probe_full();
 }
 
 #[test]
@@ -686,7 +1177,7 @@ fn build_internal_always_gobal() {
     let called = Rc::new(Cell::new((false, false)));
     let called_clone = called.clone();
     let called_clone2 = called.clone();
-    let config = create_config("toml-good", vec![("SYSTEM_DEPS_BUILD_INTERNAL", "always")])
+    let mut config = create_config("toml-good", vec![("SYSTEM_DEPS_BUILD_INTERNAL", "always")])
         .add_build_internal("testlib", move |lib, version| {
             let (_, b) = called_clone.get();
             called_clone.replace((true, b));
@@ -696,7 +1187,7 @@ fn build_internal_always_gobal() {
                 .probe(lib)
                 .unwrap();
             pkg_lib.version = version.to_string();
-            Ok(Library::from_pkg_config(&lib, pkg_lib))
+            Ok(Library::from_pkg_config(lib, pkg_lib))
         })
         .add_build_internal("testdata", move |lib, version| {
             let (a, _) = called_clone2.get();
@@ -707,7 +1198,7 @@ fn build_internal_always_gobal() {
                 .probe(lib)
                 .unwrap();
             pkg_lib.version = version.to_string();
-            Ok(Library::from_pkg_config(&lib, pkg_lib))
+            Ok(Library::from_pkg_config(lib, pkg_lib))
         });
 
     let libraries = config.probe_full().unwrap();
@@ -722,7 +1213,7 @@ fn build_internal_gobal_override() {
     let called = Rc::new(Cell::new((false, false)));
     let called_clone = called.clone();
     let called_clone2 = called.clone();
-    let config = create_config(
+    let mut config = create_config(
         "toml-good",
         vec![
             ("SYSTEM_DEPS_BUILD_INTERNAL", "always"),
@@ -738,7 +1229,7 @@ fn build_internal_gobal_override() {
             .probe(lib)
             .unwrap();
         pkg_lib.version = version.to_string();
-        Ok(Library::from_pkg_config(&lib, pkg_lib))
+        Ok(Library::from_pkg_config(lib, pkg_lib))
     })
     .add_build_internal("testdata", move |lib, version| {
         let (a, _) = called_clone2.get();
@@ -749,7 +1240,7 @@ fn build_internal_gobal_override() {
             .probe(lib)
             .unwrap();
         pkg_lib.version = version.to_string();
-        Ok(Library::from_pkg_config(&lib, pkg_lib))
+        Ok(Library::from_pkg_config(lib, pkg_lib))
     });
 
     let libraries = config.probe_full().unwrap();
@@ -758,6 +1249,113 @@ fn build_internal_gobal_override() {
     assert!(libraries.get_by_name("testdata").is_some());
 }
 
+#[test]
+fn resolve_order() {
+    let probe = |env: Vec<(&'static str, &'static str)>, version: &'static str| {
+        let called = Rc::new(Cell::new(false));
+        let called_clone = called.clone();
+        let res = create_config("toml-resolve-order", env)
+            .add_build_internal("testlib", move |lib, _version| {
+                called_clone.replace(true);
+                let mut pkg_lib = pkg_config::Config::new()
+                    .print_system_libs(false)
+                    .cargo_metadata(false)
+                    .probe(lib)
+                    .unwrap();
+                pkg_lib.version = version.to_string();
+                Ok(Library::from_pkg_config(lib, pkg_lib))
+            })
+            .probe_full();
+        (res, called.get())
+    };
+
+    // built internally first
+    let (libraries, called) = probe(vec![], "1.0");
+    let libraries = libraries.unwrap();
+    assert!(called);
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.source, Source::BuildInternal);
+    assert_eq!(testlib.version, "1.0");
+    assert_eq!(
+        libraries.get_by_name("testdata").unwrap().source,
+        Source::PkgConfig
+    );
+    // optional dependency not found by pkg-config
+    assert!(libraries.get_by_name("testmissing").is_none());
+
+    // the internal build does not satisfy the version so pkg-config is used
+    let (libraries, called) = probe(vec![], "0.9");
+    assert!(called);
+    let testlib = libraries.unwrap();
+    let testlib = testlib.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.source, Source::PkgConfig);
+    assert_eq!(testlib.version, "1.2.3");
+
+    // overridden by the env
+    let (libraries, called) = probe(
+        vec![("SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER", "pkg-config")],
+        "1.0",
+    );
+    assert!(!called);
+    assert_eq!(
+        libraries.unwrap().get_by_name("testlib").unwrap().source,
+        Source::PkgConfig
+    );
+    let (libraries, called) = probe(vec![("SYSTEM_DEPS_BUILD_INTERNAL", "never")], "1.0");
+    assert!(!called);
+    assert_eq!(
+        libraries.unwrap().get_by_name("testlib").unwrap().source,
+        Source::PkgConfig
+    );
+    let (libraries, called) = probe(
+        vec![("SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER", "pkg-config, internal")],
+        "1.0",
+    );
+    assert!(!called);
+    assert!(libraries.is_ok());
+
+    let (err, _) = probe(
+        vec![("SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER", "pkg-config,vcpkg")],
+        "1.0",
+    );
+    assert_matches!(err, Err(Error::ResolveOrderInvalid(_)));
+
+    toml_err_invalid(
+        "toml-resolve-order-invalid",
+        "invalid resolve_order value: vcpkg (allowed: 'pkg-config', 'internal')",
+    );
+}
+
+#[test]
+fn resolve_order_optional_build_failed() {
+    // the internal build of an optional dependency failing is reported
+    let called = Rc::new(Cell::new(false));
+    let called_clone = called.clone();
+    let err = create_config("toml-resolve-order-optional", vec![])
+        .add_build_internal("testmissing", move |_lib, _version| {
+            called_clone.replace(true);
+            Err(BuildInternalClosureError::failed("Something went wrong"))
+        })
+        .probe_full()
+        .unwrap_err();
+    assert!(called.get());
+    assert_matches!(err, Error::BuildInternalClosureError(..));
+
+    let err = create_config("toml-resolve-order-optional", vec![])
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::BuildInternalNoClosure(..));
+
+    // only skipped if not found by pkg-config
+    let libraries = create_config(
+        "toml-resolve-order-optional",
+        vec![("SYSTEM_DEPS_TESTMISSING_RESOLVE_ORDER", "pkg-config")],
+    )
+    .probe_full()
+    .unwrap();
+    assert!(libraries.get_by_name("testmissing").is_none());
+}
+
 #[test]
 fn build_internal_override_name() {
     let (libraries, called) = test_build_internal(
@@ -767,17 +1365,49 @@ fn build_internal_override_name() {
     )
     .unwrap();
 
-    assert_eq!(called, true);
+    assert!(called);
     assert!(libraries.get_by_name("test_lib").is_some());
 }
 
+#[test]
+fn build_internal_key_and_name() {
+    let probe = |keys: Vec<&'static str>| {
+        let called = Rc::new(RefCell::new(Vec::new()));
+        let mut config = create_config(
+            "toml-override-name",
+            vec![("SYSTEM_DEPS_TEST_LIB_RESOLVE_ORDER", "internal")],
+        );
+        for key in keys {
+            let called = called.clone();
+            config = config.add_build_internal(key, move |lib, version| {
+                called.borrow_mut().push(key);
+                let mut pkg_lib = pkg_config::Config::new()
+                    .print_system_libs(false)
+                    .cargo_metadata(false)
+                    .probe(lib)
+                    .unwrap();
+                pkg_lib.version = version.to_string();
+                Ok(Library::from_pkg_config(lib, pkg_lib))
+            });
+        }
+        let libraries = config.probe_full().unwrap();
+        assert!(libraries.get_by_name("test_lib").is_some());
+        called.take()
+    };
+
+    assert_eq!(probe(vec!["test_lib"]), vec!["test_lib"]);
+    assert_eq!(probe(vec!["testlib"]), vec!["testlib"]);
+    // the closure of the key takes precedence
+    assert_eq!(probe(vec!["testlib", "test_lib"]), vec!["test_lib"]);
+}
+
 #[test]
 fn optional() {
     // without any feature, testmore is not optional
     toml_pkg_config_err_version("toml-optional", "2", vec![]);
 
     // when enabling v3 testmore is now optional
-    let config = create_config("toml-optional", vec![("CARGO_FEATURE_V3", "")]);
+    let mut config = create_config("toml-optional", vec![("CARGO_FEATURE_V3", "")]);
     let libs = config.probe_full().unwrap();
     assert!(libs.get_by_name("testlib").is_some());
     assert!(libs.get_by_name("testmore").is_none());
@@ -787,6 +1417,33 @@ fn optional() {
     toml_pkg_config_err_version("toml-optional", "5.0", vec![("CARGO_FEATURE_V5", "")]);
 }
 
+#[test]
+fn force_missing() {
+    // the optional testlib is skipped even if installed
+    let mut config = create_config(
+        "toml-optional",
+        vec![
+            ("CARGO_FEATURE_V3", ""),
+            ("SYSTEM_DEPS_TESTLIB_FORCE_MISSING", "1"),
+        ],
+    );
+    let libs = config.probe_full().unwrap();
+    assert!(libs.get_by_name("testlib").is_none());
+
+    // required deps make the build fail
+    let (libraries, _) = toml("toml-good", vec![]).unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    let err = toml(
+        "toml-good",
+        vec![("SYSTEM_DEPS_TESTLIB_FORCE_MISSING", "1")],
+    )
+    .unwrap_err();
+    assert_matches!(
+        err,
+        Error::PkgConfig(e) if matches!(&*e, pkg_config::Error::EnvNoPkgConfig(var) if var == "SYSTEM_DEPS_TESTLIB_FORCE_MISSING")
+    );
+}
+
 #[test]
 fn aggregate() {
     let (libraries, _) = toml("toml-two-libs", vec![]).unwrap();
@@ -851,12 +1508,2375 @@ fn os_specific() {
 }
 
 #[test]
-fn invalid_cfg() {
+fn cfg_duplicate() {
     let err = toml(
-        "toml-invalid-cfg",
+        "toml-cfg-duplicate",
+        vec![("TARGET", "x86_64-unknown-linux-gnu")],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::InvalidMetadata(_));
+    assert_eq!(
+        err.to_string(),
+        "testlib is declared in both cfg(unix) and cfg(not(windows)) which match the target"
+    );
+
+    // only one of the tables matches
+    let (libraries, _) = toml(
+        "toml-cfg-duplicate",
+        vec![("TARGET", "x86_64-pc-windows-gnu")],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testlib").is_none());
+    let (libraries, _) = toml(
+        "toml-cfg-duplicate",
+        vec![("TARGET", "wasm32-unknown-unknown")],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+}
+
+#[test]
+fn fallback() {
+    let windows = ("TARGET", "x86_64-pc-windows-gnu");
+    let (libraries, flags) = toml("toml-fallback", vec![windows]).unwrap();
+    // only used if the dependency cannot be resolved
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.source, Source::PkgConfig);
+    assert_eq!(testlib.libs, vec!["test"]);
+
+    let testmissing = libraries.get_by_name("testmissing").unwrap();
+    assert_eq!(testmissing.source, Source::EnvVariables);
+    assert_eq!(testmissing.libs, vec!["missing"]);
+    assert_eq!(testmissing.link_paths, vec![Path::new("/opt/missing/lib")]);
+    assert_eq!(
+        testmissing.include_paths,
+        vec![Path::new("/opt/missing/include")]
+    );
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-search=native=/opt/missing/lib\n"));
+    assert!(flags.contains("cargo:rustc-link-lib=missing\n"));
+    assert!(libraries.get_by_name("testmissingwindows").is_some());
+
+    // the fallback of testmissingwindows does not match the target
+    let err = toml(
+        "toml-fallback",
         vec![("TARGET", "x86_64-unknown-linux-gnu")],
     )
     .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(..));
+    assert!(err.to_string().contains("testmissingwindows"));
 
-    assert_matches!(err, Error::UnsupportedCfg(_));
+    toml_err_invalid(
+        "toml-fallback-invalid",
+        "unexpected fallback key version type string",
+    );
+}
+
+#[test]
+fn fallback_no_pkg_config() {
+    let windows = ("TARGET", "x86_64-pc-windows-gnu");
+    let (libraries, flags) = toml(
+        "toml-fallback",
+        vec![windows, ("SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG", "1")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.source, Source::EnvVariables);
+    assert_eq!(testlib.libs, vec!["notused"]);
+    assert!(flags.to_string().contains("cargo:rustc-link-lib=notused\n"));
+
+    // the env variables take precedence over the fallback
+    let (libraries, _) = toml(
+        "toml-fallback",
+        vec![
+            windows,
+            ("SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG", "1"),
+            ("SYSTEM_DEPS_TESTLIB_LIB", "custom"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().libs,
+        vec!["custom"]
+    );
+}
+
+#[test]
+fn cfg_and_feature() {
+    let linux = ("TARGET", "x86_64-unknown-linux-gnu");
+    let extra = ("CARGO_FEATURE_EXTRA", "");
+
+    let (libraries, _) = toml("toml-cfg-feature", vec![linux, extra]).unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    // skipped if either the feature is disabled or the target does not match
+    let (libraries, _) = toml("toml-cfg-feature", vec![linux]).unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_none());
+
+    let (libraries, _) = toml(
+        "toml-cfg-feature",
+        vec![("TARGET", "x86_64-apple-darwin"), extra],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_none());
+
+    // same using `Config::enabled_features`
+    let libraries = create_config("toml-cfg-feature", vec![linux])
+        .enabled_features(vec!["extra".into()])
+        .probe_full()
+        .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+}
+
+#[test]
+fn probe_for_targets() {
+    let mut libraries = create_config(
+        "toml-os-specific",
+        vec![
+            ("TARGET", "x86_64-unknown-linux-gnu"),
+            (
+                "PKG_CONFIG_PATH_aarch64_apple_darwin",
+                "/opt/arm64/lib/pkgconfig",
+            ),
+        ],
+    )
+    .probe_for_targets(&["x86_64-apple-darwin", "aarch64-apple-darwin"])
+    .unwrap();
+    assert_eq!(libraries.len(), 2);
+
+    let x86_64 = libraries.remove("x86_64-apple-darwin").unwrap();
+    assert!(x86_64.get_by_name("testdata").is_none());
+    assert!(x86_64.get_by_name("testlib").is_none());
+    let testanotherlib = x86_64.get_by_name("testanotherlib").unwrap();
+    assert!(!testanotherlib
+        .probe_command()
+        .unwrap()
+        .contains("/opt/arm64/lib/pkgconfig"));
+
+    let aarch64 = libraries.remove("aarch64-apple-darwin").unwrap();
+    assert!(aarch64.get_by_name("testdata").is_none());
+    let testanotherlib = aarch64.get_by_name("testanotherlib").unwrap();
+    assert!(testanotherlib
+        .probe_command()
+        .unwrap()
+        .contains("/opt/arm64/lib/pkgconfig"));
+
+    let err = create_config("toml-os-specific", vec![])
+        .probe_for_targets(&["x86_64-apple-darwin", "badger-apple-darwin"])
+        .unwrap_err();
+    assert_matches!(err, Error::UnsupportedTarget(t) if t == "badger-apple-darwin");
+}
+
+#[test]
+fn cfg_cache() {
+    let config = create_config(
+        "toml-os-specific",
+        vec![("TARGET", "x86_64-pc-windows-msvc")],
+    );
+    let mut cache = CfgCache::default();
+    let linux = cfg_expr::Expression::parse("cfg(target_os = \"linux\")").unwrap();
+    let windows = cfg_expr::Expression::parse("cfg(windows)").unwrap();
+
+    assert!(!config.check_cfg(&mut cache, &linux).unwrap());
+    let target = cache.target.unwrap().unwrap();
+    assert_eq!(target.triple, "x86_64-pc-windows-msvc");
+    assert_eq!(cache.results.len(), 1);
+
+    // the target is only looked up once, so a config without TARGET can reuse the cache
+    let config = create_config("toml-os-specific", vec![]);
+    assert!(config.check_cfg(&mut cache, &windows).unwrap());
+    assert!(!config.check_cfg(&mut cache, &linux).unwrap());
+    assert_eq!(cache.results.len(), 2);
+}
+
+#[test]
+fn bare_metal() {
+    let (libraries, _) =
+        toml("toml-bare-metal", vec![("TARGET", "thumbv7em-none-eabihf")]).unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_none());
+
+    let (libraries, _) = toml(
+        "toml-bare-metal",
+        vec![("TARGET", "x86_64-unknown-linux-gnu")],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_none());
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    // unknown targets fallback to the cfgs defined by cargo
+    let (libraries, _) = toml(
+        "toml-bare-metal",
+        vec![
+            ("TARGET", "riscv32imac-custom-none-elf"),
+            ("CARGO_CFG_TARGET_OS", "none"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_none());
+
+    let (libraries, _) = toml(
+        "toml-os-specific",
+        vec![
+            ("TARGET", "x86_64-custom-linux-gnu"),
+            ("CARGO_CFG_TARGET_OS", "linux"),
+            ("CARGO_CFG_TARGET_FAMILY", "unix"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testanotherlib").is_some());
+}
+
+#[test]
+fn invalid_cfg() {
+    let err = toml(
+        "toml-invalid-cfg",
+        vec![("TARGET", "x86_64-unknown-linux-gnu")],
+    )
+    .unwrap_err();
+
+    assert_matches!(err, Error::UnsupportedCfg(_));
+}
+
+#[test]
+fn link_arg_files() {
+    let (libraries, flags) = toml_flags("toml-link-arg-files", vec![]).unwrap();
+    let script = env::current_dir()
+        .unwrap()
+        .join("src")
+        .join("tests")
+        .join("toml-link-arg-files")
+        .join("link.x");
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(
+        testlib.link_args,
+        vec![
+            "-Wl,--gc-sections".to_string(),
+            format!("-T{}", script.display())
+        ]
+    );
+    assert_eq!(libraries.rerun_triggers().paths, vec![script.clone()]);
+
+    let flags = flags.to_string();
+    assert!(flags.contains(&format!("cargo:rustc-link-arg=-T{}\n", script.display())));
+    assert!(flags.contains(&format!("cargo:rerun-if-changed={}\n", script.display())));
+}
+
+#[test]
+fn link_args() {
+    let (libraries, flags) = toml("toml-link-args", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.link_args, vec!["-Wl,--as-needed"]);
+    assert_eq!(testlib.link_args_bins, vec!["-Wl,-rpath,/opt/testlib/lib"]);
+    assert_eq!(testlib.link_args_tests, vec!["-Wl,-z,now"]);
+    assert_eq!(testlib.link_args_benches, vec!["-Wl,-O1"]);
+
+    // -Wl,--as-needed is requested by both libs but only emitted once
+    assert_flags(
+        flags,
+        r#"cargo:rustc-link-search=native=/usr/lib/
+cargo:rustc-link-search=framework=/usr/lib/
+cargo:rustc-link-lib=test
+cargo:rustc-link-lib=framework=someframework
+cargo:rustc-link-arg=-Wl,--as-needed
+cargo:rustc-link-arg-bins=-Wl,-rpath,/opt/testlib/lib
+cargo:rustc-link-arg-bins=-Wl,-rpath,/opt/testdata/lib
+cargo:rustc-link-arg-tests=-Wl,-z,now
+cargo:rustc-link-arg-benches=-Wl,-O1
+cargo:include=/usr/include/testlib
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_INCLUDE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_REMOVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FRAMEWORK_APPEND
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_RESOLVE_ORDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_SEARCH
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_INCLUDE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_REMOVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FRAMEWORK_APPEND
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_FORCE_MISSING
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_RESOLVE_ORDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH
+cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_ALLOW_OLDER
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_ALLOW_OLDER
+"#,
+    );
+}
+
+#[test]
+fn have_cfgs() {
+    let (_, flags) = create_config("toml-good", vec![]).probe_flags().unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-cfg=system_deps_have_testlib\n"));
+    assert!(flags.contains("cargo:rustc-cfg=system_deps_have_testdata\n"));
+    assert!(!flags.contains("cargo:rustc-cfg=system_deps_have_testmore"));
+    // check-cfg is declared for all deps, even the ones which have not been probed
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_have_testlib)\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_have_testdata)\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_have_testmore)\n"));
+
+    let (_, flags) = create_config("toml-good", vec![])
+        .emit_have_cfgs(false)
+        .probe_flags()
+        .unwrap();
+    let flags = flags.to_string();
+    assert!(!flags.contains("rustc-cfg="));
+    assert!(!flags.contains("rustc-check-cfg="));
+}
+
+#[test]
+fn cfg_name() {
+    let (_, flags) = create_config("toml-cfg-name", vec![])
+        .probe_flags()
+        .unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-cfg=system_deps_have_test\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_have_test)\n"));
+    assert!(!flags.contains("system_deps_have_testlib"));
+    assert!(flags.contains("cargo:rustc-cfg=system_deps_have_testdata\n"));
+
+    toml_err_invalid(
+        "toml-cfg-name-invalid",
+        "metadata.system-deps.testlib: cfg_name not-valid is not a valid identifier",
+    );
+}
+
+#[test]
+fn define_cfgs() {
+    let (_, flags) = create_config("toml-defines", vec![])
+        .emit_have_cfgs(false)
+        .emit_define_cfgs(true)
+        .probe_flags()
+        .unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-cfg=have_foo\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(have_foo)\n"));
+    assert!(flags.contains("cargo:rustc-cfg=have_bar_baz\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(have_bar_baz)\n"));
+    assert!(!flags.contains("other"));
+
+    // not emitted by default
+    let (_, flags) = create_config("toml-defines", vec![]).probe_flags().unwrap();
+    assert!(!flags.to_string().contains("have_foo"));
+}
+
+#[test]
+fn version_cfgs() {
+    // testdata 4.5.6 is found while the enabled feature only requires 4.2
+    let (_, flags) = create_config("toml-version-cfgs", vec![("CARGO_FEATURE_V4_2", "")])
+        .emit_have_cfgs(false)
+        .emit_version_cfgs(true)
+        .probe_flags()
+        .unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-cfg=system_deps_testdata_4_5\n"));
+    assert!(!flags.contains("cargo:rustc-cfg=system_deps_testdata_4_2\n"));
+    assert!(!flags.contains("cargo:rustc-cfg=system_deps_testdata_5\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_testdata_4_2)\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_testdata_4_5)\n"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_testdata_5)\n"));
+    assert!(!flags.contains("system_deps_testlib"));
+
+    // not emitted by default
+    let (_, flags) = create_config("toml-version-cfgs", vec![])
+        .probe_flags()
+        .unwrap();
+    assert!(!flags.to_string().contains("system_deps_testdata_4"));
+}
+
+#[test]
+fn version_cfgs_unknown_version() {
+    // the libs defined by env variables have no version
+    let (_, flags) = create_config(
+        "toml-version-cfgs",
+        vec![
+            ("SYSTEM_DEPS_TESTDATA_NO_PKG_CONFIG", "1"),
+            ("SYSTEM_DEPS_TESTDATA_LIB", "testdata"),
+        ],
+    )
+    .emit_have_cfgs(false)
+    .emit_version_cfgs(true)
+    .probe_flags()
+    .unwrap();
+    let flags = flags.to_string();
+    assert!(!flags.contains("cargo:rustc-cfg=system_deps_testdata"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_testdata_4_5)\n"));
+
+    let (_, flags) = create_config(
+        "toml-version-cfgs-unknown",
+        vec![("SYSTEM_DEPS_TESTUNKNOWNVERSION_ALLOW_OLDER", "1")],
+    )
+    .emit_have_cfgs(false)
+    .emit_version_cfgs(true)
+    .probe_flags()
+    .unwrap();
+    let flags = flags.to_string();
+    assert!(!flags.contains("cargo:rustc-cfg=system_deps_testunknownversion"));
+    assert!(flags.contains("cargo:rustc-check-cfg=cfg(system_deps_testunknownversion_1_5)\n"));
+}
+
+#[test]
+fn include() {
+    let (libraries, flags) = create_config("toml-include", vec![]).probe_flags().unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+    assert_eq!(libraries.get_by_name("testdata").unwrap().version, "4.5.6");
+
+    let include = env::current_dir()
+        .unwrap()
+        .join("src")
+        .join("tests")
+        .join("toml-include")
+        .join("system-deps.toml");
+    assert!(flags
+        .to_string()
+        .contains(&format!("cargo:rerun-if-changed={}\n", include.display())));
+}
+
+#[test]
+fn rerun_triggers() {
+    let (libraries, flags) = create_config("toml-include", vec![])
+        .probe_to_flags()
+        .unwrap();
+    let triggers = libraries.rerun_triggers();
+
+    // match the rerun instructions emitted
+    let env_vars = flags
+        .iter()
+        .filter_map(|l| l.strip_prefix("cargo:rerun-if-env-changed="))
+        .collect::<Vec<_>>();
+    assert_eq!(triggers.env_vars, env_vars);
+    assert!(env_vars.contains(&"SYSTEM_DEPS_TESTLIB_LIB"));
+    let paths = flags
+        .iter()
+        .filter_map(|l| l.strip_prefix("cargo:rerun-if-changed="))
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    assert_eq!(triggers.paths, paths);
+    assert_eq!(
+        triggers.paths,
+        vec![env::current_dir()
+            .unwrap()
+            .join("src")
+            .join("tests")
+            .join("toml-include")
+            .join("system-deps.toml")]
+    );
+}
+
+#[test]
+fn rerun_triggers_config() {
+    let path = env::temp_dir().join("system-deps-test-rerun-triggers-pinned-versions.toml");
+    let _ = std::fs::remove_file(&path);
+    let mut vars = HashMap::new();
+    vars.insert("SYSTEM_DEPS_TEST_RERUN".to_string(), "1".to_string());
+
+    let (libraries, flags) = create_config("toml-good", vec![])
+        .pin_versions(&path)
+        .pkg_config_env(vars)
+        .probe_flags()
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    let triggers = libraries.rerun_triggers();
+
+    // the env variables of the config are part of the rerun instructions as well
+    let flags = flags.to_string();
+    let env_vars = flags
+        .lines()
+        .filter_map(|l| l.strip_prefix("cargo:rerun-if-env-changed="))
+        .collect::<Vec<_>>();
+    assert_eq!(triggers.env_vars, env_vars);
+    assert!(env_vars.contains(&"SYSTEM_DEPS_REFRESH_PINNED_VERSIONS"));
+    assert!(env_vars.contains(&"SYSTEM_DEPS_TEST_RERUN"));
+}
+
+#[test]
+fn prefer_homebrew() {
+    // no-op if not on macOS or if brew is not installed
+    let libraries = create_config("toml-good", vec![])
+        .prefer_homebrew()
+        .probe_full()
+        .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testdata").is_some());
+}
+
+#[test]
+fn always_probe() {
+    let (libraries, _) = toml("toml-always-probe", vec![]).unwrap();
+    // testlib is probed even if its feature is disabled
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testdata").is_none());
+
+    let (libraries, _) = toml(
+        "toml-always-probe",
+        vec![("CARGO_FEATURE_ANOTHER_TEST_FEATURE", "")],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testdata").is_some());
+}
+
+#[test]
+fn any_version() {
+    let (libraries, _) = toml("toml-any-version", vec![]).unwrap();
+    // testdata has no version and testlib 1.2.3 does not match the required 9
+    assert_eq!(libraries.get_by_name("testdata").unwrap().version, "4.5.6");
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+
+    let err = toml("toml-any-version-not-optional", vec![]).unwrap_err();
+    assert_matches!(err, Error::InvalidMetadata(_));
+}
+
+#[test]
+fn declared_deps() {
+    let config = create_config("toml-good", vec![]);
+    assert_eq!(
+        config.declared_deps().unwrap(),
+        vec!["testdata", "testlib", "testmore"]
+    );
+
+    // cfg-gated deps are listed whatever the target
+    let config = create_config("toml-os-specific", vec![]);
+    assert_eq!(
+        config.declared_deps().unwrap(),
+        vec!["testdata", "testlib", "testanotherlib"]
+    );
+}
+
+#[test]
+fn dep_summary() {
+    let config = create_config("toml-good", vec![]);
+    assert_eq!(
+        config.dep_summary().unwrap(),
+        DepSummary {
+            total: 3,
+            optional: 0,
+            feature_gated: 2,
+            cfg_gated: 0,
+            with_version_overrides: 0,
+        }
+    );
+
+    let config = create_config("toml-os-specific", vec![]);
+    assert_eq!(config.dep_summary().unwrap().cfg_gated, 3);
+
+    let config = create_config("toml-optional", vec![]);
+    let summary = config.dep_summary().unwrap();
+    assert_eq!(summary.optional, 2);
+    assert_eq!(summary.with_version_overrides, 2);
+}
+
+#[test]
+fn check_orig_manifest() {
+    let (libraries, _) = toml("toml-orig-manifest", vec![]).unwrap();
+    assert!(libraries.get_by_name("testlib").is_none());
+
+    let (libraries, flags) = create_config("toml-orig-manifest", vec![])
+        .check_orig_manifest(true)
+        .probe_flags()
+        .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    let dir = env::current_dir().unwrap().join("src").join("tests");
+    let flags = flags.to_string();
+    assert!(flags.contains(&format!(
+        "cargo:rerun-if-changed={}\n",
+        dir.join("toml-orig-manifest")
+            .join("Cargo.toml.orig")
+            .display()
+    )));
+
+    // only used if the manifest has no metadata
+    let (_, flags) = create_config("toml-good", vec![])
+        .check_orig_manifest(true)
+        .probe_flags()
+        .unwrap();
+    assert!(flags.to_string().contains(&format!(
+        "cargo:rerun-if-changed={}\n",
+        dir.join("toml-good").join("Cargo.toml").display()
+    )));
+}
+
+#[test]
+fn manifest_path() {
+    let mut config = create_config("toml-good", vec![]);
+    if let EnvVariables::Mock(vars) = &mut config.env {
+        vars.remove("CARGO_MANIFEST_DIR");
+    }
+    let config = config.manifest_path(
+        env::current_dir()
+            .unwrap()
+            .join("src")
+            .join("tests")
+            .join("toml-good")
+            .join("Cargo.toml"),
+    );
+    let (libraries, _) = config.probe_flags().unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    // neither $CARGO_MANIFEST_DIR nor a manifest path
+    let mut config = create_config("toml-good", vec![]);
+    if let EnvVariables::Mock(vars) = &mut config.env {
+        vars.remove("CARGO_MANIFEST_DIR");
+    }
+    assert_matches!(config.probe_flags(), Err(Error::MissingManifest));
+}
+
+#[test]
+fn libs_kind() {
+    let (libraries, flags) = toml("toml-libs", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.libs, vec!["test", "extra", "plain"]);
+    assert_eq!(testlib.static_libs, vec!["test"]);
+    assert_eq!(testlib.dylib_libs, vec!["extra"]);
+    let flags = flags.to_string();
+    assert!(flags.contains(
+        "cargo:rustc-link-lib=static=test\ncargo:rustc-link-lib=dylib=extra\ncargo:rustc-link-lib=plain\n"
+    ));
+
+    // the libs reported by pkg-config are replaced
+    let testanotherlib = libraries.get_by_name("testanotherlib").unwrap();
+    assert_eq!(testanotherlib.libs, vec!["test2"]);
+    assert!(flags.contains("cargo:rustc-link-lib=dylib=test2\n"));
+
+    toml_err_invalid(
+        "toml-libs-invalid",
+        "invalid libs kind: shared (allowed: 'static', 'dylib')",
+    );
+}
+
+#[test]
+fn check() {
+    create_config("toml-good", vec![]).check().unwrap();
+
+    // all the failures are reported, the optional deps being skipped
+    let errors = create_config("toml-check", vec![]).check().unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_matches!(errors[0], Error::PkgConfig(_));
+    assert!(errors[0].to_string().contains("testlib"));
+    assert_matches!(errors[1], Error::PkgConfig(_));
+    assert!(errors[1].to_string().contains("testmissing"));
+
+    // the dependencies are checked once all have been probed
+    let errors = create_config(
+        "toml-conflicts",
+        vec![("CARGO_FEATURE_ANOTHER_TEST_FEATURE", "")],
+    )
+    .check()
+    .unwrap_err();
+    assert_matches!(errors[..], [Error::ConflictingDeps(..)]);
+}
+
+#[test]
+fn override_search() {
+    let (libraries, flags) = toml(
+        "toml-good",
+        vec![("SYSTEM_DEPS_TESTLIB_SEARCH", "/custom/path")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.search_paths, vec![Path::new("/custom/path")]);
+    // other search paths are kept
+    assert_eq!(testlib.link_paths, vec![Path::new("/usr/lib/")]);
+
+    assert!(flags
+        .to_string()
+        .contains("cargo:rustc-link-search=/custom/path\n"));
+}
+
+#[test]
+fn override_bulk() {
+    let (libraries, flags) = toml(
+        "toml-good",
+        vec![
+            (
+                "SYSTEM_DEPS_OVERRIDES",
+                r#"
+[testlib]
+libs = ["bulk-test"]
+include_paths = ["/bulk/include"]
+link_paths = ["/bulk/lib"]
+"#,
+            ),
+            // individual variables take precedence
+            ("SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE", "/custom/path"),
+        ],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.libs, vec!["bulk-test"]);
+    assert_eq!(testlib.include_paths, vec![Path::new("/bulk/include")]);
+    assert_eq!(testlib.link_paths, vec![Path::new("/custom/path")]);
+    assert_eq!(testlib.frameworks, vec!["someframework"]);
+
+    assert!(flags
+        .to_string()
+        .contains("cargo:rerun-if-env-changed=SYSTEM_DEPS_OVERRIDES\n"));
+}
+
+#[test]
+fn override_bulk_invalid() {
+    let err = toml(
+        "toml-good",
+        vec![("SYSTEM_DEPS_OVERRIDES", "[testlib]\nlibs = \"test\"")],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::InvalidOverrides(_));
+    assert_eq!(
+        err.to_string(),
+        "Invalid SYSTEM_DEPS_OVERRIDES: testlib.libs not an array of strings"
+    );
+}
+
+#[test]
+fn statik() {
+    let (libraries, _) = toml("toml-good", vec![]).unwrap();
+    assert!(!libraries.get_by_name("testlib").unwrap().is_static());
+
+    let (libraries, _) = toml("toml-good", vec![("TESTLIB_STATIC", "1")]).unwrap();
+    assert!(libraries.get_by_name("testlib").unwrap().is_static());
+    assert!(!libraries.get_by_name("testdata").unwrap().is_static());
+
+    let (libraries, _) = toml(
+        "toml-good",
+        vec![("PKG_CONFIG_ALL_STATIC", "1"), ("TESTDATA_DYNAMIC", "1")],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testlib").unwrap().is_static());
+    assert!(!libraries.get_by_name("testdata").unwrap().is_static());
+}
+
+#[test]
+fn private_include_paths() {
+    let (libraries, _) = toml("toml-private-include-paths", vec![]).unwrap();
+    let testprivate = libraries.get_by_name("testprivate").unwrap();
+    assert_eq!(
+        testprivate.include_paths,
+        vec![Path::new("/opt/private/include/public")]
+    );
+    assert!(testprivate.private_include_paths.is_empty());
+
+    // pkg-config reads `TESTPRIVATE_STATIC` from the process env as well, the public include
+    // paths must still be probed without `--static`
+    env::set_var("TESTPRIVATE_STATIC", "1");
+    let res = toml(
+        "toml-private-include-paths",
+        vec![("TESTPRIVATE_STATIC", "1")],
+    );
+    env::remove_var("TESTPRIVATE_STATIC");
+    let (libraries, _) = res.unwrap();
+    let testprivate = libraries.get_by_name("testprivate").unwrap();
+    assert_eq!(
+        testprivate.include_paths,
+        vec![Path::new("/opt/private/include/public")]
+    );
+    assert_eq!(
+        testprivate.private_include_paths,
+        vec![Path::new("/opt/private/include/private")]
+    );
+    assert_eq!(
+        libraries.all_include_paths().collect::<Vec<_>>(),
+        vec![Path::new("/opt/private/include/public")]
+    );
+    assert_eq!(
+        libraries
+            .all_include_paths_with_private()
+            .collect::<Vec<_>>(),
+        vec![
+            Path::new("/opt/private/include/private"),
+            Path::new("/opt/private/include/public")
+        ]
+    );
+}
+
+#[test]
+fn pin_versions() {
+    let path = env::temp_dir().join("system-deps-test-pinned-versions.toml");
+    let _ = std::fs::remove_file(&path);
+    let probe = |env| {
+        create_config("toml-good", env)
+            .pin_versions(&path)
+            .probe_flags()
+    };
+
+    // the versions are pinned on first probe
+    let (_, flags) = probe(vec![]).unwrap();
+    assert!(flags
+        .to_string()
+        .contains("cargo:rerun-if-env-changed=SYSTEM_DEPS_REFRESH_PINNED_VERSIONS\n"));
+    let pinned = std::fs::read_to_string(&path).unwrap();
+    assert!(pinned.contains("testlib = \"1.2.3\"\n"));
+    assert!(pinned.contains("testdata = \"4.5.6\"\n"));
+    probe(vec![]).unwrap();
+
+    // drift
+    std::fs::write(&path, pinned.replace("1.2.3", "1.2.2")).unwrap();
+    let err = probe(vec![]).unwrap_err();
+    assert_matches!(err, Error::PinnedVersionMismatch(..));
+    assert_eq!(err.to_string(), "testlib is pinned to version 1.2.2 but version 1.2.3 has been found, define SYSTEM_DEPS_REFRESH_PINNED_VERSIONS to update the pinned versions");
+
+    // refreshed
+    probe(vec![("SYSTEM_DEPS_REFRESH_PINNED_VERSIONS", "1")]).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), pinned);
+
+    std::fs::write(&path, "versions = 1").unwrap();
+    assert_matches!(probe(vec![]).unwrap_err(), Error::InvalidPinnedVersions(..));
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn cache_file() {
+    let path = env::temp_dir().join("system-deps-test-cache.toml");
+    let _ = std::fs::remove_file(&path);
+    let probe = |env| {
+        Config {
+            cache_file: Some(path.clone()),
+            ..create_config("toml-good", env)
+        }
+        .probe_flags()
+        .unwrap()
+    };
+
+    let (libraries, flags) = probe(vec![]);
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+    assert!(path.exists());
+
+    // the cached dependencies are used, and the flags generated from them
+    let cache = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, cache.replace("1.2.3", "1.2.4")).unwrap();
+    let (cached, cached_flags) = probe(vec![]);
+    let testlib = cached.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.4");
+    assert_eq!(testlib.defines.get("BADGER"), Some(&Some("yes".into())));
+    assert_eq!(testlib.defines.get("AWESOME"), Some(&None));
+    assert_eq!(testlib.source, Source::PkgConfig);
+    assert_eq!(cached_flags.to_string(), flags.to_string());
+
+    // probed again when the env changes
+    let (libraries, _) = probe(vec![("TESTLIB_STATIC", "1")]);
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.3");
+    assert!(testlib.statik);
+
+    // or when the cache is invalid
+    std::fs::write(&path, "badger").unwrap();
+    let (libraries, _) = probe(vec![("TESTLIB_STATIC", "1")]);
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn validate() {
+    let libraries = create_config("toml-good", vec![])
+        .validate(|deps| {
+            assert!(deps.get_by_name("testlib").is_some());
+            Ok(())
+        })
+        .probe_full()
+        .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    // reject mismatching versions
+    let err = create_config("toml-good", vec![])
+        .validate(|deps| {
+            let testlib = deps.get_by_name("testlib").unwrap();
+            let testdata = deps.get_by_name("testdata").unwrap();
+            if testlib.version != testdata.version {
+                Err(format!(
+                    "testlib {} and testdata {} mismatch",
+                    testlib.version, testdata.version
+                ))
+            } else {
+                Ok(())
+            }
+        })
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::ValidationFailed(_));
+    assert_eq!(
+        err.to_string(),
+        "Dependencies validation failed: testlib 1.2.3 and testdata 4.5.6 mismatch"
+    );
+}
+
+#[test]
+fn emit_include() {
+    let (libraries, flags) = create_config("toml-good", vec![])
+        .emit_include(false)
+        .probe_flags()
+        .unwrap();
+    assert!(!flags.to_string().contains("cargo:include="));
+    // include paths are still available
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().include_paths,
+        vec![Path::new("/usr/include/testlib")]
+    );
+}
+
+#[test]
+fn version_from_crate() {
+    let (libraries, _) = toml(
+        "toml-crate-version",
+        vec![
+            ("CARGO_PKG_VERSION_MAJOR", "1"),
+            ("CARGO_PKG_VERSION_MINOR", "2"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+
+    toml_pkg_config_err_version(
+        "toml-crate-version",
+        "1.3",
+        vec![
+            ("CARGO_PKG_VERSION_MAJOR", "1"),
+            ("CARGO_PKG_VERSION_MINOR", "3"),
+        ],
+    );
+
+    toml_err_invalid("toml-crate-version", "$CARGO_PKG_VERSION_MAJOR not set");
+}
+
+#[test]
+fn static_libs() {
+    let (libraries, flags) = toml("toml-static-libs", vec![]).unwrap();
+    let testanotherlib = libraries.get_by_name("testanotherlib").unwrap();
+    assert_eq!(testanotherlib.static_libs, vec!["test2"]);
+
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=static=test2\n"));
+    assert!(flags.contains("cargo:rustc-link-lib=test\n"));
+    assert!(!flags.contains("cargo:rustc-link-lib=test2\n"));
+
+    // libraries not reported by pkg-config are reported
+    let (_, flags) = toml_flags("toml-static-libs-unknown", vec![]).unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=static=test2\n"));
+    assert!(flags.contains(
+        "cargo:warning=testanotherlib: static_libs lists tset which is not one of its libraries, it is ignored\n"
+    ));
+    assert_eq!(flags.matches("cargo:warning=").count(), 1);
+}
+
+#[test]
+fn whole_archive_libs() {
+    let (libraries, flags) = toml("toml-whole-archive-libs", vec![]).unwrap();
+    let testanotherlib = libraries.get_by_name("testanotherlib").unwrap();
+    assert_eq!(testanotherlib.whole_archive_libs, vec!["test2"]);
+
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=static:+whole-archive=test2\n"));
+    assert!(flags.contains("cargo:rustc-link-lib=test\n"));
+    assert!(!flags.contains("cargo:rustc-link-lib=test2\n"));
+}
+
+#[test]
+fn verbatim_libs() {
+    let (libraries, flags) = toml("toml-verbatim-libs", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.verbatim_libs, vec!["libtest-1.2.a", "test.dll.lib"]);
+
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=test\n"));
+    // listed in `static_libs`
+    assert!(flags.contains("cargo:rustc-link-lib=static:+verbatim=libtest-1.2.a\n"));
+    assert!(flags.contains("cargo:rustc-link-lib=dylib:+verbatim=test.dll.lib\n"));
+
+    // all of them are linked statically with the library
+    let (_, flags) = toml("toml-verbatim-libs", vec![("TESTLIB_STATIC", "1")]).unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=static:+verbatim=libtest-1.2.a\n"));
+    assert!(flags.contains("cargo:rustc-link-lib=static:+verbatim=test.dll.lib\n"));
+}
+
+#[test]
+fn no_emit() {
+    let (libraries, flags) = toml("toml-no-emit", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert!(!testlib.is_emitted());
+    assert_eq!(testlib.libs, vec!["test"]);
+    assert!(libraries.get_by_name("testdata").unwrap().is_emitted());
+
+    let flags = flags.to_string();
+    assert!(!flags.contains("cargo:rustc-link-lib=test\n"));
+    assert!(!flags.contains("cargo:include="));
+    assert!(flags.contains("cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_EMIT\n"));
+
+    // using the env variable
+    let (libraries, flags) = toml("toml-good", vec![("SYSTEM_DEPS_TESTLIB_NO_EMIT", "1")]).unwrap();
+    assert!(!libraries.get_by_name("testlib").unwrap().is_emitted());
+    assert!(!flags.to_string().contains("cargo:rustc-link-lib=test\n"));
+
+    // emitted by default
+    let (libraries, flags) = toml("toml-good", vec![]).unwrap();
+    assert!(libraries.get_by_name("testlib").unwrap().is_emitted());
+    assert!(flags.to_string().contains("cargo:rustc-link-lib=test\n"));
+}
+
+#[test]
+fn link_group() {
+    let (_, flags) = toml("toml-link-group", vec![]).unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains(
+        "cargo:rustc-link-arg=-Wl,--start-group
+cargo:rustc-link-arg=-ltest
+cargo:rustc-link-arg=-Wl,-Bstatic
+cargo:rustc-link-arg=-ltest2
+cargo:rustc-link-arg=-Wl,-Bdynamic
+cargo:rustc-link-arg=-Wl,--end-group
+cargo:rustc-link-lib=framework=someframework
+"
+    ));
+    assert!(!flags.contains("cargo:rustc-link-lib=test\n"));
+
+    // not needed by MSVC
+    let (_, flags) = toml(
+        "toml-link-group",
+        vec![("TARGET", "x86_64-pc-windows-msvc")],
+    )
+    .unwrap();
+    let flags = flags.to_string();
+    assert!(!flags.contains("--start-group"));
+    assert!(flags.contains("cargo:rustc-link-lib=test\n"));
+    assert!(flags.contains("cargo:rustc-link-lib=static=test2\n"));
+}
+
+#[test]
+fn tool_path() {
+    let (libraries, _) = toml("toml-tools", vec![]).unwrap();
+    let testtools = libraries.get_by_name("testtools").unwrap();
+    let bin = env::current_dir()
+        .unwrap()
+        .join("src/tests/tools/bin")
+        .canonicalize()
+        .unwrap();
+
+    let tool = testtools.tool_path("test_tool").unwrap();
+    assert_eq!(tool.canonicalize().unwrap(), bin.join("test-tool"));
+    let tool = testtools.tool_path("test_tool_relative").unwrap();
+    assert_eq!(tool.canonicalize().unwrap(), bin.join("test-tool"));
+
+    assert_eq!(testtools.tool_path("test_missing"), None);
+    assert_eq!(testtools.tool_path("undefined"), None);
+    #[cfg(unix)]
+    assert_eq!(testtools.tool_path("test_not_executable"), None);
+
+    // not found using pkg-config
+    let (libraries, _) = toml(
+        "toml-tools",
+        vec![
+            ("SYSTEM_DEPS_TESTTOOLS_NO_PKG_CONFIG", "1"),
+            ("SYSTEM_DEPS_TESTTOOLS_LIB", "testtools"),
+        ],
+    )
+    .unwrap();
+    let testtools = libraries.get_by_name("testtools").unwrap();
+    assert_eq!(testtools.tool_path("test_tool"), None);
+}
+
+#[test]
+fn exclude_libs() {
+    let (libraries, _) = toml("toml-exclude-libs", vec![]).unwrap();
+    let testanotherlib = libraries.get_by_name("testanotherlib").unwrap();
+    assert_eq!(testanotherlib.libs, vec!["test"]);
+
+    let (libraries, _) = toml(
+        "toml-two-libs",
+        vec![("SYSTEM_DEPS_TESTANOTHERLIB_LIB_REMOVE", "test other")],
+    )
+    .unwrap();
+    let testanotherlib = libraries.get_by_name("testanotherlib").unwrap();
+    assert_eq!(testanotherlib.libs, vec!["test2"]);
+
+    // removal is applied after replacing the libs
+    let (libraries, _) = toml(
+        "toml-two-libs",
+        vec![
+            ("SYSTEM_DEPS_TESTANOTHERLIB_LIB", "custom1 custom2"),
+            ("SYSTEM_DEPS_TESTANOTHERLIB_LIB_REMOVE", "custom1"),
+        ],
+    )
+    .unwrap();
+    let testanotherlib = libraries.get_by_name("testanotherlib").unwrap();
+    assert_eq!(testanotherlib.libs, vec!["custom2"]);
+}
+
+#[test]
+fn pkg_config_paths() {
+    let (libraries, _) = toml("toml-pkg-config-paths", vec![]).unwrap();
+    let testvendored = libraries.get_by_name("testvendored").unwrap();
+    assert!(testvendored.vendored);
+    assert_eq!(testvendored.link_paths, vec![Path::new("/opt/vendor/lib/")]);
+    // testlib is not vendored so it is found in the system paths
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert!(!testlib.vendored);
+    assert_eq!(testlib.version, "1.2.3");
+
+    let vendor = env::current_dir()
+        .unwrap()
+        .join("src")
+        .join("tests")
+        .join("toml-pkg-config-paths")
+        .join("vendor");
+    assert_eq!(
+        testvendored.probe_command().unwrap(),
+        format!(
+            "PKG_CONFIG_PATH={} pkg-config --libs --cflags --atleast-version=1 testvendored",
+            env::join_paths(vec![vendor.as_os_str(), "$PKG_CONFIG_PATH".as_ref()])
+                .unwrap()
+                .to_string_lossy()
+        )
+    );
+
+    // PKG_CONFIG_PATH is restored
+    assert_eq!(
+        env::var_os("PKG_CONFIG_PATH").unwrap(),
+        env::current_dir().unwrap().join("src").join("tests")
+    );
+}
+
+#[test]
+fn prefix() {
+    let (libraries, _) = toml("toml-good", vec![]).unwrap();
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().prefix(),
+        Some(PathBuf::from("/usr"))
+    );
+
+    let (libraries, _) = toml(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG", "1"),
+            ("SYSTEM_DEPS_TESTLIB_LIB", "test"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().prefix(), None);
+}
+
+#[test]
+fn probe_command() {
+    let (libraries, _) = toml("toml-good", vec![]).unwrap();
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().probe_command(),
+        Some("pkg-config --libs --cflags --atleast-version=1 testlib".to_string())
+    );
+
+    let (libraries, _) = toml("toml-good", vec![("TESTLIB_STATIC", "1")]).unwrap();
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().probe_command(),
+        Some("pkg-config --libs --cflags --static --atleast-version=1 testlib".to_string())
+    );
+
+    let (libraries, _) = toml("toml-any-version", vec![]).unwrap();
+    assert_eq!(
+        libraries.get_by_name("testdata").unwrap().probe_command(),
+        Some("pkg-config --libs --cflags testdata".to_string())
+    );
+
+    // not found using pkg-config
+    let (libraries, _) = toml(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG", "1"),
+            ("SYSTEM_DEPS_TESTLIB_LIB", "custom-lib"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().probe_command(),
+        None
+    );
+}
+
+#[test]
+fn allow_older() {
+    toml_pkg_config_err_version("toml-allow-older", "9", vec![]);
+
+    let (libraries, flags) = toml_flags(
+        "toml-allow-older",
+        vec![("SYSTEM_DEPS_TESTLIB_ALLOW_OLDER", "1")],
+    )
+    .unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+    assert!(flags.to_string().contains(
+        "cargo:warning=testlib 1.2.3 is used while 9 is required as SYSTEM_DEPS_TESTLIB_ALLOW_OLDER is set\n"
+    ));
+
+    let (libraries, flags) =
+        toml_flags("toml-allow-older", vec![("SYSTEM_DEPS_ALLOW_OLDER", "1")]).unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+    assert!(flags
+        .to_string()
+        .contains("as SYSTEM_DEPS_ALLOW_OLDER is set\n"));
+
+    // no warning if the version matches
+    let (_, flags) = toml_flags("toml-good", vec![("SYSTEM_DEPS_ALLOW_OLDER", "1")]).unwrap();
+    assert!(!flags.to_string().contains("cargo:warning"));
+}
+
+#[test]
+fn feature_versions_frameworks() {
+    let (libraries, _) = toml("toml-feature-frameworks", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.frameworks, vec!["someframework"]);
+    assert_eq!(testlib.framework_paths, vec![Path::new("/usr/lib/")]);
+
+    let (libraries, flags) =
+        toml("toml-feature-frameworks", vec![("CARGO_FEATURE_V1_2", "")]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.frameworks, vec!["someframework", "Metal"]);
+    assert_eq!(
+        testlib.framework_paths,
+        vec![Path::new("/usr/lib/"), Path::new("/opt/frameworks")]
+    );
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=framework=Metal\n"));
+    assert!(flags.contains("cargo:rustc-link-search=framework=/opt/frameworks\n"));
+
+    // only the frameworks of the highest version are used
+    let (libraries, _) = toml(
+        "toml-feature-frameworks",
+        vec![("CARGO_FEATURE_V1_2", ""), ("CARGO_FEATURE_V1_2_3", "")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(
+        testlib.frameworks,
+        vec!["someframework", "Metal", "CoreVideo"]
+    );
+    assert_eq!(testlib.framework_paths, vec![Path::new("/usr/lib/")]);
+}
+
+#[test]
+fn same_as() {
+    let (libraries, flags) = toml("toml-same-as", vec![]).unwrap();
+    assert_eq!(
+        libraries.iter_ordered().map(|(k, _)| k).collect::<Vec<_>>(),
+        vec!["testlib", "test_lib", "another_test_lib"]
+    );
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.same_as, None);
+    for alias in &["test_lib", "another_test_lib"] {
+        let lib = libraries.get_by_name(alias).unwrap();
+        assert_eq!(lib.same_as.as_deref(), Some("testlib"));
+        assert_eq!(lib.version, testlib.version);
+        assert_eq!(lib.libs, testlib.libs);
+    }
+    // flags are emitted only once
+    assert_eq!(
+        flags
+            .to_string()
+            .matches("cargo:rustc-link-lib=test\n")
+            .count(),
+        1
+    );
+
+    let err = toml_err("toml-same-as-cycle");
+    assert!(err.to_string().contains("same_as cycle"));
+    let err = toml_err("toml-same-as-undeclared");
+    assert!(err
+        .to_string()
+        .contains("same_as references undeclared dependency testlib"));
+}
+
+#[test]
+fn flags_order() {
+    let (_, flags) = toml("toml-two-libs", vec![]).unwrap();
+    let lines = flags
+        .lines()
+        .into_iter()
+        .filter(|l| !l.starts_with("cargo:rerun-if-env-changed="))
+        .collect::<Vec<_>>();
+    // grouped by dependency in declaration order: search paths, libs then frameworks
+    assert_eq!(
+        lines,
+        vec![
+            "cargo:rustc-link-search=native=/usr/lib/",
+            "cargo:rustc-link-search=framework=/usr/lib/",
+            "cargo:rustc-link-lib=test",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-search=native=/usr/lib/",
+            "cargo:rustc-link-search=native=/usr/lib64/",
+            "cargo:rustc-link-search=framework=/usr/lib/",
+            "cargo:rustc-link-search=framework=/usr/lib64/",
+            "cargo:rustc-link-lib=test",
+            "cargo:rustc-link-lib=test2",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=framework=someotherframework",
+            "cargo:include=/usr/include/testlib:/usr/include/testlib:/usr/include/testanotherlib",
+        ]
+    );
+}
+
+#[test]
+fn static_frameworks() {
+    let (_, flags) = toml("toml-good", vec![("TESTLIB_STATIC", "1")]).unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains(
+        "cargo:warning=testlib: framework someframework cannot be linked statically, it is linked dynamically\n"
+    ));
+    // the framework is still emitted
+    assert!(flags.contains("cargo:rustc-link-lib=framework=someframework\n"));
+
+    let (_, flags) = toml("toml-good", vec![]).unwrap();
+    assert!(!flags.to_string().contains("cargo:warning"));
+}
+
+#[test]
+fn capture_requires() {
+    let (libraries, _) = create_config("toml-requires", vec![])
+        .capture_requires(true)
+        .probe_flags()
+        .unwrap();
+    let testrequires = libraries.get_by_name("testrequires").unwrap();
+    assert_eq!(testrequires.requires(), &["testlib", "testdata"]);
+
+    // disabled by default
+    let (libraries, _) = toml("toml-requires", vec![]).unwrap();
+    let testrequires = libraries.get_by_name("testrequires").unwrap();
+    assert!(testrequires.requires().is_empty());
+}
+
+#[test]
+fn requires_same_version() {
+    let (libraries, _) = toml("toml-requires-same-version", vec![]).unwrap();
+    assert_eq!(
+        libraries.get_by_name("testgstbase").unwrap().version,
+        "1.22.0"
+    );
+
+    let err = toml("toml-requires-same-version-patch", vec![]).unwrap_err();
+    assert_matches!(err, Error::MismatchedVersion(..));
+    assert_eq!(
+        err.to_string(),
+        "testgstbase 1.22.0 requires testgst 1.22.1 whose version does not match"
+    );
+
+    toml_err_invalid(
+        "toml-requires-same-version-invalid",
+        "invalid requires_same_version value: 1.2 (allowed: 'major', 'minor', 'patch')",
+    );
+}
+
+#[test]
+fn dependency_graph() {
+    let (libraries, _) = create_config("toml-requires-cycle", vec![])
+        .capture_requires(true)
+        .probe_flags()
+        .unwrap();
+    let graph = libraries.dependency_graph();
+    assert_eq!(
+        graph.nodes,
+        vec![
+            "testcyclea",
+            "testlib",
+            "testcycleb",
+            "testrequires",
+            "testdata"
+        ]
+    );
+    let edge = |from: &str, to: &str| (from.to_string(), to.to_string());
+    assert_eq!(
+        graph.edges,
+        vec![
+            edge("testcyclea", "testcycleb"),
+            edge("testcycleb", "testcyclea"),
+            edge("testcycleb", "testrequires"),
+            edge("testrequires", "testlib"),
+            edge("testrequires", "testdata"),
+        ]
+    );
+    assert_eq!(
+        graph.to_dot(),
+        r#"digraph system_deps {
+    "testcyclea";
+    "testlib";
+    "testcycleb";
+    "testrequires";
+    "testdata";
+    "testcyclea" -> "testcycleb";
+    "testcycleb" -> "testcyclea";
+    "testcycleb" -> "testrequires";
+    "testrequires" -> "testlib";
+    "testrequires" -> "testdata";
+}
+"#
+    );
+    // only the direct requires are reported by the library
+    let testcyclea = libraries.get_by_name("testcyclea").unwrap();
+    assert_eq!(testcyclea.requires(), &["testcycleb"]);
+
+    // without the requires, the graph only contains the dependencies
+    let (libraries, _) = toml("toml-requires-cycle", vec![]).unwrap();
+    let graph = libraries.dependency_graph();
+    assert_eq!(graph.nodes, vec!["testcyclea", "testlib"]);
+    assert!(graph.edges.is_empty());
+}
+
+#[test]
+fn print_system_libs() {
+    let config = create_config("toml-good", vec![]);
+    assert!(!config.should_print_system_libs(false));
+    assert!(config.should_print_system_libs(true));
+
+    // explicit setting wins
+    let config = create_config("toml-good", vec![]).print_system_libs(false);
+    assert!(!config.should_print_system_libs(true));
+    let config = create_config("toml-good", vec![]).print_system_libs(true);
+    assert!(config.should_print_system_libs(false));
+
+    let (libraries, _) = toml("toml-good", vec![("TESTLIB_STATIC", "1")]).unwrap();
+    assert!(libraries.get_by_name("testlib").unwrap().is_static());
+}
+
+#[test]
+fn extra_paths() {
+    let (libraries, flags) = create_config("toml-two-libs", vec![])
+        .add_link_path("/opt/sysroot/lib")
+        .add_link_path("/usr/lib64/")
+        .add_include_path("/opt/sysroot/include")
+        .emit_have_cfgs(false)
+        .probe_flags()
+        .unwrap();
+
+    // prepended to the paths of each lib, without duplicates
+    let testanotherlib = libraries.get_by_name("testanotherlib").unwrap();
+    assert_eq!(
+        testanotherlib.link_paths,
+        vec![
+            Path::new("/opt/sysroot/lib"),
+            Path::new("/usr/lib64/"),
+            Path::new("/usr/lib/")
+        ]
+    );
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(
+        testlib.include_paths,
+        vec![
+            Path::new("/opt/sysroot/include"),
+            Path::new("/usr/include/testlib")
+        ]
+    );
+    assert_eq!(
+        libraries.all_link_paths().collect::<Vec<_>>(),
+        vec![
+            Path::new("/opt/sysroot/lib"),
+            Path::new("/usr/lib/"),
+            Path::new("/usr/lib64/")
+        ]
+    );
+    assert!(flags
+        .to_string()
+        .contains("cargo:rustc-link-search=native=/opt/sysroot/lib\n"));
+}
+
+#[test]
+fn emit_rpath() {
+    let (_, flags) = create_config("toml-two-libs", vec![])
+        .emit_rpath(true)
+        .probe_flags()
+        .unwrap();
+    let flags = flags.to_string();
+    // paths shared by both libs are only emitted once
+    assert_eq!(
+        flags
+            .matches("cargo:rustc-link-arg=-Wl,-rpath,/usr/lib/\n")
+            .count(),
+        1
+    );
+    assert!(flags.contains("cargo:rustc-link-arg=-Wl,-rpath,/usr/lib64/\n"));
+
+    let (_, flags) = create_config("toml-two-libs", vec![])
+        .probe_flags()
+        .unwrap();
+    assert!(!flags.to_string().contains("-rpath"));
+
+    let (_, flags) = create_config("toml-two-libs", vec![("TARGET", "x86_64-pc-windows-gnu")])
+        .emit_rpath(true)
+        .probe_flags()
+        .unwrap();
+    assert!(!flags.to_string().contains("-rpath"));
+}
+
+#[test]
+fn defines_as_args() {
+    let (libraries, _) = toml("toml-two-libs", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.defines_as_args(), vec!["-DAWESOME", "-DBADGER=yes"]);
+
+    assert_eq!(
+        libraries.all_defines_as_args().collect::<Vec<_>>(),
+        vec!["-DAWESOME", "-DBADGER=yes", "-DGREAT"]
+    );
+
+    let mut testlib = Library::from_env_variables("testlib");
+    testlib
+        .defines
+        .insert("GREETING".into(), Some(r#"hello "big" world"#.into()));
+    assert_eq!(
+        testlib.defines_as_args(),
+        vec![r#"-DGREETING=hello "big" world"#]
+    );
+    // each define is a single argv entry, passed as is to the compiler
+    assert_eq!(
+        testlib.cflags(),
+        vec![r#"-DGREETING=hello "big" world"#.to_string()]
+    );
+}
+
+#[test]
+fn defines_conflict() {
+    let (libraries, flags) = toml("toml-defines-conflict", vec![]).unwrap();
+    // the value of the first library is used
+    assert_eq!(
+        libraries.all_defines().collect::<Vec<_>>(),
+        vec![("AWESOME", &None), ("BADGER", &Some("yes".into()))]
+    );
+    assert_eq!(
+        libraries.all_defines_as_args().collect::<Vec<_>>(),
+        vec!["-DAWESOME", "-DBADGER=yes"]
+    );
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:warning=BADGER is defined with conflicting values: testlib defines BADGER=yes, testconflict defines BADGER=no; using BADGER=yes\n"));
+    // same value
+    assert!(!flags.contains("AWESOME is defined"));
+}
+
+#[test]
+fn defines_first_wins() {
+    let (libraries, _) = toml("toml-defines-conflict", vec![]).unwrap();
+    // in declaration order, testlib shadowing testconflict
+    assert_eq!(
+        libraries.all_defines_first_wins().collect::<Vec<_>>(),
+        vec![("AWESOME", &None), ("BADGER", &Some("yes".into()))]
+    );
+
+    // testconflict declared first
+    let (libraries, _) = toml("toml-defines-shadow", vec![]).unwrap();
+    assert_eq!(
+        libraries.all_defines_first_wins().collect::<Vec<_>>(),
+        vec![("AWESOME", &None), ("BADGER", &Some("no".into()))]
+    );
+    assert_eq!(
+        libraries.all_defines_as_args().collect::<Vec<_>>(),
+        vec!["-DAWESOME", "-DBADGER=no"]
+    );
+}
+
+#[test]
+fn include_arg_style() {
+    let (libraries, _) = toml("toml-two-libs", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.include_args(), vec!["-I/usr/include/testlib"]);
+    assert_eq!(
+        testlib.isystem_args(),
+        vec!["-isystem", "/usr/include/testlib"]
+    );
+    assert_eq!(
+        testlib.cflags(),
+        vec!["-I/usr/include/testlib", "-DAWESOME", "-DBADGER=yes"]
+    );
+
+    let libraries = create_config("toml-two-libs", vec![])
+        .include_arg_style(IncludeArgStyle::ISystem)
+        .probe_full()
+        .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(
+        testlib.cflags(),
+        vec![
+            "-isystem",
+            "/usr/include/testlib",
+            "-DAWESOME",
+            "-DBADGER=yes"
+        ]
+    );
+}
+
+#[test]
+fn conflicts() {
+    // no conflict if only one of them is found
+    let (libraries, _) = toml("toml-conflicts", vec![]).unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testanotherlib").is_none());
+
+    let err = toml(
+        "toml-conflicts",
+        vec![("CARGO_FEATURE_ANOTHER_TEST_FEATURE", "")],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::ConflictingDeps(..));
+    assert_eq!(err.to_string(), "testlib conflicts with testanotherlib");
+}
+
+#[test]
+fn build_internal_feature_version() {
+    // v5 is not available so is built internally
+    let (libraries, called) = test_build_internal(
+        "toml-build-internal-version",
+        vec![("CARGO_FEATURE_V5", "")],
+        "testdata",
+    )
+    .unwrap();
+    assert!(called);
+    assert_eq!(libraries.get_by_name("testdata").unwrap().version, "5");
+
+    // not built if the feature version is not enabled
+    let (_, called) =
+        test_build_internal("toml-build-internal-version", vec![], "testdata").unwrap();
+    assert!(!called);
+
+    // env variable takes precedence
+    let (err, called) = test_build_internal(
+        "toml-build-internal-version",
+        vec![
+            ("CARGO_FEATURE_V5", ""),
+            ("SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL", "never"),
+        ],
+        "testdata",
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(..));
+    assert!(!called);
+}
+
+#[test]
+fn build_internal_feature_version_invalid() {
+    toml_err_invalid(
+        "toml-build-internal-version-invalid",
+        "metadata.system-deps.testdata: invalid build_internal value: badger (allowed: 'auto', 'always', 'never')",
+    );
+}
+
+#[test]
+fn probe_to_flags() {
+    let (libraries, flags) = create_config("toml-good", vec![]).probe_to_flags().unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(flags.contains(&"cargo:rustc-link-lib=test".to_string()));
+    assert!(flags.contains(&"cargo:rustc-cfg=system_deps_have_testlib".to_string()));
+    assert!(flags.iter().all(|f| f.starts_with("cargo:")));
+}
+
+#[test]
+fn frameworks_ordered() {
+    let (libraries, _) = toml(
+        "toml-two-libs",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_LIB_FRAMEWORK", "zframework aframework"),
+            (
+                "SYSTEM_DEPS_TESTANOTHERLIB_LIB_FRAMEWORK",
+                "bframework zframework",
+            ),
+            ("SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK", "/z/frameworks"),
+            (
+                "SYSTEM_DEPS_TESTANOTHERLIB_SEARCH_FRAMEWORK",
+                "/a/frameworks",
+            ),
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(
+        libraries.all_frameworks().collect::<Vec<_>>(),
+        vec!["aframework", "bframework", "zframework"]
+    );
+    // testlib is declared first
+    assert_eq!(
+        libraries.all_frameworks_ordered().collect::<Vec<_>>(),
+        vec!["zframework", "aframework", "bframework"]
+    );
+    assert_eq!(
+        libraries.all_framework_paths_ordered().collect::<Vec<_>>(),
+        vec![Path::new("/z/frameworks"), Path::new("/a/frameworks")]
+    );
+}
+
+#[test]
+fn link_paths_ordered() {
+    #[cfg(target_os = "windows")]
+    let paths_env = "/usr/lib64;/vendored/lib;/usr/lib";
+    #[cfg(not(target_os = "windows"))]
+    let paths_env = "/usr/lib64:/vendored/lib:/usr/lib";
+
+    let (libraries, _) = toml(
+        "toml-two-libs",
+        vec![
+            ("SYSTEM_DEPS_TESTANOTHERLIB_SEARCH_NATIVE", "/vendored/lib"),
+            ("SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE", paths_env),
+        ],
+    )
+    .unwrap();
+
+    // sorted
+    assert_eq!(
+        libraries.all_link_paths().collect::<Vec<_>>(),
+        vec![
+            Path::new("/usr/lib"),
+            Path::new("/usr/lib64"),
+            Path::new("/vendored/lib")
+        ]
+    );
+    // testlib is declared first
+    assert_eq!(
+        libraries.all_link_paths_ordered().collect::<Vec<_>>(),
+        vec![
+            Path::new("/usr/lib64"),
+            Path::new("/vendored/lib"),
+            Path::new("/usr/lib")
+        ]
+    );
+}
+
+#[test]
+fn iter_ordered() {
+    let (libraries, _) = toml(
+        "toml-declaration-order",
+        vec![("TARGET", "x86_64-unknown-linux-gnu")],
+    )
+    .unwrap();
+    assert_eq!(
+        libraries.iter_ordered().map(|(k, _)| k).collect::<Vec<_>>(),
+        vec!["testlib", "testdata", "testanotherlib"]
+    );
+}
+
+#[test]
+fn build_internal_ctx() {
+    let ctx = Rc::new(Cell::new(None));
+    let ctx_clone = ctx.clone();
+    let mut config = create_config(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always"),
+            ("TARGET", "x86_64-unknown-linux-gnu"),
+            ("TESTLIB_STATIC", "1"),
+        ],
+    )
+    .add_build_internal_ctx("testlib", move |ctx: &BuildInternalContext| {
+        ctx_clone.set(Some(ctx.clone()));
+        let mut pkg_lib = pkg_config::Config::new()
+            .print_system_libs(false)
+            .cargo_metadata(false)
+            .probe(&ctx.name)
+            .unwrap();
+        pkg_lib.version = ctx.version.clone();
+        Ok(Library::from_pkg_config(&ctx.name, pkg_lib))
+    });
+
+    let libraries = config.probe_full().unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    let ctx = ctx.take().unwrap();
+    assert_eq!(ctx.name, "testlib");
+    assert_eq!(ctx.version, "1");
+    assert_eq!(ctx.target.as_deref(), Some("x86_64-unknown-linux-gnu"));
+    assert!(ctx.statik);
+    assert_eq!(ctx.enabled_features, vec!["test_feature"]);
+}
+
+#[test]
+fn one_of() {
+    let (libraries, flags) = toml("toml-one-of", vec![]).unwrap();
+    // members are tried in declaration order
+    let test = libraries.get_by_name("test").unwrap();
+    assert_eq!(test.name, "testlib");
+    assert_eq!(test.version, "1.2.3");
+    assert_eq!(
+        test.probe_command().unwrap(),
+        "pkg-config --libs --cflags --atleast-version=1 testlib"
+    );
+    assert!(flags.to_string().contains("cargo:rustc-link-lib=test\n"));
+    // optional groups are skipped if no member is found
+    assert!(libraries.get_by_name("optional_test").is_none());
+
+    let err = toml_err("toml-one-of-missing");
+    assert_matches!(err, Error::NoGroupMember(ref key, _) if key == "test");
+    let msg = err.to_string();
+    assert!(msg.contains("testmissing 1: "));
+    assert!(msg.contains("testlib 5: "));
+}
+
+#[test]
+fn modules_allow_partial() {
+    let (libraries, flags) = toml_flags("toml-modules", vec![]).unwrap();
+    // the library aggregates the modules found
+    let test = libraries.get_by_name("test").unwrap();
+    assert_eq!(test.name, "testlib");
+    assert_eq!(test.libs, vec!["test", "test2"]);
+    assert_eq!(
+        test.include_paths,
+        vec![
+            Path::new("/usr/include/testlib"),
+            Path::new("/usr/include/testanotherlib")
+        ]
+    );
+    assert_eq!(test.defines.get("GREAT"), Some(&None));
+    assert!(flags
+        .to_string()
+        .contains("cargo:warning=testmissing 1 cannot be found, test is used without it\n"));
+
+    // all the modules are required by default
+    let err = toml_err("toml-modules-missing");
+    assert_matches!(err, Error::PkgConfig(_));
+    assert!(err.to_string().contains("testmissing"));
+}
+
+#[test]
+fn description() {
+    let err = toml_err("toml-description");
+    assert_matches!(err, Error::ConflictingDeps(..));
+    assert_eq!(
+        err.to_string(),
+        "Test library (testlib) conflicts with Test data (testdata)"
+    );
+
+    let (err, called) = test_build_internal(
+        "toml-description",
+        vec![("SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL", "always")],
+        "testlib",
+    )
+    .unwrap_err();
+    assert!(!called);
+    assert_eq!(
+        err.to_string(),
+        "Missing build internal closure for Test data (testdata) (version 4)"
+    );
+}
+
+#[test]
+fn no_version() {
+    let (libraries, flags) = toml_flags(
+        "toml-no-version",
+        vec![("SYSTEM_DEPS_TESTUNKNOWNVERSION_ALLOW_OLDER", "1")],
+    )
+    .unwrap();
+    // read from the `version` variable
+    assert_eq!(
+        libraries.get_by_name("testnoversion").unwrap().version,
+        "2.1.0"
+    );
+    assert_eq!(
+        libraries.get_by_name("testunknownversion").unwrap().version,
+        "unknown"
+    );
+    assert!(flags.to_string().contains(
+        "cargo:warning=testunknownversion does not report its version, cannot check that 1 is available\n"
+    ));
+}
+
+#[test]
+fn pkg_config_libdir() {
+    // only found in the pinned libdir
+    toml_err("toml-pkg-config-libdir");
+
+    let libdir = env::current_dir()
+        .unwrap()
+        .join("src")
+        .join("tests")
+        .join("toml-pkg-config-paths")
+        .join("vendor");
+    let old = env::var_os("PKG_CONFIG_LIBDIR");
+    let (libraries, flags) = create_config("toml-pkg-config-libdir", vec![])
+        .pkg_config_libdir(&libdir)
+        .probe_flags()
+        .unwrap();
+    let testvendored = libraries.get_by_name("testvendored").unwrap();
+    assert_eq!(testvendored.link_paths, vec![Path::new("/opt/vendor/lib/")]);
+    assert!(flags
+        .to_string()
+        .contains("cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR\n"));
+    // restored once done
+    assert_eq!(env::var_os("PKG_CONFIG_LIBDIR"), old);
+}
+
+#[test]
+fn pkg_config_env() {
+    let libdir = env::current_dir()
+        .unwrap()
+        .join("src")
+        .join("tests")
+        .join("toml-pkg-config-paths")
+        .join("vendor");
+    let old = env::var_os("PKG_CONFIG_LIBDIR");
+    let mut vars = HashMap::new();
+    vars.insert(
+        "PKG_CONFIG_LIBDIR".to_string(),
+        libdir.to_string_lossy().to_string(),
+    );
+    vars.insert("SYSTEM_DEPS_TEST_UNSET".to_string(), "1".to_string());
+
+    let (libraries, flags) = create_config("toml-pkg-config-libdir", vec![])
+        .pkg_config_env(vars)
+        .probe_flags()
+        .unwrap();
+    let testvendored = libraries.get_by_name("testvendored").unwrap();
+    assert_eq!(testvendored.link_paths, vec![Path::new("/opt/vendor/lib/")]);
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR\n"));
+    assert!(flags.contains("cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_UNSET\n"));
+    // restored once done, or removed if it was not set
+    assert_eq!(env::var_os("PKG_CONFIG_LIBDIR"), old);
+    assert_eq!(env::var_os("SYSTEM_DEPS_TEST_UNSET"), None);
+}
+
+#[test]
+fn probe_only() {
+    let libraries = create_config("toml-good", vec![])
+        .probe_only(&["testlib"])
+        .unwrap();
+    assert_eq!(
+        libraries.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+        vec!["testlib"]
+    );
+
+    let (libraries, flags) = Config {
+        only: Some(vec!["testdata".to_string()]),
+        ..create_config("toml-good", vec![])
+    }
+    .probe_flags()
+    .unwrap();
+    assert!(libraries.get_by_name("testlib").is_none());
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB\n"));
+    assert!(!flags.contains("SYSTEM_DEPS_TESTLIB_LIB"));
+    assert!(!flags.contains("cargo:rustc-link-lib=test\n"));
+    assert!(!flags.contains("system_deps_have_testlib"));
+
+    let err = create_config("toml-good", vec![])
+        .probe_only(&["testlib", "badger", "snake"])
+        .unwrap_err();
+    assert_matches!(err, Error::InvalidMetadata(_));
+    assert_eq!(err.to_string(), "Unknown dependencies: badger, snake");
+}
+
+#[test]
+fn pkg_config_paths_env_vars() {
+    let (libraries, _) = toml("toml-pkg-config-paths-env", vec![("VENDOR_DIR", "vendor")]).unwrap();
+    let testvendored = libraries.get_by_name("testvendored").unwrap();
+    assert_eq!(testvendored.link_paths, vec![Path::new("/opt/vendor/lib/")]);
+
+    toml_err_invalid(
+        "toml-pkg-config-paths-env",
+        "testvendored: environment variable VENDOR_DIR is not set in path ../toml-pkg-config-paths/${VENDOR_DIR}",
+    );
+}
+
+#[test]
+fn skip_version_check() {
+    let (libraries, _) = toml("toml-skip-version-check", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.3");
+    assert_eq!(
+        testlib.probe_command().unwrap(),
+        "pkg-config --libs --cflags testlib"
+    );
+
+    // the version of the lib built internally is not checked either
+    let libraries = create_config(
+        "toml-skip-version-check",
+        vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
+    )
+    .add_build_internal("testlib", |lib, _version| {
+        let pkg_lib = pkg_config::Config::new()
+            .print_system_libs(false)
+            .cargo_metadata(false)
+            .probe(lib)
+            .unwrap();
+        Ok(Library::from_pkg_config(lib, pkg_lib))
+    })
+    .probe_full()
+    .unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+
+    toml_err_invalid(
+        "toml-skip-version-check-invalid",
+        "metadata.system-deps.test: skip_version_check requires the name of the module to probe",
+    );
+}
+
+#[test]
+fn rewrite_paths() {
+    let (libraries, flags) = create_config("toml-relocated", vec![])
+        .rewrite_paths(|path| match path.strip_prefix("/build/sdk") {
+            Ok(rel) => Path::new("/opt/sdk").join(rel),
+            Err(_) => path.to_path_buf(),
+        })
+        .emit_have_cfgs(false)
+        .probe_flags()
+        .unwrap();
+
+    let lib = libraries.get_by_name("testrelocated").unwrap();
+    assert_eq!(lib.link_paths, vec![Path::new("/opt/sdk/lib")]);
+    assert_eq!(lib.framework_paths, vec![Path::new("/opt/sdk/lib")]);
+    assert_eq!(lib.include_paths, vec![Path::new("/opt/sdk/include")]);
+
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-search=native=/opt/sdk/lib\n"));
+    assert!(flags.contains("cargo:rustc-link-search=framework=/opt/sdk/lib\n"));
+    assert!(flags.contains("cargo:include=/opt/sdk/include\n"));
+    assert!(!flags.contains("/build/sdk"));
+}
+
+#[test]
+fn probe_hooks() {
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let (before, after) = (calls.clone(), calls.clone());
+    let libraries = create_config("toml-good", vec![])
+        .before_probe(move |key| before.borrow_mut().push(format!("before {}", key)))
+        .after_probe(move |key| after.borrow_mut().push(format!("after {}", key)))
+        .probe_full()
+        .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert_eq!(
+        *calls.borrow(),
+        vec![
+            "before testdata",
+            "after testdata",
+            "before testlib",
+            "after testlib"
+        ]
+    );
+}
+
+#[test]
+fn weak_frameworks() {
+    let macos = ("TARGET", "x86_64-apple-darwin");
+    let (libraries, flags) = toml(
+        "toml-weak-frameworks",
+        vec![macos, ("MACOSX_DEPLOYMENT_TARGET", "11.0")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.frameworks, vec!["someframework", "Metal", "Foo"]);
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=framework=Metal\n"));
+    assert!(flags.contains("cargo:rustc-link-arg=-Wl,-weak_framework,Foo\n"));
+    assert!(!flags.contains("cargo:rustc-link-lib=framework=Foo\n"));
+
+    // strongly linked when targeting a new enough OS
+    let (_, flags) = toml(
+        "toml-weak-frameworks",
+        vec![macos, ("MACOSX_DEPLOYMENT_TARGET", "12.0")],
+    )
+    .unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=framework=Foo\n"));
+    assert!(!flags.contains("-weak_framework"));
+
+    // the default deployment target may be older
+    let (_, flags) = toml("toml-weak-frameworks", vec![macos]).unwrap();
+    assert!(flags
+        .to_string()
+        .contains("cargo:rustc-link-arg=-Wl,-weak_framework,Foo\n"));
+
+    // not on other targets
+    let (_, flags) = toml(
+        "toml-weak-frameworks",
+        vec![
+            ("TARGET", "x86_64-unknown-linux-gnu"),
+            ("MACOSX_DEPLOYMENT_TARGET", "11.0"),
+        ],
+    )
+    .unwrap();
+    assert!(flags
+        .to_string()
+        .contains("cargo:rustc-link-lib=framework=Foo\n"));
+}
+
+#[test]
+fn min_os_version() {
+    // not checked on other targets
+    let (libraries, _) = toml(
+        "toml-min-os-version",
+        vec![
+            ("TARGET", "x86_64-unknown-linux-gnu"),
+            ("MACOSX_DEPLOYMENT_TARGET", "10.15"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testdata").is_some());
+
+    let err = toml(
+        "toml-min-os-version",
+        vec![
+            ("TARGET", "x86_64-apple-darwin"),
+            ("MACOSX_DEPLOYMENT_TARGET", "10.15"),
+        ],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::DeploymentTargetTooOld(..));
+    assert_eq!(
+        err.to_string(),
+        "testlib requires an OS version of at least 11.0 but MACOSX_DEPLOYMENT_TARGET is set to 10.15"
+    );
+
+    // optional deps are skipped
+    let (libraries, _) = toml(
+        "toml-min-os-version",
+        vec![
+            ("TARGET", "aarch64-apple-darwin"),
+            ("MACOSX_DEPLOYMENT_TARGET", "11.2"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testdata").is_none());
+
+    // the variable of the target OS is used
+    let (libraries, _) = toml(
+        "toml-min-os-version",
+        vec![
+            ("TARGET", "aarch64-apple-ios"),
+            ("MACOSX_DEPLOYMENT_TARGET", "10.15"),
+            ("IPHONEOS_DEPLOYMENT_TARGET", "16.0"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+}
+
+#[test]
+fn default_version() {
+    toml_err_invalid("toml-default-version", "No version defined for testlib");
+
+    let libraries = create_config("toml-default-version", vec![])
+        .default_version("1")
+        .probe_full()
+        .unwrap();
+    assert_eq!(
+        libraries.get_by_name("testlib").unwrap().probe_command(),
+        Some("pkg-config --libs --cflags --atleast-version=1 testlib".to_string())
+    );
+    assert!(libraries.get_by_name("testanotherlib").is_some());
+    // the version of the dep takes precedence
+    assert_eq!(
+        libraries.get_by_name("testdata").unwrap().probe_command(),
+        Some("pkg-config --libs --cflags --atleast-version=4 testdata".to_string())
+    );
+
+    let err = create_config("toml-default-version", vec![])
+        .default_version("2")
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(_));
+}
+
+#[test]
+fn require_defines() {
+    let (libraries, _) = toml("toml-require-defines", vec![]).unwrap();
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    let err = toml_err("toml-require-defines-missing");
+    assert_matches!(err, Error::MissingDefine(..));
+    assert_eq!(err.to_string(), "testlib does not define TESTLIB_ENABLE_X");
+
+    let err = toml_err("toml-require-defines-wrong-value");
+    assert_matches!(err, Error::MissingDefine(..));
+    assert_eq!(err.to_string(), "testlib does not define BADGER=no");
+
+    toml_err_invalid(
+        "toml-require-defines-invalid",
+        "metadata.system-deps.testlib: require_defines items must be a define name",
+    );
+}
+
+#[test]
+fn rustflags_link_paths() {
+    let rustflags = "-Copt-level=2\x1f-L\x1fnative=src/tests/rustflags/lib\x1f-L/opt/rustflags/lib\x1f-Ldependency=/opt/deps\x1f-Lframework=/opt/frameworks";
+
+    // not used by default
+    let err = create_config(
+        "toml-rustflags",
+        vec![("CARGO_ENCODED_RUSTFLAGS", rustflags)],
+    )
+    .probe_flags()
+    .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(_));
+
+    let config = create_config(
+        "toml-rustflags",
+        vec![("CARGO_ENCODED_RUSTFLAGS", rustflags)],
+    )
+    .respect_rustflags_link_paths(true);
+    assert_eq!(
+        config.rustflags_link_paths(),
+        vec![
+            Path::new("src/tests/rustflags/lib"),
+            Path::new("/opt/rustflags/lib")
+        ]
+    );
+
+    let (libraries, flags) = config.probe_flags().unwrap();
+    let testrustflags = libraries.get_by_name("testrustflags").unwrap();
+    assert_eq!(
+        testrustflags.link_paths,
+        vec![Path::new("/opt/rustflags/lib")]
+    );
+    let flags = flags.to_string();
+    assert!(flags.contains("cargo:rustc-link-lib=rustflags\n"));
+    assert!(!flags.contains("cargo:rustc-link-search=native=/opt/rustflags/lib\n"));
+}
+
+#[test]
+fn enabled_features() {
+    let (libraries, _) = toml("toml-enabled-features", vec![]).unwrap();
+    assert!(libraries.get_by_name("testdata").is_none());
+    assert!(libraries.get_by_name("testlib").is_none());
+
+    let (libraries, _) = toml(
+        "toml-enabled-features",
+        vec![
+            ("CARGO_FEATURE_USE_DATA", ""),
+            ("CARGO_FEATURE_USE_LIB", ""),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    let libraries = create_config("toml-enabled-features", vec![])
+        .enabled_features(vec!["use-lib".into()])
+        .probe_full()
+        .unwrap();
+    assert!(libraries.get_by_name("testdata").is_none());
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    // the env variables are ignored
+    let libraries = create_config("toml-enabled-features", vec![("CARGO_FEATURE_USE_LIB", "")])
+        .enabled_features(vec!["use-data".into()])
+        .probe_full()
+        .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_none());
+
+    // including for feature versions
+    let err = create_config("toml-feature-versions", vec![])
+        .enabled_features(vec!["v6".into()])
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(err, Error::PkgConfig(_));
+}
+
+#[test]
+fn validate_manifest() {
+    assert!(super::validate_manifest(Path::new("src/tests/toml-good/Cargo.toml")).is_ok());
+    assert!(super::validate_manifest(Path::new("src/tests/toml-cfg-name/Cargo.toml")).is_ok());
+
+    let errors =
+        super::validate_manifest(Path::new("src/tests/toml-validate/Cargo.toml")).unwrap_err();
+    assert!(errors
+        .iter()
+        .all(|e| matches!(e, Error::InvalidMetadata(_))));
+    let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    let prefix = "src/tests/toml-validate/Cargo.toml: package.metadata.system-deps.";
+    for expected in &[
+        "badtype: not a string or table",
+        "cfg(not(unix): not(unix",
+        "testanotherlib: unexpected key unexpected type boolean",
+        "cfg(unix).testmore: invalid version none",
+        "testlib: invalid version not a version",
+        "test_lib: conflicts_with references undeclared dependency testundeclared",
+    ] {
+        assert!(
+            errors
+                .iter()
+                .any(|e| e.starts_with(&format!("{}{}", prefix, expected))),
+            "{} not found in {:?}",
+            expected,
+            errors
+        );
+    }
+    assert_eq!(errors.len(), 6);
+
+    let errors =
+        super::validate_manifest(Path::new("src/tests/toml-missing-file/Cargo.toml")).unwrap_err();
+    assert_matches!(errors.as_slice(), [Error::FailToRead(_, _)]);
+}
+
+#[cfg(feature = "log")]
+mod log_capture {
+    use std::sync::{Mutex, Once};
+
+    lazy_static! {
+        static ref RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            RECORDS.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger;
+    static INIT: Once = Once::new();
+
+    pub fn init() {
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+    }
+
+    // the logger is global so the records of the tests running concurrently are captured as well
+    pub fn contains(message: &str) -> bool {
+        RECORDS.lock().unwrap().iter().any(|r| r == message)
+    }
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn log_overrides() {
+    log_capture::init();
+
+    let (libraries, _) = toml(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE", "/custom/path"),
+            ("SYSTEM_DEPS_TESTLIB_INCLUDE", "/custom/include"),
+        ],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.include_paths, vec![Path::new("/custom/include")]);
+
+    for expected in &[
+        "testlib: link_paths overridden by SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE",
+        "testlib: include_paths overridden by SYSTEM_DEPS_TESTLIB_INCLUDE",
+    ] {
+        assert!(log_capture::contains(expected), "{} not logged", expected);
+    }
 }